@@ -1,13 +1,40 @@
-use rig::{agent::AgentBuilder, completion::CompletionModel, embeddings::EmbeddingModel};
-use tracing::info;
+use futures::stream;
+use rig::{
+    agent::AgentBuilder,
+    completion::{self, Chat, Completion, CompletionModel, PromptError},
+    embeddings::EmbeddingModel,
+    vector_store::VectorStoreIndex,
+};
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
 
-use crate::{character::Character, knowledge::KnowledgeBase};
+use crate::{
+    character::{render_template, Character, CharacterHandle, Persona, PromptContext},
+    clients::discord,
+    context::{ContextAssembler, ContextBudget, Priority, Section},
+    knowledge::{ChannelType, ConversationMessage, KnowledgeBase, Role, Source},
+    streaming::{StreamingCompletionModel, TokenStream},
+    tools::ToolRegistry,
+};
+
+const DEFAULT_HISTORY_WINDOW: usize = 10;
+
+/// A reply being generated by [`Agent::reply_stream`].
+pub struct ReplyStream {
+    pub tokens: TokenStream,
+    /// Documents attached to the prompt, after fitting the context budget.
+    pub documents: Vec<String>,
+}
 
 #[derive(Clone)]
 pub struct Agent<M: CompletionModel, E: EmbeddingModel + 'static> {
-    pub character: Character,
+    character: CharacterHandle,
     completion_model: M,
     knowledge: KnowledgeBase<E>,
+    history_window: usize,
+    context_budget: ContextBudget,
+    tools: ToolRegistry,
+    streaming_model: Option<Arc<dyn StreamingCompletionModel>>,
 }
 
 impl<M: CompletionModel, E: EmbeddingModel> Agent<M, E> {
@@ -15,22 +42,423 @@ impl<M: CompletionModel, E: EmbeddingModel> Agent<M, E> {
         info!(name = character.name, "Creating new agent");
 
         Self {
-            character,
+            character: CharacterHandle::new(character),
             completion_model,
             knowledge,
+            history_window: DEFAULT_HISTORY_WINDOW,
+            context_budget: ContextBudget::default(),
+            tools: ToolRegistry::default(),
+            streaming_model: None,
         }
     }
 
+    /// Sets the token budget used by [`Agent::reply`] to fit persona, history,
+    /// retrieved documents and examples into the model's context window.
+    pub fn with_context_budget(mut self, context_budget: ContextBudget) -> Self {
+        self.context_budget = context_budget;
+        self
+    }
+
+    /// Sets the tools offered to the model when replying on any client.
+    pub fn with_tools(mut self, tools: ToolRegistry) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    pub fn tools(&self) -> &ToolRegistry {
+        &self.tools
+    }
+
+    /// Sets the model used by [`Agent::reply_stream`]. It should be the same
+    /// model as the completion model, only called through a streaming API.
+    pub fn with_streaming_model(mut self, model: impl StreamingCompletionModel + 'static) -> Self {
+        self.streaming_model = Some(Arc::new(model));
+        self
+    }
+
+    /// Sets how many previous messages are sent as chat history with each reply.
+    pub fn with_history_window(mut self, history_window: usize) -> Self {
+        self.history_window = history_window;
+        self
+    }
+
+    pub fn history_window(&self) -> usize {
+        self.history_window
+    }
+
+    /// The character currently in use. Hold on to the returned value for the
+    /// duration of a request so a reload can't change it halfway through.
+    pub fn character(&self) -> Arc<Character> {
+        self.character.get()
+    }
+
+    /// Handle that can be used to swap the character while the agent is running.
+    pub fn character_handle(&self) -> CharacterHandle {
+        self.character.clone()
+    }
+
     pub fn builder(&self) -> AgentBuilder<M> {
+        self.builder_with(&PromptContext::default())
+    }
+
+    /// Builds an agent using the character's persona for the given platform and
+    /// channel type.
+    pub fn builder_for(&self, source: Source, channel_type: ChannelType) -> AgentBuilder<M> {
+        self.builder_with(&PromptContext {
+            source: Some(source),
+            channel_type: Some(channel_type),
+            ..Default::default()
+        })
+    }
+
+    /// Like [`Agent::builder_for`], also filling user and channel templates.
+    pub fn builder_with(&self, context: &PromptContext) -> AgentBuilder<M> {
+        let character = self.character();
+        let persona = persona(&character, context);
+
+        let variables = context.variables(&character);
+        let mut prompt = system_prompt(&character, &persona);
+        let examples = examples(&character);
+        if !examples.is_empty() {
+            prompt = format!("{}\n\n{}", prompt, examples.join("\n\n"));
+        }
+        let preamble = render_template(&prompt, &variables);
+
         let builder = AgentBuilder::new(self.completion_model.clone())
-            .preamble(&self.character.preamble)
-            .context(&format!("Your name: {}", self.character.name))
-            .dynamic_context(2, self.knowledge.clone().document_index());
+            .preamble(&preamble)
+            .context(&format!("Your name: {}", character.name))
+            .dynamic_context(
+                self.context_budget.documents,
+                self.knowledge.clone().document_index(),
+            );
 
-        builder
+        self.tools.attach(builder)
     }
 
     pub fn knowledge(&self) -> &KnowledgeBase<E> {
         &self.knowledge
     }
+
+    /// Generates a reply to `prompt`. `history` holds the conversation leading up
+    /// to it, oldest first and without the prompt itself.
+    ///
+    /// The context is fitted into the agent's [`ContextBudget`], keeping the
+    /// persona, then the most recent history, then retrieved documents and
+    /// finally the character's examples.
+    pub async fn reply(
+        &self,
+        context: &PromptContext,
+        history: &[ConversationMessage],
+        prompt: &str,
+    ) -> Result<String, PromptError> {
+        let (agent, chat_history, _) = self.prepare(context, history, prompt).await;
+        agent.chat(prompt, chat_history).await
+    }
+
+    /// Like [`Agent::reply`], yielding the response as it is generated along
+    /// with the documents attached to the prompt.
+    ///
+    /// Without a streaming model, or when tools are registered, the complete
+    /// response is yielded as a single chunk since tool calls need the whole
+    /// response before anything can be shown. The same happens when the
+    /// streaming request fails, so the completion model's fallbacks apply.
+    pub async fn reply_stream(
+        &self,
+        context: &PromptContext,
+        history: &[ConversationMessage],
+        prompt: &str,
+    ) -> Result<ReplyStream, PromptError> {
+        let (agent, chat_history, documents) = self.prepare(context, history, prompt).await;
+
+        if let Some(model) = self
+            .streaming_model
+            .as_ref()
+            .filter(|_| self.tools.is_empty())
+        {
+            let request = agent
+                .completion(prompt, chat_history.clone())
+                .await?
+                .build();
+            match model.stream(request).await {
+                Ok(tokens) => return Ok(ReplyStream { tokens, documents }),
+                // The completion model has its own retries and fallbacks
+                Err(err) => warn!(%err, "Failed to start streaming, falling back to completion"),
+            }
+        }
+
+        let response = agent.chat(prompt, chat_history).await?;
+        Ok(ReplyStream {
+            tokens: Box::pin(stream::once(async move { Ok(response) })),
+            documents,
+        })
+    }
+
+    /// Builds the agent for a reply along with the chat history and documents
+    /// that fit in the context budget.
+    async fn prepare(
+        &self,
+        context: &PromptContext,
+        history: &[ConversationMessage],
+        prompt: &str,
+    ) -> (rig::agent::Agent<M>, Vec<completion::Message>, Vec<String>) {
+        let character = self.character();
+        let persona = persona(&character, context);
+        let variables = context.variables(&character);
+
+        let mut persona_prompt = format!(
+            "{}\n\nYour name: {}\n\nCurrent time: {}",
+            render_template(&system_prompt(&character, &persona), &variables),
+            character.name,
+            chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
+        );
+        if let Some(extra) = &context.context {
+            persona_prompt = format!("{}\n\n{}", persona_prompt, extra);
+        }
+        let chat_history = self.chat_history(history);
+        let documents = self.retrieve_documents(prompt).await;
+        let examples = examples(&character)
+            .iter()
+            .map(|example| render_template(example, &variables))
+            .collect();
+
+        let mut assembled = ContextAssembler::new(self.context_budget.available())
+            .section(Section {
+                name: "persona",
+                priority: Priority::Persona,
+                items: vec![persona_prompt, prompt.to_string()],
+                truncate: false,
+            })
+            .section(Section {
+                name: "history",
+                priority: Priority::History,
+                // Newest first, so the oldest messages are dropped first
+                items: chat_history
+                    .iter()
+                    .rev()
+                    .map(|msg| msg.content.clone())
+                    .collect(),
+                truncate: false,
+            })
+            .section(Section {
+                name: "documents",
+                priority: Priority::Documents,
+                items: documents,
+                truncate: true,
+            })
+            .section(Section {
+                name: "examples",
+                priority: Priority::Examples,
+                items: examples,
+                truncate: false,
+            })
+            .assemble();
+
+        let mut preamble = assembled.take("persona").remove(0).1;
+        let examples = assembled.take("examples");
+        if !examples.is_empty() {
+            preamble = format!(
+                "{}\n\n{}",
+                preamble,
+                examples
+                    .into_iter()
+                    .map(|(_, example)| example)
+                    .collect::<Vec<_>>()
+                    .join("\n\n")
+            );
+        }
+
+        let documents: Vec<String> = assembled
+            .take("documents")
+            .into_iter()
+            .map(|(_, document)| document)
+            .collect();
+        let mut builder = AgentBuilder::new(self.completion_model.clone()).preamble(&preamble);
+        for document in &documents {
+            builder = builder.context(document);
+        }
+        let agent = self.tools.attach(builder).build();
+
+        let mut kept_history: Vec<completion::Message> = assembled
+            .take("history")
+            .into_iter()
+            .map(|(index, _)| chat_history[chat_history.len() - 1 - index].clone())
+            .collect();
+        kept_history.reverse();
+
+        debug!(
+            history = kept_history.len(),
+            documents = documents.len(),
+            used_tokens = assembled.used_tokens,
+            "Sending prompt with chat history"
+        );
+
+        (agent, kept_history, documents)
+    }
+
+    /// Documents from the knowledge base relevant to `prompt`, as they are
+    /// attached to the prompt before budgeting.
+    pub async fn retrieve_documents(&self, prompt: &str) -> Vec<String> {
+        if self.context_budget.documents == 0 {
+            return Vec::new();
+        }
+
+        match self
+            .knowledge
+            .document_index()
+            .top_n::<serde_json::Value>(prompt, self.context_budget.documents)
+            .await
+        {
+            Ok(results) => results
+                .into_iter()
+                .filter_map(|(_, id, document)| {
+                    let content = document.get("content")?.as_str()?;
+                    Some(format!("<file id: {}>\n{}\n</file>", id, content))
+                })
+                .collect(),
+            Err(err) => {
+                error!(?err, "Failed to retrieve documents");
+                Vec::new()
+            }
+        }
+    }
+
+    /// Converts the last `history_window` messages into chat history. Messages
+    /// from other users are prefixed with their author so the model can tell
+    /// participants apart in group channels.
+    pub fn chat_history(&self, history: &[ConversationMessage]) -> Vec<completion::Message> {
+        let start = history.len().saturating_sub(self.history_window);
+
+        history[start..]
+            .iter()
+            .map(|entry| {
+                let msg = &entry.message;
+                if msg.role == Role::Assistant {
+                    completion::Message {
+                        role: Role::Assistant.as_str().to_string(),
+                        content: msg.content.clone(),
+                    }
+                } else {
+                    let author = entry.author.as_deref().unwrap_or(&msg.account_id);
+                    completion::Message {
+                        role: Role::User.as_str().to_string(),
+                        content: format!("{}: {}", author, msg.content),
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Assembles the system prompt from every section of the character file.
+/// Empty sections are skipped so minimal characters stay minimal.
+fn system_prompt(character: &Character, persona: &Persona) -> String {
+    let mut sections = vec![character.preamble.trim().to_string()];
+
+    if !character.lore.is_empty() {
+        sections.push(format!("Background:\n{}", bullet_list(&character.lore)));
+    }
+
+    if !character.adjectives.is_empty() {
+        sections.push(format!("Personality: {}", character.adjectives.join(", ")));
+    }
+
+    if !character.topics.is_empty() {
+        sections.push(format!(
+            "Topics you are interested in: {}",
+            character.topics.join(", ")
+        ));
+    }
+
+    let style: Vec<String> = character
+        .style
+        .all
+        .iter()
+        .chain(character.style.chat.iter())
+        .chain(persona.style.iter())
+        .cloned()
+        .collect();
+    if !style.is_empty() {
+        sections.push(format!("Style guidelines:\n{}", bullet_list(&style)));
+    }
+
+    let mut guidelines = Vec::new();
+    if let Some(max_length) = persona.max_length {
+        guidelines.push(format!(
+            "Keep your responses concise and under {} characters.",
+            max_length
+        ));
+    }
+    if !persona.forbidden_formatting.is_empty() {
+        guidelines.push(format!(
+            "Do not use the following formatting: {}.",
+            persona.forbidden_formatting.join(", ")
+        ));
+    }
+    guidelines.extend(persona.context.iter().cloned());
+    if !guidelines.is_empty() {
+        sections.push(format!(
+            "Platform guidelines:\n{}",
+            bullet_list(&guidelines)
+        ));
+    }
+
+    sections.join("\n\n")
+}
+
+/// Renders each example conversation, plus the post examples, as a separate
+/// block so they can be dropped individually when the context is tight.
+fn examples(character: &Character) -> Vec<String> {
+    let mut examples: Vec<String> = character
+        .message_examples
+        .iter()
+        .map(|example| {
+            let messages = example
+                .messages
+                .iter()
+                .map(|msg| format!("{}: {}", msg.user, msg.content.text.trim()))
+                .collect::<Vec<_>>()
+                .join("\n");
+            format!("Example conversation:\n{}", messages)
+        })
+        .collect();
+
+    if !character.post_examples.is_empty() {
+        examples.push(format!(
+            "Example posts:\n{}",
+            bullet_list(&character.post_examples)
+        ));
+    }
+
+    examples
+}
+
+/// Resolves the platform persona for the context, falling back to the default
+/// length limit of the platform.
+fn persona(character: &Character, context: &PromptContext) -> Persona {
+    match &context.source {
+        Some(source) => {
+            let channel_type = context.channel_type.clone().unwrap_or(ChannelType::Text);
+            let mut persona = character.persona_for(source, &channel_type);
+            persona.max_length = persona.max_length.or(Some(default_max_length(source)));
+            persona
+        }
+        None => Persona::default(),
+    }
+}
+
+/// Length limit used when the character doesn't set one for the platform.
+fn default_max_length(source: &Source) -> usize {
+    match source {
+        Source::Twitter | Source::X => 280,
+        Source::Discord => discord::MAX_MESSAGE_LENGTH,
+        _ => 2000,
+    }
+}
+
+fn bullet_list(items: &[String]) -> String {
+    items
+        .iter()
+        .map(|item| format!("- {}", item.trim()))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
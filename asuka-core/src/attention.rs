@@ -1,13 +1,43 @@
+use regex::{Regex, RegexBuilder};
 use rig::completion::{CompletionModel, ModelChoice};
-use tracing::debug;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
 
-use crate::knowledge::{ChannelType, Source};
-use std::collections::HashSet;
+use crate::{
+    character::{render_template, Character, CharacterHandle, PromptContext},
+    knowledge::{ChannelType, Source},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 const RESPOND_COMMAND: &str = "[RESPOND]";
 const IGNORE_COMMAND: &str = "[IGNORE]";
 const STOP_COMMAND: &str = "[STOP]";
 
+const DEFAULT_DECISION_PROMPT: &str = "You are in a room with other users. You should only respond when addressed or when the conversation is relevant to you.";
+const DEFAULT_STOP_PHRASES: &[&str] = &[
+    "shut up",
+    "stop",
+    "please shut up",
+    "shut up please",
+    "dont talk",
+    "silence",
+    "stop talking",
+    "be quiet",
+    "hush",
+    "wtf",
+    "stfu",
+    "stupid bot",
+    "dumb bot",
+    "stop responding",
+    "can you not",
+    "can you stop",
+];
+
 #[derive(Debug, PartialEq)]
 pub enum AttentionCommand {
     Respond,
@@ -15,60 +45,347 @@ pub enum AttentionCommand {
     Stop,
 }
 
+/// How the bot treats messages in a type of channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelBehavior {
+    /// Reply to every message.
+    Always,
+    /// Only reply when addressed, without asking the model.
+    Mentioned,
+    /// Reply when addressed, otherwise let the model decide.
+    Decide,
+    /// Never reply.
+    Never,
+}
+
 #[derive(Debug)]
 pub struct AttentionContext {
     pub message_content: String,
+    pub channel_id: String,
     pub mentioned_names: HashSet<String>,
+    /// Platform user ids mentioned in the message.
+    pub mentioned_ids: HashSet<String>,
+    /// The bot's own user id on the platform, if the client knows it.
+    pub bot_id: Option<String>,
+    pub is_reply_to_bot: bool,
     pub history: Vec<(String, String)>,
     pub channel_type: ChannelType,
     pub source: Source,
+    /// Messages in the channel since the bot last replied, including this
+    /// one, or `None` if it hasn't replied within the history.
+    pub messages_since_reply: Option<usize>,
+    /// Set when the bot is in a conversation with the author of the message.
+    pub engagement: Option<Engagement>,
+}
+
+/// A conversation the bot is having with a user in a channel, started when
+/// it replies to them. Each of their messages uses up a turn, and it ends
+/// when no turns are left or it expires.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Engagement {
+    pub user_id: String,
+    pub turns_left: usize,
+    pub expires_at: Instant,
+}
+
+/// The model's decision along with how relevant it rated the message, from
+/// 0 to 1.
+#[derive(Debug, PartialEq)]
+struct Decision {
+    command: AttentionCommand,
+    score: f32,
+}
+
+impl Decision {
+    /// Parses a response like `[RESPOND] 0.8`. Without a score the command is
+    /// taken at face value.
+    fn parse(text: &str) -> Self {
+        let command = if text.contains(RESPOND_COMMAND) {
+            AttentionCommand::Respond
+        } else if text.contains(STOP_COMMAND) {
+            AttentionCommand::Stop
+        } else {
+            AttentionCommand::Ignore
+        };
+
+        let score = text
+            .split_whitespace()
+            .filter_map(|word| {
+                word.trim_matches(|c: char| !c.is_ascii_digit())
+                    .parse::<f32>()
+                    .ok()
+            })
+            .find(|score| (0.0..=1.0).contains(score))
+            .unwrap_or(match command {
+                AttentionCommand::Respond => 1.0,
+                _ => 0.0,
+            });
+
+        Self { command, score }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct AttentionConfig {
     pub bot_names: Vec<String>,
+    /// Channel ids the bot replies in. Empty allows every channel.
+    pub allow_channels: Vec<String>,
+    /// Channel ids the bot never replies in.
+    pub deny_channels: Vec<String>,
+    /// Words that make the bot reply, like its name does.
+    pub keywords: Vec<String>,
+    /// Patterns that tell the bot to stop talking.
+    pub stop_phrases: Vec<Regex>,
+    /// Overrides of the default behavior per channel type, which is to
+    /// always reply in direct messages and decide elsewhere.
+    pub channel_behavior: HashMap<ChannelType, ChannelBehavior>,
+    /// Replaces the instructions of the prompt used to decide whether to
+    /// reply. The response options and messages are always added.
+    pub decision_prompt: Option<String>,
+    /// Relevance the model has to rate a message at for the bot to join in
+    /// unprompted, from 0 to 1.
     pub reply_threshold: f32,
     pub max_history_messages: i64,
+    /// Messages others have to send in a channel after a reply before the
+    /// bot joins in unprompted again.
     pub cooldown_messages: i64,
+    /// How long the bot stays quiet after being told to stop.
+    pub mute_duration: Duration,
+    /// Accounts that may mute and unmute the bot with `!mute` and `!unmute`.
+    pub admin_ids: Vec<String>,
+    /// Messages from a user the bot stays engaged for after replying to them.
+    pub engagement_turns: usize,
+    /// How long the bot stays engaged after replying, at most.
+    pub engagement_duration: Duration,
 }
 
 impl Default for AttentionConfig {
     fn default() -> Self {
         Self {
-            bot_names: vec!["shinobai".to_string(), "shinobi".to_string()],
+            bot_names: Vec::new(),
+            allow_channels: Vec::new(),
+            deny_channels: Vec::new(),
+            keywords: Vec::new(),
+            stop_phrases: DEFAULT_STOP_PHRASES
+                .iter()
+                .map(|phrase| stop_phrase(&regex::escape(phrase)).unwrap())
+                .collect(),
+            channel_behavior: HashMap::new(),
+            decision_prompt: None,
             reply_threshold: 0.6,
             max_history_messages: 10,
             cooldown_messages: 3,
+            mute_duration: Duration::from_secs(60 * 60),
+            admin_ids: Vec::new(),
+            engagement_turns: 3,
+            engagement_duration: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl AttentionConfig {
+    /// Builds the config from the character's `[attention]` section. The
+    /// character's name is always one of the bot names.
+    pub fn from_character(character: &Character) -> Result<Self, regex::Error> {
+        Self::default().with_character(character)
+    }
+
+    /// Layers the character's `[attention]` section on top of the config:
+    /// lists are extended, everything else set in the section replaces the
+    /// current value.
+    pub fn with_character(mut self, character: &Character) -> Result<Self, regex::Error> {
+        let rules = &character.attention;
+
+        for name in std::iter::once(&character.name).chain(&rules.bot_names) {
+            if !self.bot_names.contains(name) {
+                self.bot_names.push(name.clone());
+            }
+        }
+        self.allow_channels
+            .extend(rules.allow_channels.iter().cloned());
+        self.deny_channels
+            .extend(rules.deny_channels.iter().cloned());
+        self.keywords.extend(rules.keywords.iter().cloned());
+        if !rules.stop_phrases.is_empty() {
+            self.stop_phrases = rules
+                .stop_phrases
+                .iter()
+                .map(|pattern| stop_phrase(pattern))
+                .collect::<Result<_, _>>()?;
         }
+        // Unknown channel types are rejected when the character is loaded
+        self.channel_behavior
+            .extend(
+                rules
+                    .channel_types
+                    .iter()
+                    .filter_map(|(channel_type, behavior)| {
+                        Some((ChannelType::from_str(channel_type).ok()?, *behavior))
+                    }),
+            );
+        if rules.decision_prompt.is_some() {
+            self.decision_prompt = rules.decision_prompt.clone();
+        }
+        if let Some(reply_threshold) = rules.reply_threshold {
+            self.reply_threshold = reply_threshold;
+        }
+        if let Some(cooldown_messages) = rules.cooldown_messages {
+            self.cooldown_messages = cooldown_messages;
+        }
+
+        Ok(self)
     }
+
+    pub fn behavior_for(&self, channel_type: &ChannelType) -> ChannelBehavior {
+        match self.channel_behavior.get(channel_type) {
+            Some(behavior) => *behavior,
+            None if *channel_type == ChannelType::DirectMessage => ChannelBehavior::Always,
+            None => ChannelBehavior::Decide,
+        }
+    }
+
+    /// Whether the channel passes the allow and deny lists.
+    pub fn is_channel_allowed(&self, channel_id: &str) -> bool {
+        !self.deny_channels.iter().any(|id| id == channel_id)
+            && (self.allow_channels.is_empty()
+                || self.allow_channels.iter().any(|id| id == channel_id))
+    }
+}
+
+/// Compiles a stop phrase, matched case-insensitively on word boundaries.
+pub fn stop_phrase(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(&format!(r"\b(?:{})\b", pattern))
+        .case_insensitive(true)
+        .build()
 }
 
 #[derive(Clone)]
 pub struct Attention<M: CompletionModel> {
-    config: AttentionConfig,
+    /// Config given to [`Attention::new`], before the character's rules.
+    base: AttentionConfig,
+    character: Option<CharacterHandle>,
+    /// Config in use and the character it was built from.
+    config: Arc<Mutex<(Option<Arc<Character>>, Arc<AttentionConfig>)>>,
     completion_model: M,
+    /// Engagements by channel id, shared by every client using the attention.
+    engagements: Arc<Mutex<HashMap<String, Engagement>>>,
 }
 
 impl<M: CompletionModel> Attention<M> {
     pub fn new(config: AttentionConfig, completion_model: M) -> Self {
         Self {
-            config,
+            config: Arc::new(Mutex::new((None, Arc::new(config.clone())))),
+            base: config,
+            character: None,
             completion_model,
+            engagements: Arc::default(),
         }
     }
 
-    pub async fn should_reply(&self, context: &AttentionContext) -> AttentionCommand {
-        let content = context.message_content.to_lowercase();
+    /// Applies the `[attention]` rules of the handle's character, and of the
+    /// characters swapped into it later, e.g. by a
+    /// [`crate::character::CharacterWatcher`].
+    pub fn with_character(mut self, character: CharacterHandle) -> Self {
+        self.character = Some(character);
+        self
+    }
 
-        // Always reply to DMs
-        if context.channel_type == ChannelType::DirectMessage {
-            return AttentionCommand::Respond;
+    pub fn config(&self) -> Arc<AttentionConfig> {
+        let mut current = self.config.lock().unwrap();
+        let Some(handle) = &self.character else {
+            return current.1.clone();
+        };
+
+        let character = handle.get();
+        if current
+            .0
+            .as_ref()
+            .is_some_and(|built_from| Arc::ptr_eq(built_from, &character))
+        {
+            return current.1.clone();
         }
 
-        // Check for mentions or name references
-        for name in &self.config.bot_names {
+        match self.base.clone().with_character(&character) {
+            Ok(config) => {
+                debug!(
+                    name = character.name,
+                    "Applied the character's attention rules"
+                );
+                current.1 = Arc::new(config);
+            }
+            Err(err) => {
+                error!(%err, "Invalid attention rules, keeping the previous ones");
+            }
+        }
+        current.0 = Some(character);
+        current.1.clone()
+    }
+
+    /// Character the current config was built from, if any.
+    fn current_character(&self) -> Option<Arc<Character>> {
+        self.config.lock().unwrap().0.clone()
+    }
+
+    /// Engages the bot with a user it replied to, replacing any other
+    /// engagement in the channel.
+    pub fn engage(&self, channel_id: &str, user_id: &str) {
+        let config = self.config();
+        let engagement = Engagement {
+            user_id: user_id.to_string(),
+            turns_left: config.engagement_turns,
+            expires_at: Instant::now() + config.engagement_duration,
+        };
+        debug!(channel_id, ?engagement, "Engaged in conversation");
+
+        self.engagements
+            .lock()
+            .unwrap()
+            .insert(channel_id.to_string(), engagement);
+    }
+
+    pub fn disengage(&self, channel_id: &str) {
+        self.engagements.lock().unwrap().remove(channel_id);
+    }
+
+    /// Counts a message from the user against the channel's engagement and
+    /// returns it, if the bot is engaged with them.
+    pub fn engagement(&self, channel_id: &str, user_id: &str) -> Option<Engagement> {
+        let mut engagements = self.engagements.lock().unwrap();
+        let engagement = engagements.get_mut(channel_id)?;
+
+        if engagement.expires_at <= Instant::now() || engagement.turns_left == 0 {
+            engagements.remove(channel_id);
+            return None;
+        }
+        if engagement.user_id != user_id {
+            return None;
+        }
+
+        let current = engagement.clone();
+        engagement.turns_left -= 1;
+        Some(current)
+    }
+
+    /// Whether the message is addressed to the bot: a reply to one of its
+    /// messages, a mention or its name as a word of the text.
+    pub fn is_mentioned(&self, context: &AttentionContext) -> bool {
+        if context.is_reply_to_bot {
+            debug!("Message replies to the bot");
+            return true;
+        }
+
+        if let Some(bot_id) = &context.bot_id {
+            if context.mentioned_ids.contains(bot_id) {
+                debug!(bot_id, "Bot was mentioned");
+                return true;
+            }
+        }
+
+        let content = context.message_content.to_lowercase();
+        for name in &self.config().bot_names {
             let mentioned = context.mentioned_names.contains(name);
-            let name_in_content = content.contains(&name.to_lowercase());
+            let name_in_content = contains_word(&content, &name.to_lowercase());
 
             debug!(
                 name = name,
@@ -78,51 +395,121 @@ impl<M: CompletionModel> Attention<M> {
             );
 
             if mentioned || name_in_content {
-                debug!("Bot name {} was mentioned, will reply", name);
-                return AttentionCommand::Respond;
+                debug!("Bot name {} was mentioned", name);
+                return true;
             }
         }
 
-        // Check for stop/disengage phrases
-        let stop_phrases = [
-            "shut up",
-            "stop",
-            "please shut up",
-            "shut up please",
-            "dont talk",
-            "silence",
-            "stop talking",
-            "be quiet",
-            "hush",
-            "wtf",
-            "stfu",
-            "stupid bot",
-            "dumb bot",
-            "stop responding",
-            "can you not",
-            "can you stop",
-            "be quiet",
-        ];
-
-        if stop_phrases.iter().any(|phrase| content.contains(phrase)) {
+        false
+    }
+
+    /// Whether the message tells the bot to stop: a stop phrase in a group
+    /// channel, addressed to the bot or sent while it is engaged with the
+    /// author. Elsewhere "wtf is this error" is just part of the conversation,
+    /// and direct messages are always answered, so "how do I stop my
+    /// controller from drifting" is a question there.
+    pub fn is_stop_request(&self, context: &AttentionContext) -> bool {
+        if context.channel_type == ChannelType::DirectMessage {
+            return false;
+        }
+
+        let stop_phrase = self
+            .config()
+            .stop_phrases
+            .iter()
+            .any(|phrase| phrase.is_match(&context.message_content));
+
+        stop_phrase && (context.engagement.is_some() || self.is_mentioned(context))
+    }
+
+    pub async fn should_reply(&self, context: &AttentionContext) -> AttentionCommand {
+        let config = self.config();
+        let content = context.message_content.to_lowercase();
+
+        if !config.is_channel_allowed(&context.channel_id) {
+            debug!(channel_id = context.channel_id, "Channel isn't allowed");
+            return AttentionCommand::Ignore;
+        }
+
+        let behavior = config.behavior_for(&context.channel_type);
+        if behavior == ChannelBehavior::Never {
+            return AttentionCommand::Ignore;
+        }
+
+        if self.is_stop_request(context) {
             return AttentionCommand::Stop;
         }
 
+        if behavior == ChannelBehavior::Always {
+            return AttentionCommand::Respond;
+        }
+
+        // Check for mentions or name references
+        if self.is_mentioned(context) {
+            return AttentionCommand::Respond;
+        }
+
+        if let Some(keyword) = config
+            .keywords
+            .iter()
+            .find(|keyword| contains_word(&content, &keyword.to_lowercase()))
+        {
+            debug!(keyword, "Keyword was mentioned, will reply");
+            return AttentionCommand::Respond;
+        }
+
+        if behavior == ChannelBehavior::Mentioned {
+            return AttentionCommand::Ignore;
+        }
+
         // Ignore very short messages
         if content.len() < 4 {
             return AttentionCommand::Ignore;
         }
 
+        // Let others talk before joining in again, unless the conversation
+        // with this user is still going
+        if let (Some(count), None) = (context.messages_since_reply, &context.engagement) {
+            if (count as i64) < config.cooldown_messages {
+                debug!(count, "Bot replied recently, cooling down");
+                return AttentionCommand::Ignore;
+            }
+        }
+
+        let engagement = match context.engagement {
+            Some(_) => "You replied to the author of the latest message recently and are still talking with them, \
+                so follow-ups from them are likely meant for you.\n\n",
+            None => "",
+        };
+
         // Use LLM to decide if we should respond
+        let instructions = match (&config.decision_prompt, self.current_character()) {
+            (Some(prompt), Some(character)) => {
+                let variables = PromptContext {
+                    source: Some(context.source.clone()),
+                    channel_type: Some(context.channel_type.clone()),
+                    channel: Some(context.channel_id.clone()),
+                    ..Default::default()
+                }
+                .variables(&character);
+                render_template(prompt, &variables)
+            }
+            (Some(prompt), None) => prompt.clone(),
+            (None, _) => DEFAULT_DECISION_PROMPT.to_string(),
+        };
         let prompt = format!(
-            "You are in a room with other users. You should only respond when addressed or when the conversation is relevant to you.\n\n\
+            "{instructions}\n\n\
+            {engagement}\
             Response options:\n\
             {RESPOND_COMMAND} - Message is directed at you or conversation is relevant\n\
             {IGNORE_COMMAND} - Message is not interesting or not directed at you\n\
             {STOP_COMMAND} - User wants you to stop or conversation has concluded\n\n\
             Recent messages:\n{}\n\nLatest message: {}\n\n\
-            Choose one response option:",
-            context.history.iter()
+            Choose one response option, followed by how relevant the latest message is to you \
+            from 0.0 (not at all) to 1.0 (directly addressed), e.g. `{RESPOND_COMMAND} 0.8`:",
+            context
+                .history
+                .iter()
                 .map(|(_, msg)| format!("- {}", msg))
                 .collect::<Vec<_>>()
                 .join("\n"),
@@ -131,20 +518,306 @@ impl<M: CompletionModel> Attention<M> {
 
         let builder = self.completion_model.completion_request(&prompt);
 
-        match self.completion_model.completion(builder.build()).await {
+        let decision = match self.completion_model.completion(builder.build()).await {
             Ok(response) => match response.choice {
-                ModelChoice::Message(text) => {
-                    if text.contains(RESPOND_COMMAND) {
-                        AttentionCommand::Respond
-                    } else if text.contains(STOP_COMMAND) {
-                        AttentionCommand::Stop
-                    } else {
-                        AttentionCommand::Ignore
-                    }
-                }
-                ModelChoice::ToolCall(_, _, _) => AttentionCommand::Ignore,
+                ModelChoice::Message(text) => Decision::parse(&text),
+                ModelChoice::ToolCall(_, _, _) => return AttentionCommand::Ignore,
             },
-            Err(_) => AttentionCommand::Ignore,
+            Err(_) => return AttentionCommand::Ignore,
+        };
+        debug!(
+            ?decision,
+            threshold = config.reply_threshold,
+            "Attention decision"
+        );
+
+        match decision.command {
+            AttentionCommand::Respond if decision.score >= config.reply_threshold => {
+                AttentionCommand::Respond
+            }
+            AttentionCommand::Stop => AttentionCommand::Stop,
+            _ => AttentionCommand::Ignore,
+        }
+    }
+}
+
+/// Whether `word` appears in `text` between non-word characters, so
+/// "shinobi?" matches "shinobi" and "shinobixyz" doesn't.
+fn contains_word(text: &str, word: &str) -> bool {
+    if word.is_empty() {
+        return false;
+    }
+
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(word).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + word.len()..].chars().next();
+        !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestModel;
+
+    fn attention_with(response: &'static str) -> Attention<TestModel> {
+        let config = AttentionConfig {
+            bot_names: vec!["shinobi".to_string()],
+            ..Default::default()
+        };
+        attention_with_config(config, response)
+    }
+
+    fn attention_with_config(
+        config: AttentionConfig,
+        response: &'static str,
+    ) -> Attention<TestModel> {
+        Attention::new(config, TestModel::new(response))
+    }
+
+    fn context(messages_since_reply: Option<usize>) -> AttentionContext {
+        AttentionContext {
+            message_content: "anyone know how dojo models work?".to_string(),
+            channel_id: "general".to_string(),
+            mentioned_names: HashSet::new(),
+            mentioned_ids: HashSet::new(),
+            bot_id: None,
+            is_reply_to_bot: false,
+            history: Vec::new(),
+            channel_type: ChannelType::Text,
+            source: Source::Discord,
+            messages_since_reply,
+            engagement: None,
         }
     }
+
+    #[test]
+    fn test_parse_decision() {
+        assert_eq!(
+            Decision::parse("[RESPOND] 0.8"),
+            Decision {
+                command: AttentionCommand::Respond,
+                score: 0.8
+            }
+        );
+        assert_eq!(
+            Decision::parse("[IGNORE] (0.2)"),
+            Decision {
+                command: AttentionCommand::Ignore,
+                score: 0.2
+            }
+        );
+        assert_eq!(Decision::parse("[RESPOND]").score, 1.0);
+    }
+
+    #[tokio::test]
+    async fn test_reply_threshold() {
+        let attention = attention_with("[RESPOND] 0.5");
+        assert_eq!(
+            attention.should_reply(&context(None)).await,
+            AttentionCommand::Ignore
+        );
+
+        let attention = attention_with("[RESPOND] 0.9");
+        assert_eq!(
+            attention.should_reply(&context(None)).await,
+            AttentionCommand::Respond
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cooldown() {
+        let attention = attention_with("[RESPOND] 0.9");
+        assert_eq!(
+            attention.should_reply(&context(Some(2))).await,
+            AttentionCommand::Ignore
+        );
+        assert_eq!(attention.completion_model.calls(), 0);
+
+        assert_eq!(
+            attention.should_reply(&context(Some(3))).await,
+            AttentionCommand::Respond
+        );
+    }
+
+    #[test]
+    fn test_is_mentioned() {
+        let attention = attention_with("[IGNORE]");
+        let mut context = context(None);
+
+        context.message_content = "shinobixyz is a great name".to_string();
+        assert!(!attention.is_mentioned(&context));
+        context.message_content = "what do you think, Shinobi?".to_string();
+        assert!(attention.is_mentioned(&context));
+
+        context.message_content = "and what about models?".to_string();
+        assert!(!attention.is_mentioned(&context));
+        context.is_reply_to_bot = true;
+        assert!(attention.is_mentioned(&context));
+
+        context.is_reply_to_bot = false;
+        context.bot_id = Some("42".to_string());
+        context.mentioned_ids.insert("42".to_string());
+        assert!(attention.is_mentioned(&context));
+    }
+
+    #[tokio::test]
+    async fn test_character_rules() {
+        let character: Character = toml::from_str(
+            r#"
+name = "Shinobi"
+preamble = "You are a support bot."
+
+[attention]
+bot_names = ["shinobai"]
+deny_channels = ["announcements"]
+keywords = ["controller"]
+stop_phrases = ["go (away|home)"]
+decision_prompt = "Only talk about games."
+
+[attention.channel_types]
+thread = "mentioned"
+direct_message = "never"
+"#,
+        )
+        .unwrap();
+        let config = AttentionConfig::from_character(&character).unwrap();
+        assert_eq!(config.bot_names, vec!["Shinobi", "shinobai"]);
+        assert_eq!(
+            config.decision_prompt.as_deref(),
+            Some("Only talk about games.")
+        );
+        let attention = attention_with_config(config, "[IGNORE] 0.1");
+
+        let mut context = context(None);
+        context.message_content = "my controller broke".to_string();
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Respond
+        );
+        context.channel_id = "announcements".to_string();
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Ignore
+        );
+
+        context.channel_id = "general".to_string();
+        context.message_content = "ok shinobi, GO HOME now".to_string();
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Stop
+        );
+        context.message_content = "please stop".to_string();
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Ignore
+        );
+
+        context.channel_type = ChannelType::Thread;
+        context.message_content = "anyone know how dojo models work?".to_string();
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Ignore
+        );
+        assert_eq!(attention.completion_model.calls(), 1);
+
+        context.channel_type = ChannelType::DirectMessage;
+        context.message_content = "hey shinobai".to_string();
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Ignore
+        );
+    }
+
+    #[test]
+    fn test_character_reload() {
+        let character = |keyword: &str| -> Character {
+            toml::from_str(&format!(
+                "name = \"Shinobi\"\npreamble = \"You are a support bot.\"\n\n[attention]\nkeywords = [\"{keyword}\"]"
+            ))
+            .unwrap()
+        };
+        let handle = CharacterHandle::new(character("dojo"));
+        let config = AttentionConfig {
+            admin_ids: vec!["admin".to_string()],
+            ..Default::default()
+        };
+        let attention =
+            Attention::new(config, TestModel::new("[IGNORE]")).with_character(handle.clone());
+        assert_eq!(attention.config().keywords, vec!["dojo"]);
+
+        handle.swap(character("katana"));
+        let config = attention.config();
+        assert_eq!(config.keywords, vec!["katana"]);
+        assert_eq!(config.bot_names, vec!["Shinobi"]);
+        assert_eq!(config.admin_ids, vec!["admin"]);
+    }
+
+    #[test]
+    fn test_is_stop_request() {
+        let attention = attention_with("[IGNORE]");
+        let mut context = context(None);
+
+        context.message_content = "wtf is this error".to_string();
+        assert!(!attention.is_stop_request(&context));
+        context.message_content = "shinobi, wtf is this".to_string();
+        assert!(attention.is_stop_request(&context));
+
+        context.message_content = "please stop".to_string();
+        assert!(!attention.is_stop_request(&context));
+        context.engagement = Some(Engagement {
+            user_id: "alice".to_string(),
+            turns_left: 1,
+            expires_at: Instant::now() + Duration::from_secs(60),
+        });
+        assert!(attention.is_stop_request(&context));
+
+        context.channel_type = ChannelType::DirectMessage;
+        assert!(!attention.is_stop_request(&context));
+    }
+
+    #[tokio::test]
+    async fn test_stop_phrase_in_direct_message() {
+        let attention = attention_with("[IGNORE]");
+        let mut context = context(None);
+        context.channel_type = ChannelType::DirectMessage;
+        context.message_content = "how do I stop my controller from drifting".to_string();
+
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Respond
+        );
+    }
+
+    #[test]
+    fn test_engagement() {
+        let attention = attention_with("[IGNORE]");
+        assert_eq!(attention.engagement("general", "alice"), None);
+
+        attention.engage("general", "alice");
+        assert_eq!(attention.engagement("general", "bob"), None);
+        for turns_left in (1..=3).rev() {
+            let engagement = attention.engagement("general", "alice").unwrap();
+            assert_eq!(engagement.turns_left, turns_left);
+        }
+        assert_eq!(attention.engagement("general", "alice"), None);
+
+        attention.engage("general", "alice");
+        attention.disengage("general");
+        assert_eq!(attention.engagement("general", "alice"), None);
+    }
+
+    #[tokio::test]
+    async fn test_engagement_skips_cooldown() {
+        let attention = attention_with("[RESPOND] 0.9");
+        attention.engage("general", "alice");
+
+        let mut context = context(Some(1));
+        context.engagement = attention.engagement("general", "alice");
+        assert_eq!(
+            attention.should_reply(&context).await,
+            AttentionCommand::Respond
+        );
+    }
 }
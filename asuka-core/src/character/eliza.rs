@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{Character, CharacterError, Message, MessageContent, MessageExample, Style};
+
+/// Eliza spells the agent name placeholder differently.
+const ELIZA_AGENT_NAME: &str = "{{agentName}}";
+const AGENT_NAME: &str = "{{agent_name}}";
+
+/// A character in the Eliza `character.json` format.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ElizaCharacter {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    #[serde(default)]
+    pub bio: Bio,
+    #[serde(default)]
+    pub lore: Vec<String>,
+    #[serde(default)]
+    pub message_examples: Vec<Vec<ElizaMessage>>,
+    #[serde(default)]
+    pub post_examples: Vec<String>,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default)]
+    pub style: Style,
+    #[serde(default)]
+    pub adjectives: Vec<String>,
+    /// Fields without an equivalent in our schema, such as `clients` or `settings`.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+/// Eliza accepts the bio as a single string or a list of lines.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Bio {
+    Text(String),
+    Lines(Vec<String>),
+}
+
+impl Default for Bio {
+    fn default() -> Self {
+        Bio::Lines(Vec::new())
+    }
+}
+
+impl Bio {
+    fn into_text(self) -> String {
+        match self {
+            Bio::Text(text) => text,
+            Bio::Lines(lines) => lines.join("\n"),
+        }
+    }
+
+    fn into_lines(self) -> Vec<String> {
+        match self {
+            Bio::Text(text) if text.is_empty() => Vec::new(),
+            Bio::Text(text) => vec![text],
+            Bio::Lines(lines) => lines,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ElizaMessage {
+    pub user: String,
+    pub content: ElizaContent,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ElizaContent {
+    pub text: String,
+    /// Extra content fields such as `action`.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
+}
+
+impl Character {
+    /// Parses an Eliza `character.json`. Returns the character along with
+    /// warnings for every field that couldn't be represented.
+    ///
+    /// The `system` prompt becomes the preamble and the bio is kept as lore. Without
+    /// a `system` prompt the bio itself becomes the preamble.
+    pub fn from_eliza_json(json: &str) -> Result<(Self, Vec<String>), CharacterError> {
+        let eliza: ElizaCharacter =
+            serde_json::from_str(&json.replace(ELIZA_AGENT_NAME, AGENT_NAME))?;
+        let mut warnings: Vec<String> = eliza
+            .other
+            .keys()
+            .map(|key| format!("Field `{key}` is not supported and was dropped"))
+            .collect();
+
+        let (preamble, mut lore) = match eliza.system {
+            Some(system) => (system, eliza.bio.into_lines()),
+            None => (eliza.bio.into_text(), Vec::new()),
+        };
+        lore.extend(eliza.lore);
+
+        let message_examples = eliza
+            .message_examples
+            .into_iter()
+            .enumerate()
+            .map(|(i, messages)| MessageExample {
+                messages: messages
+                    .into_iter()
+                    .enumerate()
+                    .map(|(j, msg)| {
+                        warnings.extend(msg.content.other.keys().map(|key| {
+                            format!(
+                                "Field `messageExamples[{i}][{j}].content.{key}` is not supported and was dropped"
+                            )
+                        }));
+                        Message {
+                            user: msg.user,
+                            content: MessageContent {
+                                text: msg.content.text,
+                            },
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let character = Self {
+            name: eliza.name,
+            preamble,
+            lore,
+            message_examples,
+            post_examples: eliza.post_examples,
+            topics: eliza.topics,
+            style: eliza.style,
+            adjectives: eliza.adjectives,
+            platforms: Default::default(),
+            attention: Default::default(),
+        };
+        character.validate_templates()?;
+
+        Ok((character, warnings))
+    }
+
+    /// Serializes the character as an Eliza `character.json`, with warnings for
+    /// every field that couldn't be represented.
+    ///
+    /// The preamble is written as the `system` prompt, so importing the file
+    /// again restores it. The bio is left empty as imported bios live in the lore.
+    pub fn to_eliza_json(&self) -> Result<(String, Vec<String>), CharacterError> {
+        let mut warnings = Vec::new();
+        if !self.platforms.is_empty() {
+            warnings.push(
+                "Field `platforms` is not supported by the Eliza format and was dropped"
+                    .to_string(),
+            );
+        }
+        if !self.attention.is_empty() {
+            warnings.push(
+                "Field `attention` is not supported by the Eliza format and was dropped"
+                    .to_string(),
+            );
+        }
+
+        let eliza = ElizaCharacter {
+            name: self.name.clone(),
+            system: Some(self.preamble.clone()),
+            bio: Bio::default(),
+            lore: self.lore.clone(),
+            message_examples: self
+                .message_examples
+                .iter()
+                .map(|example| {
+                    example
+                        .messages
+                        .iter()
+                        .map(|msg| ElizaMessage {
+                            user: msg.user.clone(),
+                            content: ElizaContent {
+                                text: msg.content.text.clone(),
+                                other: Map::new(),
+                            },
+                        })
+                        .collect()
+                })
+                .collect(),
+            post_examples: self.post_examples.clone(),
+            topics: self.topics.clone(),
+            style: self.style.clone(),
+            adjectives: self.adjectives.clone(),
+            other: Map::new(),
+        };
+
+        let json = serde_json::to_string_pretty(&eliza)?.replace(AGENT_NAME, ELIZA_AGENT_NAME);
+
+        Ok((json, warnings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ELIZA_JSON: &str = r#"{
+        "name": "Shinobi",
+        "clients": ["discord"],
+        "bio": ["Cartridge support AI", "Knows {{agentName}} lore"],
+        "lore": ["Once debugged a controller blindfolded"],
+        "messageExamples": [[
+            {"user": "{{user1}}", "content": {"text": "My controller isn't working"}},
+            {"user": "Shinobi", "content": {"text": "Check the FAQ", "action": "NONE"}}
+        ]],
+        "postExamples": ["check the FAQ first"],
+        "topics": ["Controller troubleshooting"],
+        "style": {"all": ["terse"], "chat": ["link docs"], "post": []},
+        "adjectives": ["unfussy"]
+    }"#;
+
+    #[test]
+    fn test_import_eliza() {
+        let (character, warnings) = Character::from_eliza_json(ELIZA_JSON).unwrap();
+
+        assert_eq!(
+            character.preamble,
+            "Cartridge support AI\nKnows {{agent_name}} lore"
+        );
+        assert_eq!(
+            character.lore,
+            vec!["Once debugged a controller blindfolded"]
+        );
+        assert_eq!(character.message_examples[0].messages.len(), 2);
+        assert_eq!(character.style.chat, vec!["link docs"]);
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].contains("clients"));
+        assert!(warnings[1].contains("action"));
+    }
+
+    #[test]
+    fn test_eliza_round_trip() {
+        let (character, _) = Character::from_eliza_json(ELIZA_JSON).unwrap();
+        let (json, warnings) = character.to_eliza_json().unwrap();
+        assert!(warnings.is_empty());
+        assert!(json.contains("{{agentName}}"));
+
+        let (reimported, warnings) = Character::from_eliza_json(&json).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(reimported, character);
+
+        let toml = character.to_toml().unwrap();
+        let from_toml: Character = toml::from_str(&toml).unwrap();
+        assert_eq!(from_toml, character);
+    }
+
+    #[test]
+    fn test_eliza_round_trip_with_system() {
+        let json = ELIZA_JSON.replace(
+            r#""bio":"#,
+            r#""system": "You are {{agentName}}, a support bot.", "bio":"#,
+        );
+        let (character, _) = Character::from_eliza_json(&json).unwrap();
+        assert_eq!(character.preamble, "You are {{agent_name}}, a support bot.");
+        assert_eq!(
+            character.lore,
+            vec![
+                "Cartridge support AI",
+                "Knows {{agent_name}} lore",
+                "Once debugged a controller blindfolded"
+            ]
+        );
+
+        let (exported, _) = character.to_eliza_json().unwrap();
+        let (reimported, warnings) = Character::from_eliza_json(&exported).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(reimported, character);
+    }
+}
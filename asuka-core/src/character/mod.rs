@@ -0,0 +1,892 @@
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+};
+use thiserror::Error;
+use tracing::{debug, info};
+
+use crate::{
+    attention::{self, ChannelBehavior},
+    knowledge::{ChannelType, Source},
+};
+
+mod eliza;
+mod watcher;
+
+pub use eliza::{Bio, ElizaCharacter, ElizaContent, ElizaMessage};
+pub use watcher::{CharacterHandle, CharacterWatcher};
+
+/// Variables that can be used as `{{variable}}` placeholders in character files.
+/// Numbered speakers such as `{{user1}}` are accepted as well.
+pub const TEMPLATE_VARIABLES: &[&str] =
+    &["agent_name", "user", "platform", "channel", "date", "time"];
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("Unknown template variable {{{{{variable}}}}} in {field}")]
+    UnknownVariable { field: String, variable: String },
+}
+
+#[derive(Error, Debug)]
+pub enum CharacterError {
+    #[error("Failed to read character file {path}: {source}")]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Failed to parse character file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        #[source]
+        source: toml::de::Error,
+    },
+
+    #[error("Character inheritance cycle: {}", display_chain(.0))]
+    Cycle(Vec<PathBuf>),
+
+    #[error("Character file {path} is missing required field `{field}`")]
+    MissingField { path: PathBuf, field: &'static str },
+
+    #[error("Unknown platform `{platform}` in character file {path}")]
+    UnknownPlatform { path: PathBuf, platform: String },
+
+    #[error("Unknown channel type `{channel_type}` in character file {path}")]
+    UnknownChannelType { path: PathBuf, channel_type: String },
+
+    #[error("Invalid stop phrase `{pattern}` in character file {path}: {source}")]
+    StopPhrase {
+        path: PathBuf,
+        pattern: String,
+        #[source]
+        source: regex::Error,
+    },
+
+    #[error("Invalid Eliza character: {0}")]
+    Eliza(#[from] serde_json::Error),
+
+    #[error("Failed to serialize character: {0}")]
+    Serialize(#[from] toml::ser::Error),
+
+    #[error(transparent)]
+    Template(#[from] TemplateError),
+}
+
+fn display_chain(chain: &[PathBuf]) -> String {
+    chain
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Character {
+    pub name: String,
+    pub preamble: String,
+    #[serde(default)]
+    pub lore: Vec<String>,
+    #[serde(default)]
+    pub message_examples: Vec<MessageExample>,
+    #[serde(default)]
+    pub post_examples: Vec<String>,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    #[serde(default)]
+    pub style: Style,
+    #[serde(default)]
+    pub adjectives: Vec<String>,
+    /// Per-platform overrides keyed by [`Source`] name, e.g. `[platforms.discord]`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub platforms: BTreeMap<String, PlatformPersona>,
+    /// When the character joins a conversation, from the `[attention]` section.
+    #[serde(default, skip_serializing_if = "AttentionRules::is_empty")]
+    pub attention: AttentionRules,
+}
+
+impl Character {
+    /// Loads a character file, resolving `extends` and `mixins` relative to the
+    /// file that declares them.
+    ///
+    /// Fields are merged base first, then mixins in order, then the file itself:
+    /// `name` and `preamble` are overridden by the last file that sets them, list
+    /// fields (`lore`, `topics`, `adjectives`, examples and each `style` list) are
+    /// appended, skipping duplicate entries.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, CharacterError> {
+        Self::load_with_files(path.as_ref()).map(|(character, _)| character)
+    }
+
+    /// Loads a character and returns every file it was assembled from.
+    fn load_with_files(path: &Path) -> Result<(Self, Vec<PathBuf>), CharacterError> {
+        info!(path = %path.display(), "Loading character configuration");
+
+        let mut files = Vec::new();
+        let file = CharacterFile::resolve(path, &mut Vec::new(), &mut files)?;
+        let character = file.into_character(path)?;
+        character.validate_templates()?;
+        debug!(
+            name = character.name,
+            lore = character.lore.len(),
+            message_examples = character.message_examples.len(),
+            post_examples = character.post_examples.len(),
+            topics = character.topics.len(),
+            "Character loaded successfully"
+        );
+        Ok((character, files))
+    }
+
+    /// Serializes the character in the TOML format read by [`Character::load`].
+    pub fn to_toml(&self) -> Result<String, CharacterError> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// Resolves the persona overrides for a platform, layering the direct message
+    /// or group section on top of the platform defaults.
+    pub fn persona_for(&self, source: &Source, channel_type: &ChannelType) -> Persona {
+        let Some(platform) = self.platforms.get(source.as_str()) else {
+            return Persona::default();
+        };
+
+        let scoped = match channel_type {
+            ChannelType::DirectMessage => platform.direct_message.clone(),
+            _ => platform.group.clone(),
+        };
+
+        match scoped {
+            Some(scoped) => platform.persona.clone().merge(scoped),
+            None => platform.persona.clone(),
+        }
+    }
+
+    /// Checks that every placeholder used in the character is a known variable,
+    /// so typos are reported at load time instead of reaching the prompt.
+    pub fn validate_templates(&self) -> Result<(), TemplateError> {
+        let mut fields: Vec<(String, &str)> =
+            vec![("preamble".to_string(), self.preamble.as_str())];
+
+        fields.extend(
+            self.lore
+                .iter()
+                .enumerate()
+                .map(|(i, text)| (format!("lore[{i}]"), text.as_str())),
+        );
+        for (i, example) in self.message_examples.iter().enumerate() {
+            for (j, msg) in example.messages.iter().enumerate() {
+                let field = format!("message_examples[{i}].messages[{j}]");
+                fields.push((format!("{field}.user"), msg.user.as_str()));
+                fields.push((format!("{field}.content.text"), msg.content.text.as_str()));
+            }
+        }
+        fields.extend(
+            self.post_examples
+                .iter()
+                .enumerate()
+                .map(|(i, text)| (format!("post_examples[{i}]"), text.as_str())),
+        );
+        fields.extend(
+            self.topics
+                .iter()
+                .enumerate()
+                .map(|(i, text)| (format!("topics[{i}]"), text.as_str())),
+        );
+        for (name, lines) in [
+            ("style.all", &self.style.all),
+            ("style.chat", &self.style.chat),
+            ("style.post", &self.style.post),
+        ] {
+            fields.extend(
+                lines
+                    .iter()
+                    .enumerate()
+                    .map(|(i, text)| (format!("{name}[{i}]"), text.as_str())),
+            );
+        }
+        fields.extend(
+            self.adjectives
+                .iter()
+                .enumerate()
+                .map(|(i, text)| (format!("adjectives[{i}]"), text.as_str())),
+        );
+        for (platform, persona) in &self.platforms {
+            let scopes = [
+                (String::new(), Some(&persona.persona)),
+                (
+                    ".direct_message".to_string(),
+                    persona.direct_message.as_ref(),
+                ),
+                (".group".to_string(), persona.group.as_ref()),
+            ];
+            for (scope, persona) in scopes {
+                let Some(persona) = persona else { continue };
+                for (name, lines) in [
+                    ("style", &persona.style),
+                    ("context", &persona.context),
+                    ("forbidden_formatting", &persona.forbidden_formatting),
+                ] {
+                    fields.extend(lines.iter().enumerate().map(|(i, text)| {
+                        (
+                            format!("platforms.{platform}{scope}.{name}[{i}]"),
+                            text.as_str(),
+                        )
+                    }));
+                }
+            }
+        }
+        if let Some(prompt) = &self.attention.decision_prompt {
+            fields.push(("attention.decision_prompt".to_string(), prompt.as_str()));
+        }
+
+        for (field, text) in fields {
+            for captures in template_regex().captures_iter(text) {
+                let variable = &captures[1];
+                if !is_known_variable(variable) {
+                    return Err(TemplateError::UnknownVariable {
+                        field,
+                        variable: variable.to_string(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Describes where a prompt is sent. Used to pick the platform persona and to
+/// fill character templates.
+#[derive(Clone, Debug, Default)]
+pub struct PromptContext {
+    pub source: Option<Source>,
+    pub channel_type: Option<ChannelType>,
+    pub user: Option<String>,
+    pub channel: Option<String>,
+    /// Added to the system prompt as is, e.g. what is happening in a game.
+    pub context: Option<String>,
+}
+
+impl PromptContext {
+    pub fn variables(&self, character: &Character) -> HashMap<String, String> {
+        let now = chrono::Local::now();
+        HashMap::from([
+            ("agent_name".to_string(), character.name.clone()),
+            (
+                "user".to_string(),
+                self.user.clone().unwrap_or_else(|| "User".to_string()),
+            ),
+            (
+                "platform".to_string(),
+                self.source
+                    .as_ref()
+                    .map(|source| source.as_str().to_string())
+                    .unwrap_or_else(|| "chat".to_string()),
+            ),
+            (
+                "channel".to_string(),
+                self.channel
+                    .clone()
+                    .unwrap_or_else(|| "this channel".to_string()),
+            ),
+            ("date".to_string(), now.format("%Y-%m-%d").to_string()),
+            ("time".to_string(), now.format("%I:%M %p").to_string()),
+        ])
+    }
+}
+
+/// Replaces `{{variable}}` placeholders with their values. Numbered speakers
+/// without an explicit value render as `User1`, `User2`, ...
+pub fn render_template(template: &str, variables: &HashMap<String, String>) -> String {
+    template_regex()
+        .replace_all(template, |captures: &regex::Captures| {
+            let variable = &captures[1];
+            match variables.get(variable) {
+                Some(value) => value.clone(),
+                None => match variable.strip_prefix("user") {
+                    Some(n) if is_numbered(n) => format!("User{n}"),
+                    _ => captures[0].to_string(),
+                },
+            }
+        })
+        .into_owned()
+}
+
+fn template_regex() -> &'static Regex {
+    static TEMPLATE_REGEX: OnceLock<Regex> = OnceLock::new();
+    TEMPLATE_REGEX.get_or_init(|| Regex::new(r"\{\{\s*([A-Za-z0-9_]+)\s*\}\}").unwrap())
+}
+
+fn is_known_variable(variable: &str) -> bool {
+    TEMPLATE_VARIABLES.contains(&variable) || variable.strip_prefix("user").is_some_and(is_numbered)
+}
+
+fn is_numbered(suffix: &str) -> bool {
+    !suffix.is_empty() && suffix.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Platform specific overrides from a `[platforms.<source>]` section. The
+/// optional `direct_message` and `group` tables refine it per channel type.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlatformPersona {
+    #[serde(flatten)]
+    pub persona: Persona,
+    pub direct_message: Option<Persona>,
+    pub group: Option<Persona>,
+}
+
+impl PlatformPersona {
+    fn merge(self, overlay: Self) -> Self {
+        let merge_scope = |base: Option<Persona>, overlay: Option<Persona>| match (base, overlay) {
+            (Some(base), Some(overlay)) => Some(base.merge(overlay)),
+            (base, overlay) => overlay.or(base),
+        };
+
+        Self {
+            persona: self.persona.merge(overlay.persona),
+            direct_message: merge_scope(self.direct_message, overlay.direct_message),
+            group: merge_scope(self.group, overlay.group),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Persona {
+    /// Extra style guidelines for this platform.
+    #[serde(default)]
+    pub style: Vec<String>,
+    /// Maximum response length in characters.
+    pub max_length: Option<usize>,
+    /// Additional context added to the prompt.
+    #[serde(default)]
+    pub context: Vec<String>,
+    /// Formatting the platform can't render, e.g. "tables" or "headings".
+    #[serde(default)]
+    pub forbidden_formatting: Vec<String>,
+}
+
+impl Persona {
+    fn merge(mut self, overlay: Self) -> Self {
+        append_unique(&mut self.style, overlay.style);
+        append_unique(&mut self.context, overlay.context);
+        append_unique(&mut self.forbidden_formatting, overlay.forbidden_formatting);
+        if overlay.max_length.is_some() {
+            self.max_length = overlay.max_length;
+        }
+        self
+    }
+}
+
+/// Rules from the `[attention]` section deciding when the character replies.
+/// Anything left out keeps the defaults of [`crate::attention::AttentionConfig`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AttentionRules {
+    /// Other names the character answers to, besides its `name`.
+    pub bot_names: Vec<String>,
+    /// Channel ids the character replies in. Empty allows every channel.
+    pub allow_channels: Vec<String>,
+    /// Channel ids the character never replies in.
+    pub deny_channels: Vec<String>,
+    /// Words that make the character reply, like its name does.
+    pub keywords: Vec<String>,
+    /// Regular expressions that tell the character to stop talking, matched
+    /// case-insensitively. Replaces the built-in phrases when set.
+    pub stop_phrases: Vec<String>,
+    /// Behavior keyed by channel type, e.g. `thread = "mentioned"`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub channel_types: BTreeMap<String, ChannelBehavior>,
+    /// Instructions of the prompt deciding whether to reply. Can use the same
+    /// `{{variable}}` placeholders as the preamble.
+    pub decision_prompt: Option<String>,
+    pub reply_threshold: Option<f32>,
+    pub cooldown_messages: Option<i64>,
+}
+
+impl AttentionRules {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn merge(mut self, overlay: Self) -> Self {
+        append_unique(&mut self.bot_names, overlay.bot_names);
+        append_unique(&mut self.allow_channels, overlay.allow_channels);
+        append_unique(&mut self.deny_channels, overlay.deny_channels);
+        append_unique(&mut self.keywords, overlay.keywords);
+        append_unique(&mut self.stop_phrases, overlay.stop_phrases);
+        self.channel_types.extend(overlay.channel_types);
+        if overlay.decision_prompt.is_some() {
+            self.decision_prompt = overlay.decision_prompt;
+        }
+        if overlay.reply_threshold.is_some() {
+            self.reply_threshold = overlay.reply_threshold;
+        }
+        if overlay.cooldown_messages.is_some() {
+            self.cooldown_messages = overlay.cooldown_messages;
+        }
+        self
+    }
+}
+
+/// A character file as written on disk, before inheritance is resolved.
+#[derive(Debug, Default, Deserialize)]
+struct CharacterFile {
+    extends: Option<String>,
+    #[serde(default)]
+    mixins: Vec<String>,
+    name: Option<String>,
+    preamble: Option<String>,
+    #[serde(default)]
+    lore: Vec<String>,
+    #[serde(default)]
+    message_examples: Vec<MessageExample>,
+    #[serde(default)]
+    post_examples: Vec<String>,
+    #[serde(default)]
+    topics: Vec<String>,
+    #[serde(default)]
+    style: Style,
+    #[serde(default)]
+    adjectives: Vec<String>,
+    #[serde(default)]
+    platforms: BTreeMap<String, PlatformPersona>,
+    #[serde(default)]
+    attention: AttentionRules,
+}
+
+impl CharacterFile {
+    fn read(path: &Path) -> Result<Self, CharacterError> {
+        let content = std::fs::read_to_string(path).map_err(|source| CharacterError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        toml::from_str(&content).map_err(|source| CharacterError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    /// Reads `path` and merges it on top of its parents. `stack` holds the files
+    /// currently being resolved and is used to detect cycles, `files` collects
+    /// every file visited.
+    fn resolve(
+        path: &Path,
+        stack: &mut Vec<PathBuf>,
+        files: &mut Vec<PathBuf>,
+    ) -> Result<Self, CharacterError> {
+        let canonical = path.canonicalize().map_err(|source| CharacterError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        if stack.contains(&canonical) {
+            let mut chain = stack.clone();
+            chain.push(canonical);
+            return Err(CharacterError::Cycle(chain));
+        }
+
+        let file = Self::read(&canonical)?;
+        if !files.contains(&canonical) {
+            files.push(canonical.clone());
+        }
+        let dir = canonical.parent().unwrap_or(Path::new("."));
+
+        stack.push(canonical.clone());
+
+        let mut merged = match &file.extends {
+            Some(parent) => {
+                debug!(path = %canonical.display(), extends = parent, "Resolving parent character");
+                Self::resolve(&dir.join(parent), stack, files)?
+            }
+            None => Self::default(),
+        };
+
+        for mixin in &file.mixins {
+            debug!(path = %canonical.display(), mixin = mixin, "Resolving character mixin");
+            merged = merged.merge(Self::resolve(&dir.join(mixin), stack, files)?);
+        }
+
+        stack.pop();
+
+        Ok(merged.merge(file))
+    }
+
+    fn merge(mut self, overlay: Self) -> Self {
+        if overlay.name.is_some() {
+            self.name = overlay.name;
+        }
+        if overlay.preamble.is_some() {
+            self.preamble = overlay.preamble;
+        }
+
+        append_unique(&mut self.lore, overlay.lore);
+        append_unique(&mut self.post_examples, overlay.post_examples);
+        append_unique(&mut self.topics, overlay.topics);
+        append_unique(&mut self.adjectives, overlay.adjectives);
+        append_unique(&mut self.style.all, overlay.style.all);
+        append_unique(&mut self.style.chat, overlay.style.chat);
+        append_unique(&mut self.style.post, overlay.style.post);
+        self.message_examples.extend(overlay.message_examples);
+
+        for (platform, persona) in overlay.platforms {
+            let merged = match self.platforms.remove(&platform) {
+                Some(base) => base.merge(persona),
+                None => persona,
+            };
+            self.platforms.insert(platform, merged);
+        }
+        self.attention = self.attention.merge(overlay.attention);
+
+        self
+    }
+
+    fn into_character(self, path: &Path) -> Result<Character, CharacterError> {
+        let missing = |field| CharacterError::MissingField {
+            path: path.to_path_buf(),
+            field,
+        };
+
+        // Platforms are matched case-insensitively, so keys are normalized to
+        // the name `persona_for` looks up, merging sections that only differ by case
+        let mut platforms: BTreeMap<String, PlatformPersona> = BTreeMap::new();
+        for (platform, persona) in self.platforms {
+            let Ok(source) = Source::from_str(&platform) else {
+                return Err(CharacterError::UnknownPlatform {
+                    path: path.to_path_buf(),
+                    platform,
+                });
+            };
+            let merged = match platforms.remove(source.as_str()) {
+                Some(base) => base.merge(persona),
+                None => persona,
+            };
+            platforms.insert(source.as_str().to_string(), merged);
+        }
+        if let Some(channel_type) = self
+            .attention
+            .channel_types
+            .keys()
+            .find(|channel_type| ChannelType::from_str(channel_type).is_err())
+        {
+            return Err(CharacterError::UnknownChannelType {
+                path: path.to_path_buf(),
+                channel_type: channel_type.clone(),
+            });
+        }
+        for pattern in &self.attention.stop_phrases {
+            attention::stop_phrase(pattern).map_err(|source| CharacterError::StopPhrase {
+                path: path.to_path_buf(),
+                pattern: pattern.clone(),
+                source,
+            })?;
+        }
+
+        Ok(Character {
+            name: self.name.ok_or_else(|| missing("name"))?,
+            preamble: self.preamble.ok_or_else(|| missing("preamble"))?,
+            lore: self.lore,
+            message_examples: self.message_examples,
+            post_examples: self.post_examples,
+            topics: self.topics,
+            style: self.style,
+            adjectives: self.adjectives,
+            platforms,
+            attention: self.attention,
+        })
+    }
+}
+
+fn append_unique(target: &mut Vec<String>, items: Vec<String>) {
+    for item in items {
+        if !target.contains(&item) {
+            target.push(item);
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageExample {
+    pub messages: Vec<Message>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Message {
+    pub user: String,
+    pub content: MessageContent,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MessageContent {
+    pub text: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Style {
+    #[serde(default)]
+    pub all: Vec<String>,
+    #[serde(default)]
+    pub chat: Vec<String>,
+    #[serde(default)]
+    pub post: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn character(preamble: &str) -> Character {
+        toml::from_str(&format!("name = \"Shinobi\"\npreamble = \"{preamble}\"")).unwrap()
+    }
+
+    pub(super) struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub(super) fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("asuka-{}-{}", name, std::process::id()));
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        pub(super) fn write(&self, file: &str, content: &str) -> PathBuf {
+            let path = self.0.join(file);
+            std::fs::write(&path, content).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_load_merges_parents_and_mixins() {
+        let dir = TempDir::new("character-merge");
+        dir.write(
+            "base.toml",
+            r#"
+name = "Base"
+preamble = "You are a support bot."
+topics = ["Controller", "Tech"]
+
+[style]
+all = ["Be terse"]
+"#,
+        );
+        dir.write(
+            "pirate.toml",
+            r#"
+adjectives = ["salty"]
+
+[style]
+chat = ["Talk like a pirate"]
+"#,
+        );
+        let path = dir.write(
+            "shinobi.toml",
+            r#"
+extends = "base.toml"
+mixins = ["pirate.toml"]
+name = "Shinobi"
+topics = ["Tech", "Philosophy"]
+
+[style]
+all = ["Be terse", "No emojis"]
+"#,
+        );
+
+        let character = Character::load(&path).unwrap();
+        assert_eq!(character.name, "Shinobi");
+        assert_eq!(character.preamble, "You are a support bot.");
+        assert_eq!(character.topics, vec!["Controller", "Tech", "Philosophy"]);
+        assert_eq!(character.adjectives, vec!["salty"]);
+        assert_eq!(character.style.all, vec!["Be terse", "No emojis"]);
+        assert_eq!(character.style.chat, vec!["Talk like a pirate"]);
+    }
+
+    #[test]
+    fn test_load_example_character() {
+        let path =
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("../examples/src/characters/shinobi.toml");
+        let character = Character::load(&path).unwrap();
+
+        assert!(!character.post_examples.is_empty());
+        assert!(!character.topics.is_empty());
+        assert!(!character.adjectives.is_empty());
+        assert!(!character.message_examples.is_empty());
+        assert!(!character.style.all.is_empty());
+        assert!(!character.style.chat.is_empty());
+        assert!(!character.style.post.is_empty());
+    }
+
+    #[test]
+    fn test_persona_for_channel_type() {
+        let character: Character = toml::from_str(
+            r#"
+name = "Shinobi"
+preamble = "You are a support bot."
+
+[platforms.discord]
+max_length = 2000
+forbidden_formatting = ["tables"]
+
+[platforms.discord.direct_message]
+max_length = 4000
+style = ["Be more personal"]
+"#,
+        )
+        .unwrap();
+
+        let dm = character.persona_for(&Source::Discord, &ChannelType::DirectMessage);
+        assert_eq!(dm.max_length, Some(4000));
+        assert_eq!(dm.style, vec!["Be more personal"]);
+        assert_eq!(dm.forbidden_formatting, vec!["tables"]);
+
+        let group = character.persona_for(&Source::Discord, &ChannelType::Text);
+        assert_eq!(group.max_length, Some(2000));
+        assert!(group.style.is_empty());
+
+        let telegram = character.persona_for(&Source::Telegram, &ChannelType::Text);
+        assert_eq!(telegram.max_length, None);
+    }
+
+    #[test]
+    fn test_load_normalizes_platforms() {
+        let dir = TempDir::new("character-platforms");
+        let path = dir.write(
+            "shinobi.toml",
+            r#"
+name = "Shinobi"
+preamble = "You are a support bot."
+
+[platforms.Discord]
+max_length = 2000
+
+[platforms.discord]
+style = ["Use Discord markdown"]
+"#,
+        );
+        let character = Character::load(&path).unwrap();
+
+        assert_eq!(
+            character.platforms.keys().collect::<Vec<_>>(),
+            vec!["discord"]
+        );
+        let persona = character.persona_for(&Source::Discord, &ChannelType::Text);
+        assert_eq!(persona.max_length, Some(2000));
+        assert_eq!(persona.style, vec!["Use Discord markdown"]);
+    }
+
+    #[test]
+    fn test_load_detects_cycles() {
+        let dir = TempDir::new("character-cycle");
+        dir.write(
+            "a.toml",
+            "extends = \"b.toml\"\nname = \"A\"\npreamble = \"a\"",
+        );
+        let path = dir.write("b.toml", "extends = \"a.toml\"");
+
+        assert!(matches!(
+            Character::load(&path),
+            Err(CharacterError::Cycle(chain)) if chain.len() == 3
+        ));
+    }
+
+    #[test]
+    fn test_load_validates_attention() {
+        let dir = TempDir::new("character-attention");
+        let path = dir.write(
+            "shinobi.toml",
+            r#"
+name = "Shinobi"
+preamble = "You are a support bot."
+
+[attention.channel_types]
+forum = "never"
+"#,
+        );
+        assert!(matches!(
+            Character::load(&path),
+            Err(CharacterError::UnknownChannelType { channel_type, .. }) if channel_type == "forum"
+        ));
+
+        let path = dir.write(
+            "shinobi.toml",
+            r#"
+name = "Shinobi"
+preamble = "You are a support bot."
+
+[attention]
+stop_phrases = ["(go away"]
+"#,
+        );
+        assert!(matches!(
+            Character::load(&path),
+            Err(CharacterError::StopPhrase { pattern, .. }) if pattern == "(go away"
+        ));
+    }
+
+    #[test]
+    fn test_render_template() {
+        let variables = HashMap::from([
+            ("agent_name".to_string(), "Shinobi".to_string()),
+            ("user".to_string(), "alice".to_string()),
+        ]);
+        let rendered = render_template(
+            "{{agent_name}} talks to {{ user }} and {{user2}}",
+            &variables,
+        );
+        assert_eq!(rendered, "Shinobi talks to alice and User2");
+    }
+
+    #[test]
+    fn test_validate_templates_accepts_known_variables() {
+        let character = character("You are {{agent_name}} on {{platform}}, today is {{date}}");
+        assert!(character.validate_templates().is_ok());
+    }
+
+    #[test]
+    fn test_validate_templates_rejects_unknown_variables() {
+        let character = character("You are {{agent}}");
+        let err = character.validate_templates().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown template variable {{agent}} in preamble"
+        );
+    }
+
+    #[test]
+    fn test_validate_templates_checks_attention_and_platforms() {
+        let mut character = character("You are {{agent_name}}");
+        character.attention.decision_prompt = Some("Reply if {{agent_name}} is asked".to_string());
+        assert!(character.validate_templates().is_ok());
+
+        character.attention.decision_prompt = Some("Reply if {{name}} is asked".to_string());
+        let err = character.validate_templates().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown template variable {{name}} in attention.decision_prompt"
+        );
+
+        character.attention.decision_prompt = None;
+        character.platforms.insert(
+            "discord".to_string(),
+            PlatformPersona {
+                persona: Persona {
+                    forbidden_formatting: vec!["{{format}}".to_string()],
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let err = character.validate_templates().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown template variable {{format}} in platforms.discord.forbidden_formatting[0]"
+        );
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info};
+
+use super::Character;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Shared, swappable reference to the active character. Clones point to the
+/// same character, so a swap is visible to every agent and client holding it.
+#[derive(Clone, Debug)]
+pub struct CharacterHandle {
+    inner: Arc<RwLock<Arc<Character>>>,
+}
+
+impl CharacterHandle {
+    pub fn new(character: Character) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Arc::new(character))),
+        }
+    }
+
+    pub fn get(&self) -> Arc<Character> {
+        self.inner
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Replaces the character and returns the previous one.
+    pub fn swap(&self, character: Character) -> Arc<Character> {
+        let mut current = self
+            .inner
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::mem::replace(&mut *current, Arc::new(character))
+    }
+}
+
+/// Polls a character file, including the files it extends, and reloads it into
+/// a [`CharacterHandle`] when any of them change. A character that fails to
+/// load is reported and the previous one is kept.
+pub struct CharacterWatcher {
+    path: PathBuf,
+    handle: CharacterHandle,
+    interval: Duration,
+}
+
+impl CharacterWatcher {
+    pub fn new(path: impl Into<PathBuf>, handle: CharacterHandle) -> Self {
+        Self {
+            path: path.into(),
+            handle,
+            interval: DEFAULT_POLL_INTERVAL,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(self.run())
+    }
+
+    async fn run(self) {
+        info!(path = %self.path.display(), "Watching character file for changes");
+
+        let mut files = match Character::load_with_files(&self.path) {
+            Ok((_, files)) => files,
+            Err(err) => {
+                error!(%err, "Failed to load character, watching the root file only");
+                vec![self.path.clone()]
+            }
+        };
+        let mut last_modified = latest_modification(&files);
+
+        let mut interval = tokio::time::interval(self.interval);
+        loop {
+            interval.tick().await;
+
+            let modified = latest_modification(&files);
+            if modified == last_modified {
+                continue;
+            }
+            last_modified = modified;
+
+            debug!(path = %self.path.display(), "Character file changed, reloading");
+            match Character::load_with_files(&self.path) {
+                Ok((character, reloaded_files)) => {
+                    let changes = diff(&self.handle.get(), &character);
+                    if changes.is_empty() {
+                        debug!("Character reloaded without changes");
+                    } else {
+                        info!(name = character.name, ?changes, "Character reloaded");
+                        self.handle.swap(character);
+                    }
+
+                    files = reloaded_files;
+                    last_modified = latest_modification(&files);
+                }
+                Err(err) => {
+                    error!(%err, "Failed to reload character, keeping the previous one");
+                }
+            }
+        }
+    }
+}
+
+fn latest_modification(files: &[PathBuf]) -> Option<SystemTime> {
+    files
+        .iter()
+        .filter_map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+        .max()
+}
+
+/// Lists the fields that differ between two characters.
+fn diff(old: &Character, new: &Character) -> Vec<String> {
+    let mut changes = Vec::new();
+
+    if old.name != new.name {
+        changes.push(format!("name: {} -> {}", old.name, new.name));
+    }
+    if old.preamble != new.preamble {
+        changes.push("preamble".to_string());
+    }
+
+    let lists = [
+        ("lore", &old.lore, &new.lore),
+        ("post_examples", &old.post_examples, &new.post_examples),
+        ("topics", &old.topics, &new.topics),
+        ("adjectives", &old.adjectives, &new.adjectives),
+        ("style.all", &old.style.all, &new.style.all),
+        ("style.chat", &old.style.chat, &new.style.chat),
+        ("style.post", &old.style.post, &new.style.post),
+    ];
+    for (field, old, new) in lists {
+        if old != new {
+            let added = new.iter().filter(|item| !old.contains(item)).count();
+            let removed = old.iter().filter(|item| !new.contains(item)).count();
+            changes.push(format!("{field}: +{added} -{removed}"));
+        }
+    }
+
+    if old.message_examples != new.message_examples {
+        changes.push(format!(
+            "message_examples: {} -> {}",
+            old.message_examples.len(),
+            new.message_examples.len()
+        ));
+    }
+    if old.platforms != new.platforms {
+        changes.push("platforms".to_string());
+    }
+    if old.attention != new.attention {
+        changes.push("attention".to_string());
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::{tests::TempDir, MessageExample, PlatformPersona};
+
+    fn character(preamble: &str) -> Character {
+        toml::from_str(&format!("name = \"Shinobi\"\npreamble = \"{preamble}\"")).unwrap()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = character("You are a support bot.");
+        assert!(diff(&old, &old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.name = "Ninja".to_string();
+        new.preamble = "You are a ninja.".to_string();
+        new.lore = vec!["Trained in the mountains".to_string()];
+        new.post_examples = vec!["gm".to_string()];
+        new.topics = vec!["Tech".to_string()];
+        new.adjectives = vec!["stealthy".to_string()];
+        new.style.all = vec!["Be terse".to_string()];
+        new.style.chat = vec!["Use emojis".to_string()];
+        new.style.post = vec!["No hashtags".to_string()];
+        new.message_examples = vec![MessageExample {
+            messages: Vec::new(),
+        }];
+        new.platforms
+            .insert("discord".to_string(), PlatformPersona::default());
+        new.attention.bot_names = vec!["shinobi".to_string()];
+
+        assert_eq!(
+            diff(&old, &new),
+            vec![
+                "name: Shinobi -> Ninja",
+                "preamble",
+                "lore: +1 -0",
+                "post_examples: +1 -0",
+                "topics: +1 -0",
+                "adjectives: +1 -0",
+                "style.all: +1 -0",
+                "style.chat: +1 -0",
+                "style.post: +1 -0",
+                "message_examples: 0 -> 1",
+                "platforms",
+                "attention",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diff_counts_added_and_removed_items() {
+        let mut old = character("You are a support bot.");
+        old.topics = vec!["Tech".to_string(), "Controller".to_string()];
+        let mut new = old.clone();
+        new.topics = vec![
+            "Tech".to_string(),
+            "Philosophy".to_string(),
+            "Gaming".to_string(),
+        ];
+
+        assert_eq!(diff(&old, &new), vec!["topics: +2 -1"]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_reload_keeps_character() {
+        let dir = TempDir::new("watcher-invalid");
+        let path = dir.write(
+            "shinobi.toml",
+            "name = \"Shinobi\"\npreamble = \"You are a support bot.\"",
+        );
+        let handle = CharacterHandle::new(Character::load(&path).unwrap());
+        let watcher = CharacterWatcher::new(&path, handle.clone())
+            .with_interval(Duration::from_millis(10))
+            .spawn();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        dir.write("shinobi.toml", "name = \"Shinobi\"\npreamble = ");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(handle.get().preamble, "You are a support bot.");
+
+        // The watcher is still running and picks up the fixed file.
+        dir.write(
+            "shinobi.toml",
+            "name = \"Shinobi\"\npreamble = \"You are a ninja.\"",
+        );
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(handle.get().preamble, "You are a ninja.");
+
+        watcher.abort();
+    }
+}
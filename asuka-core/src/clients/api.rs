@@ -0,0 +1,690 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use futures::{stream, StreamExt};
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tracing::{debug, error, info};
+
+use crate::{
+    agent::Agent,
+    attention::Attention,
+    character::PromptContext,
+    context::estimate_tokens,
+    knowledge::{ChannelType, ConversationMessage, Message, Role, Source},
+    pipeline::MessagePipeline,
+};
+
+const DEFAULT_USER: &str = "api";
+
+/// Tells apart the messages stored within the same nanosecond.
+static MESSAGE_IDS: AtomicU64 = AtomicU64::new(0);
+
+/// HTTP server implementing the OpenAI chat completions API, so tools that
+/// speak it can talk to the agent. The character's name is used as the model
+/// name and the conversation history is taken from each request.
+///
+/// Requests reply directly instead of going through attention, but count as
+/// messages being handled by the pipeline, so shutdown waits for them.
+#[derive(Clone)]
+pub struct ApiServer<M: CompletionModel, E: EmbeddingModel + 'static> {
+    pipeline: MessagePipeline<M, E>,
+    api_key: Option<Arc<str>>,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> ApiServer<M, E> {
+    pub fn new(agent: Agent<M, E>, attention: Attention<M>) -> Self {
+        Self::from_pipeline(MessagePipeline::new(agent, attention))
+    }
+
+    /// Creates a server from a pipeline, e.g. one shared with other clients.
+    pub fn from_pipeline(pipeline: MessagePipeline<M, E>) -> Self {
+        Self {
+            pipeline,
+            api_key: None,
+        }
+    }
+
+    /// Requires requests to send the key as a bearer token.
+    pub fn with_api_key(mut self, api_key: &str) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    pub fn router(&self) -> Router {
+        Router::new()
+            .route("/v1/models", get(models::<M, E>))
+            .route("/v1/chat/completions", post(chat_completions::<M, E>))
+            .with_state(self.clone())
+    }
+
+    pub async fn start(&self, addr: SocketAddr) -> Result<(), std::io::Error> {
+        let listener = tokio::net::TcpListener::bind(addr).await?;
+
+        info!(%addr, "Starting api server");
+        axum::serve(listener, self.router()).await
+    }
+
+    fn agent(&self) -> &Agent<M, E> {
+        self.pipeline.agent()
+    }
+
+    fn model(&self) -> String {
+        self.agent().character().name.clone()
+    }
+
+    fn authorize(&self, headers: &HeaderMap) -> Result<(), ApiError> {
+        let Some(api_key) = &self.api_key else {
+            return Ok(());
+        };
+
+        let token = headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if token != Some(api_key.as_ref()) {
+            return Err(ApiError::new(
+                StatusCode::UNAUTHORIZED,
+                "invalid_api_key",
+                "Incorrect API key provided",
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn store(&self, message: Message) {
+        if let Err(err) = self.agent().knowledge().create_message(message).await {
+            error!(?err, "Failed to store message");
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatCompletionRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    /// Identifies the end user, used as the conversation's channel.
+    user: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: Option<ChatContent>,
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ChatContent {
+    Text(String),
+    Parts(Vec<ChatContentPart>),
+}
+
+#[derive(Debug, Deserialize)]
+struct ChatContentPart {
+    #[serde(rename = "type")]
+    kind: String,
+    text: Option<String>,
+}
+
+impl ChatMessage {
+    /// Text of the message. Parts that aren't text, like images, are skipped.
+    fn text(&self) -> String {
+        match &self.content {
+            Some(ChatContent::Text(text)) => text.clone(),
+            Some(ChatContent::Parts(parts)) => parts
+                .iter()
+                .filter(|part| part.kind == "text")
+                .filter_map(|part| part.text.as_deref())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            None => String::new(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChatCompletion {
+    id: String,
+    object: &'static str,
+    created: i64,
+    model: String,
+    choices: Vec<Choice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+}
+
+#[derive(Debug, Serialize)]
+struct Choice {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<serde_json::Value>,
+    finish_reason: Option<&'static str>,
+}
+
+#[derive(Debug, Serialize)]
+struct Usage {
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request", message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let kind = if self.status.is_server_error() {
+            "server_error"
+        } else {
+            "invalid_request_error"
+        };
+        let body = json!({
+            "error": {
+                "message": self.message,
+                "type": kind,
+                "code": self.code,
+            }
+        });
+
+        (self.status, Json(body)).into_response()
+    }
+}
+
+/// A request mapped onto the agent: the last user message is the prompt and
+/// the messages before it the history. System messages are skipped since the
+/// character provides the system prompt.
+struct Conversation {
+    user: String,
+    prompt: Message,
+    history: Vec<ConversationMessage>,
+}
+
+impl Conversation {
+    fn from_request(request: &ChatCompletionRequest, model: &str) -> Result<Self, ApiError> {
+        let user = request
+            .user
+            .clone()
+            .unwrap_or_else(|| DEFAULT_USER.to_string());
+        let channel_id = format!("api-{}", user);
+        let now = chrono::Utc::now();
+
+        let mut messages: Vec<ConversationMessage> = request
+            .messages
+            .iter()
+            .filter_map(|msg| {
+                let role = match msg.role.as_str() {
+                    "user" => Role::User,
+                    "assistant" => Role::Assistant,
+                    _ => return None,
+                };
+                let author = match role {
+                    Role::User => msg.name.clone().unwrap_or_else(|| user.clone()),
+                    Role::Assistant => model.to_string(),
+                };
+
+                Some(ConversationMessage {
+                    author: Some(author.clone()),
+                    message: Message {
+                        id: message_id(),
+                        source: Source::Api,
+                        source_id: author.clone(),
+                        channel_type: ChannelType::DirectMessage,
+                        channel_id: channel_id.clone(),
+                        account_id: author,
+                        role,
+                        content: msg.text(),
+                        created_at: Some(now),
+                        reply_to: None,
+                    },
+                })
+            })
+            .collect();
+
+        let prompt = match messages.pop() {
+            Some(last) if last.message.role == Role::User => last.message,
+            _ => {
+                return Err(ApiError::invalid_request(
+                    "The last message must be a user message",
+                ))
+            }
+        };
+
+        Ok(Self {
+            user,
+            prompt,
+            history: messages,
+        })
+    }
+
+    fn prompt_context(&self) -> PromptContext {
+        PromptContext {
+            source: Some(Source::Api),
+            channel_type: Some(ChannelType::DirectMessage),
+            user: Some(self.prompt.account_id.clone()),
+            channel: Some(self.user.clone()),
+            context: None,
+        }
+    }
+
+    fn reply(&self, id: &str, content: String, model: &str) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Api,
+            source_id: model.to_string(),
+            channel_type: ChannelType::DirectMessage,
+            channel_id: self.prompt.channel_id.clone(),
+            account_id: model.to_string(),
+            role: Role::Assistant,
+            content,
+            created_at: Some(chrono::Utc::now()),
+            reply_to: Some(self.prompt.id.clone()),
+        }
+    }
+
+    fn prompt_tokens(&self) -> usize {
+        self.history
+            .iter()
+            .map(|entry| estimate_tokens(&entry.message.content))
+            .sum::<usize>()
+            + estimate_tokens(&self.prompt.content)
+    }
+}
+
+async fn models<M: CompletionModel + 'static, E: EmbeddingModel + 'static>(
+    State(server): State<ApiServer<M, E>>,
+    headers: HeaderMap,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    server.authorize(&headers)?;
+
+    Ok(Json(json!({
+        "object": "list",
+        "data": [{
+            "id": server.model(),
+            "object": "model",
+            "created": 0,
+            "owned_by": "asuka",
+        }],
+    })))
+}
+
+async fn chat_completions<M: CompletionModel + 'static, E: EmbeddingModel + 'static>(
+    State(server): State<ApiServer<M, E>>,
+    headers: HeaderMap,
+    Json(request): Json<ChatCompletionRequest>,
+) -> Result<Response, ApiError> {
+    server.authorize(&headers)?;
+    let Some(handling) = server.pipeline.enter() else {
+        return Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "shutting_down",
+            "The server is shutting down",
+        ));
+    };
+
+    let model = server.model();
+    if !request.model.eq_ignore_ascii_case(&model) {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "model_not_found",
+            format!("The model `{}` does not exist", request.model),
+        ));
+    }
+
+    let conversation = Conversation::from_request(&request, &model)?;
+    debug!(
+        user = conversation.user,
+        history = conversation.history.len(),
+        stream = request.stream,
+        "Chat completion request"
+    );
+    server.store(conversation.prompt.clone()).await;
+
+    let id = format!("chatcmpl-{}", message_id());
+    let created = chrono::Utc::now().timestamp();
+    let context = conversation.prompt_context();
+
+    if !request.stream {
+        let response = server
+            .agent()
+            .reply(
+                &context,
+                &conversation.history,
+                &conversation.prompt.content,
+            )
+            .await
+            .map_err(|err| {
+                error!(?err, "Failed to generate response");
+                ApiError::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "completion_failed",
+                    err.to_string(),
+                )
+            })?;
+
+        let usage = Usage {
+            prompt_tokens: conversation.prompt_tokens(),
+            completion_tokens: estimate_tokens(&response),
+            total_tokens: conversation.prompt_tokens() + estimate_tokens(&response),
+        };
+        server
+            .store(conversation.reply(&id, response.clone(), &model))
+            .await;
+
+        return Ok(Json(ChatCompletion {
+            id,
+            object: "chat.completion",
+            created,
+            model,
+            choices: vec![Choice {
+                index: 0,
+                message: Some(json!({ "role": "assistant", "content": response })),
+                delta: None,
+                finish_reason: Some("stop"),
+            }],
+            usage: Some(usage),
+        })
+        .into_response());
+    }
+
+    let tokens = server
+        .agent()
+        .reply_stream(
+            &context,
+            &conversation.history,
+            &conversation.prompt.content,
+        )
+        .await
+        .map_err(|err| {
+            error!(?err, "Failed to generate response");
+            ApiError::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "completion_failed",
+                err.to_string(),
+            )
+        })?
+        .tokens;
+
+    let chunk = {
+        let id = id.clone();
+        let model = model.clone();
+        move |delta: serde_json::Value, finish_reason: Option<&'static str>| {
+            Event::default().json_data(ChatCompletion {
+                id: id.clone(),
+                object: "chat.completion.chunk",
+                created,
+                model: model.clone(),
+                choices: vec![Choice {
+                    index: 0,
+                    message: None,
+                    delta: Some(delta),
+                    finish_reason,
+                }],
+                usage: None,
+            })
+        }
+    };
+
+    // Collect the response as it is streamed so it can be stored once done
+    let response = Arc::new(Mutex::new(String::new()));
+    let deltas = tokens.map({
+        let chunk = chunk.clone();
+        let response = response.clone();
+        move |token| match token {
+            Ok(token) => {
+                response.lock().unwrap().push_str(&token);
+                chunk(json!({ "content": token }), None)
+            }
+            Err(err) => {
+                error!(?err, "Failed to stream response");
+                Event::default().json_data(json!({
+                    "error": { "message": err.to_string(), "type": "server_error" }
+                }))
+            }
+        }
+    });
+    let finish = stream::once({
+        let chunk = chunk.clone();
+        async move {
+            let response = response.lock().unwrap().clone();
+            server
+                .store(conversation.reply(&id, response, &model))
+                .await;
+            drop(handling);
+            chunk(json!({}), Some("stop"))
+        }
+    });
+
+    let events = stream::once(std::future::ready(chunk(
+        json!({ "role": "assistant", "content": "" }),
+        None,
+    )))
+    .chain(deltas)
+    .chain(finish)
+    .chain(stream::once(std::future::ready(Ok(
+        Event::default().data("[DONE]")
+    ))));
+
+    Ok(Sse::new(events)
+        .keep_alive(KeepAlive::default())
+        .into_response())
+}
+
+/// Unique id for a stored message, prefixed like the other sources' ids.
+fn message_id() -> String {
+    let now = chrono::Utc::now();
+    format!(
+        "api-{}-{}",
+        now.timestamp_nanos_opt()
+            .unwrap_or_else(|| now.timestamp_micros()),
+        MESSAGE_IDS.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{knowledge_base, TestEmbeddingModel, TestModel};
+    use std::future::IntoFuture;
+
+    fn request(messages: serde_json::Value) -> ChatCompletionRequest {
+        serde_json::from_value(json!({
+            "model": "shinobi",
+            "messages": messages,
+            "user": "player-1",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_conversation_from_request() {
+        let request = request(json!([
+            { "role": "system", "content": "You are a helpful assistant" },
+            { "role": "user", "content": "hi" },
+            { "role": "assistant", "content": "hello" },
+            { "role": "user", "content": [{ "type": "text", "text": "how are you?" }] },
+        ]));
+
+        let conversation = Conversation::from_request(&request, "shinobi").unwrap();
+        assert_eq!(conversation.prompt.content, "how are you?");
+        assert_eq!(conversation.prompt.channel_id, "api-player-1");
+        assert_eq!(conversation.history.len(), 2);
+        assert_eq!(conversation.history[0].message.role, Role::User);
+        assert_eq!(conversation.history[1].message.role, Role::Assistant);
+        assert_eq!(conversation.history[1].author.as_deref(), Some("shinobi"));
+    }
+
+    #[test]
+    fn test_last_message_must_be_from_user() {
+        let request = request(json!([
+            { "role": "user", "content": "hi" },
+            { "role": "assistant", "content": "hello" },
+        ]));
+
+        assert!(Conversation::from_request(&request, "shinobi").is_err());
+    }
+
+    async fn serve(
+        api_key: Option<&str>,
+    ) -> (SocketAddr, ApiServer<TestModel, TestEmbeddingModel>) {
+        let character =
+            toml::from_str("name = \"Shinobi\"\npreamble = \"You are a support bot.\"").unwrap();
+        let model = TestModel::new("Check the FAQ first.");
+        let agent = Agent::new(character, model.clone(), knowledge_base().await);
+        let mut server = ApiServer::new(agent, Attention::new(Default::default(), model));
+        if let Some(api_key) = api_key {
+            server = server.with_api_key(api_key);
+        }
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(listener, server.router()).into_future());
+
+        (addr, server)
+    }
+
+    async fn chat_completion(
+        addr: SocketAddr,
+        api_key: Option<&str>,
+        stream: bool,
+    ) -> reqwest::Response {
+        let mut request = reqwest::Client::new()
+            .post(format!("http://{addr}/v1/chat/completions"))
+            .json(&json!({
+                "model": "shinobi",
+                "messages": [{ "role": "user", "content": "my controller isn't working" }],
+                "stream": stream,
+                "user": "player-1",
+            }));
+        if let Some(api_key) = api_key {
+            request = request.bearer_auth(api_key);
+        }
+        request.send().await.unwrap()
+    }
+
+    async fn stored(server: &ApiServer<TestModel, TestEmbeddingModel>) -> Vec<Message> {
+        server
+            .agent()
+            .knowledge()
+            .channel_history(&Source::Api, "api-player-1", 10)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.message)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion() {
+        let (addr, server) = serve(Some("secret")).await;
+
+        let response = chat_completion(addr, Some("secret"), false).await;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["object"], "chat.completion");
+        assert_eq!(
+            body["choices"][0]["message"]["content"],
+            "Check the FAQ first."
+        );
+
+        let messages = stored(&server).await;
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].id.starts_with("api-"));
+        assert_eq!(messages[1].id, body["id"]);
+        assert_eq!(messages[1].role, Role::Assistant);
+        assert_eq!(messages[1].reply_to.as_ref(), Some(&messages[0].id));
+    }
+
+    #[tokio::test]
+    async fn test_chat_completion_stream() {
+        let (addr, server) = serve(None).await;
+
+        let response = chat_completion(addr, None, true).await;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert!(response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .starts_with("text/event-stream"));
+
+        let body = response.text().await.unwrap();
+        let events: Vec<&str> = body
+            .lines()
+            .filter_map(|line| line.strip_prefix("data: "))
+            .collect();
+        assert_eq!(events.last(), Some(&"[DONE]"));
+        let content: String = events
+            .iter()
+            .filter_map(|event| serde_json::from_str::<serde_json::Value>(event).ok())
+            .filter_map(|chunk| {
+                chunk["choices"][0]["delta"]["content"]
+                    .as_str()
+                    .map(String::from)
+            })
+            .collect();
+        assert_eq!(content, "Check the FAQ first.");
+
+        let messages = stored(&server).await;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].content, "Check the FAQ first.");
+    }
+
+    #[tokio::test]
+    async fn test_rejects_invalid_api_key() {
+        let (addr, server) = serve(Some("secret")).await;
+
+        for api_key in [None, Some("wrong")] {
+            let response = chat_completion(addr, api_key, false).await;
+            assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+            let body: serde_json::Value = response.json().await.unwrap();
+            assert_eq!(body["error"]["code"], "invalid_api_key");
+        }
+        assert!(stored(&server).await.is_empty());
+    }
+
+    #[test]
+    fn test_message_ids_are_unique() {
+        let first = message_id();
+        let second = message_id();
+        assert!(first.starts_with("api-"));
+        assert_ne!(first, second);
+    }
+}
@@ -0,0 +1,276 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use std::collections::HashSet;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tracing::info;
+
+use crate::{
+    agent::Agent,
+    attention::{Attention, AttentionCommand},
+    knowledge::{ChannelType, Message, Role, Source},
+    pipeline::{AdapterError, InboundMessage, MessagePipeline, Middleware, PlatformAdapter},
+};
+
+const MAX_MESSAGE_LENGTH: usize = 4000;
+const DEFAULT_USER: &str = "user";
+const GROUP_CHANNEL_ID: &str = "cli-group";
+
+const HELP: &str = "Commands:
+  /dm            talk to the bot in a direct message
+  /group         talk to the bot in a group channel
+  /user <name>   change the simulated user
+  /help          show this message
+  /quit          exit";
+
+/// Terminal client for trying out a character locally. Every line read from
+/// stdin goes through the same pipeline as the platform clients, and the
+/// attention decision, retrieved documents and response are printed.
+pub struct CliClient<M: CompletionModel, E: EmbeddingModel + 'static> {
+    pipeline: MessagePipeline<M, E>,
+    session: Session,
+}
+
+#[derive(Clone, Debug)]
+struct Session {
+    user: String,
+    channel_type: ChannelType,
+}
+
+impl Session {
+    fn channel_id(&self) -> String {
+        match self.channel_type {
+            ChannelType::DirectMessage => format!("cli-dm-{}", self.user),
+            _ => GROUP_CHANNEL_ID.to_string(),
+        }
+    }
+
+    fn channel_name(&self) -> &'static str {
+        match self.channel_type {
+            ChannelType::DirectMessage => "DM",
+            _ => "group",
+        }
+    }
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> CliClient<M, E> {
+    pub fn new(agent: Agent<M, E>, attention: Attention<M>) -> Self {
+        Self::from_pipeline(MessagePipeline::new(agent, attention))
+    }
+
+    /// Creates a client from a pipeline, e.g. one with middleware.
+    pub fn from_pipeline(pipeline: MessagePipeline<M, E>) -> Self {
+        Self {
+            pipeline: pipeline.with_middleware(Inspector),
+            session: Session {
+                user: DEFAULT_USER.to_string(),
+                channel_type: ChannelType::DirectMessage,
+            },
+        }
+    }
+
+    /// Starts in a direct message by default.
+    pub fn with_channel_type(mut self, channel_type: ChannelType) -> Self {
+        self.session.channel_type = channel_type;
+        self
+    }
+
+    pub fn with_user(mut self, user: &str) -> Self {
+        self.session.user = user.to_string();
+        self
+    }
+
+    /// Reads messages from stdin until `/quit` or the end of input.
+    pub async fn start(&self) -> Result<()> {
+        info!("Starting cli client");
+        println!("Chatting with {}. {}\n", self.name(), HELP);
+
+        let mut session = self.session.clone();
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        loop {
+            print_prompt(&session).await?;
+            let Some(line) = lines.next_line().await? else {
+                break;
+            };
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('/') {
+                if !command(&mut session, line) {
+                    break;
+                }
+                continue;
+            }
+
+            let adapter = CliAdapter {
+                session: session.clone(),
+                name: self.name(),
+            };
+            match self.pipeline.handle(&adapter, line.to_string()).await {
+                Ok(outcome) if outcome.command == AttentionCommand::Respond => {}
+                Ok(_) => println!(),
+                Err(err) => println!("[error] {}\n", err),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn name(&self) -> String {
+        self.pipeline.agent().character().name.clone()
+    }
+}
+
+async fn print_prompt(session: &Session) -> Result<()> {
+    let mut stdout = tokio::io::stdout();
+    stdout
+        .write_all(format!("[{}] {}> ", session.channel_name(), session.user).as_bytes())
+        .await?;
+    stdout.flush().await?;
+    Ok(())
+}
+
+/// Handles a `/` command. Returns false when the session should end.
+fn command(session: &mut Session, line: &str) -> bool {
+    let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+
+    match command {
+        "/dm" => session.channel_type = ChannelType::DirectMessage,
+        "/group" => session.channel_type = ChannelType::Text,
+        "/user" if !argument.trim().is_empty() => session.user = argument.trim().to_string(),
+        "/quit" | "/exit" => return false,
+        "/help" => {
+            println!("{}\n", HELP);
+            return true;
+        }
+        _ => {
+            println!("Unknown command. {}\n", HELP);
+            return true;
+        }
+    }
+
+    println!(
+        "Now talking as {} in the {} channel\n",
+        session.user,
+        session.channel_name()
+    );
+    true
+}
+
+struct CliAdapter {
+    session: Session,
+    name: String,
+}
+
+#[async_trait]
+impl PlatformAdapter for CliAdapter {
+    type Event = String;
+
+    fn normalize(&self, line: &String) -> Option<InboundMessage> {
+        let mentioned_names = line
+            .split_whitespace()
+            .filter_map(|word| word.strip_prefix('@'))
+            .map(|name| name.trim_end_matches(|c: char| !c.is_alphanumeric()))
+            .map(|name| name.to_string())
+            .collect::<HashSet<_>>();
+
+        Some(InboundMessage {
+            message: Message {
+                id: message_id(),
+                source: Source::Cli,
+                source_id: self.session.user.clone(),
+                channel_type: self.session.channel_type.clone(),
+                channel_id: self.session.channel_id(),
+                account_id: self.session.user.clone(),
+                role: Role::User,
+                content: line.clone(),
+                created_at: Some(chrono::Utc::now()),
+                reply_to: None,
+            },
+            author: Some(self.session.user.clone()),
+            channel_name: Some(self.session.channel_name().to_string()),
+            context: None,
+            mentioned_names,
+            mentioned_ids: Default::default(),
+            is_reply_to_bot: false,
+        })
+    }
+
+    fn max_message_length(&self) -> usize {
+        MAX_MESSAGE_LENGTH
+    }
+
+    async fn send(&self, inbound: &InboundMessage, text: &str) -> Result<Message, AdapterError> {
+        println!("{}: {}\n", self.name, text);
+
+        Ok(Message {
+            id: message_id(),
+            source: Source::Cli,
+            source_id: self.name.clone(),
+            channel_type: inbound.message.channel_type.clone(),
+            channel_id: inbound.message.channel_id.clone(),
+            account_id: self.name.clone(),
+            role: Role::Assistant,
+            content: text.to_string(),
+            created_at: Some(chrono::Utc::now()),
+            reply_to: Some(inbound.message.id.clone()),
+        })
+    }
+}
+
+/// Prints what the pipeline decided before the response is generated.
+struct Inspector;
+
+#[async_trait]
+impl Middleware for Inspector {
+    async fn on_attention(
+        &self,
+        _inbound: &InboundMessage,
+        command: AttentionCommand,
+    ) -> AttentionCommand {
+        println!("[attention] {:?}", command);
+        command
+    }
+
+    async fn on_documents(&self, _inbound: &InboundMessage, documents: &[String]) {
+        println!("[documents] {} retrieved", documents.len());
+        for document in documents {
+            println!("{}", document);
+        }
+    }
+}
+
+fn message_id() -> String {
+    let now = chrono::Utc::now();
+    format!(
+        "cli-{}",
+        now.timestamp_nanos_opt()
+            .unwrap_or_else(|| now.timestamp_micros())
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commands_switch_channel() {
+        let mut session = Session {
+            user: DEFAULT_USER.to_string(),
+            channel_type: ChannelType::DirectMessage,
+        };
+        assert_eq!(session.channel_id(), "cli-dm-user");
+
+        assert!(command(&mut session, "/group"));
+        assert_eq!(session.channel_type, ChannelType::Text);
+        assert_eq!(session.channel_id(), GROUP_CHANNEL_ID);
+
+        assert!(command(&mut session, "/user alice"));
+        assert!(command(&mut session, "/dm"));
+        assert_eq!(session.channel_id(), "cli-dm-alice");
+
+        assert!(!command(&mut session, "/quit"));
+    }
+}
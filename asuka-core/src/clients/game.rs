@@ -0,0 +1,465 @@
+use async_trait::async_trait;
+use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+};
+use tokio_tungstenite::{tungstenite::Message as WsMessage, WebSocketStream};
+use tracing::{debug, error, info, warn};
+
+use crate::pipeline::{AdapterError, InboundMessage, MessagePipeline, PlatformAdapter};
+use crate::{agent::Agent, attention::Attention, knowledge};
+
+/// Replies are sent whole, games lay out text themselves.
+const MAX_MESSAGE_LENGTH: usize = 16_000;
+/// Marks a line of the response as an action instead of dialogue.
+const ACTION_PREFIX: &str = "[ACTION]";
+
+/// A player talking to the NPC, sent by the game as a JSON text frame.
+#[derive(Clone, Debug, Deserialize)]
+pub struct GameMessage {
+    /// Id the game uses to match the reply, generated when missing.
+    #[serde(default)]
+    pub id: Option<String>,
+    pub player_id: String,
+    #[serde(default)]
+    pub player_name: Option<String>,
+    /// Players in the same session share a conversation. Without one the
+    /// player talks to the NPC alone.
+    #[serde(default)]
+    pub session_id: Option<String>,
+    /// Whether several players talk in the session, so the NPC only answers
+    /// when addressed or when the conversation concerns it. Otherwise it
+    /// answers every message, like in a one-on-one conversation.
+    #[serde(default)]
+    pub group: bool,
+    pub text: String,
+    /// Anything the NPC should know about the game right now, e.g. the
+    /// player's position or inventory.
+    #[serde(default)]
+    pub game_state: Option<Value>,
+    /// Actions the NPC may take in its reply.
+    #[serde(default)]
+    pub actions: Vec<ActionSpec>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ActionSpec {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// An action chosen by the NPC. The game is expected to validate it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Action {
+    pub name: String,
+    pub args: Value,
+}
+
+/// Frames sent back to the game.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Reply {
+        id: String,
+        reply_to: String,
+        text: String,
+        actions: Vec<Action>,
+    },
+    /// The NPC decided not to answer, so the game can stop waiting.
+    NoReply { reply_to: String },
+    Error {
+        reply_to: Option<String>,
+        message: String,
+    },
+}
+
+impl GameMessage {
+    fn channel_id(&self) -> String {
+        match &self.session_id {
+            Some(session_id) => format!("game-{}", session_id),
+            None => format!("game-player-{}", self.player_id),
+        }
+    }
+
+    /// Id the message is stored under. Games pick their own ids, so they are
+    /// only unique per channel and player.
+    fn message_id(&self) -> String {
+        format!(
+            "{}-{}-{}",
+            self.channel_id(),
+            self.player_id,
+            self.id.as_deref().unwrap_or_default()
+        )
+    }
+
+    /// Game state and available actions, added to the reply prompt.
+    fn context(&self) -> Option<String> {
+        let mut sections = Vec::new();
+        if let Some(state) = &self.game_state {
+            sections.push(format!(
+                "Current game state:\n```json\n{}\n```",
+                serde_json::to_string_pretty(state).unwrap_or_default()
+            ));
+        }
+        if !self.actions.is_empty() {
+            let actions = self
+                .actions
+                .iter()
+                .map(|action| format!("- {}: {}", action.name, action.description))
+                .collect::<Vec<_>>()
+                .join("\n");
+            sections.push(format!(
+                "You can act in the game by ending your reply with lines like \
+                 `{} name {{\"arg\": \"value\"}}`, one per action. Available actions:\n{}",
+                ACTION_PREFIX, actions
+            ));
+        }
+
+        (!sections.is_empty()).then(|| sections.join("\n\n"))
+    }
+}
+
+impl From<&GameMessage> for knowledge::Message {
+    fn from(msg: &GameMessage) -> Self {
+        Self {
+            id: msg.message_id(),
+            source: knowledge::Source::Game,
+            source_id: msg.player_id.clone(),
+            channel_type: match msg.session_id {
+                Some(_) if msg.group => knowledge::ChannelType::Text,
+                _ => knowledge::ChannelType::DirectMessage,
+            },
+            channel_id: msg.channel_id(),
+            account_id: msg.player_id.clone(),
+            role: knowledge::Role::User,
+            content: msg.text.clone(),
+            created_at: Some(chrono::Utc::now()),
+            reply_to: None,
+        }
+    }
+}
+
+/// The id the game gave a stored message, see [`GameMessage::message_id`].
+fn game_id(message: &knowledge::Message) -> &str {
+    let prefix = format!("{}-{}-", message.channel_id, message.account_id);
+    message.id.strip_prefix(&prefix).unwrap_or(&message.id)
+}
+
+/// Splits the actions off a response, returning the remaining text. Actions
+/// the game didn't offer are dropped.
+fn parse_actions(response: &str, offered: &[String]) -> (String, Vec<Action>) {
+    let mut text = Vec::new();
+    let mut actions = Vec::new();
+
+    for line in response.lines() {
+        let Some(action) = line.trim().strip_prefix(ACTION_PREFIX) else {
+            text.push(line);
+            continue;
+        };
+        let action = action.trim();
+        let (name, args) = action
+            .split_once(char::is_whitespace)
+            .unwrap_or((action, ""));
+        if !offered.iter().any(|offered| offered == name) {
+            warn!(name, "Skipping action the game didn't offer");
+            continue;
+        }
+        let args = match args.trim() {
+            "" => Value::Null,
+            args => match serde_json::from_str(args) {
+                Ok(args) => args,
+                Err(err) => {
+                    warn!(%err, name, "Skipping action with invalid arguments");
+                    continue;
+                }
+            },
+        };
+        actions.push(Action {
+            name: name.to_string(),
+            args,
+        });
+    }
+
+    (text.join("\n").trim().to_string(), actions)
+}
+
+/// WebSocket server a game connects to so players can talk to the agent as
+/// an NPC. Each frame is a [`GameMessage`] and is answered with a
+/// [`ServerMessage`], sessions or players without one are channels.
+#[derive(Clone)]
+pub struct GameServer<M: CompletionModel, E: EmbeddingModel + 'static> {
+    pipeline: MessagePipeline<M, E>,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> GameServer<M, E> {
+    pub fn new(agent: Agent<M, E>, attention: Attention<M>) -> Self {
+        Self::from_pipeline(MessagePipeline::new(agent, attention))
+    }
+
+    /// Creates a server from a pipeline, e.g. one with middleware.
+    pub fn from_pipeline(pipeline: MessagePipeline<M, E>) -> Self {
+        Self { pipeline }
+    }
+
+    pub async fn start(&self, addr: SocketAddr) -> Result<(), std::io::Error> {
+        let listener = TcpListener::bind(addr).await?;
+        info!(%addr, "Starting game server");
+
+        loop {
+            let (stream, peer) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.handle_connection(stream).await {
+                    error!(%err, %peer, "Game connection failed");
+                }
+            });
+        }
+    }
+
+    async fn handle_connection(
+        &self,
+        stream: TcpStream,
+    ) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+        let socket = tokio_tungstenite::accept_async(stream).await?;
+        let (sink, mut stream) = socket.split();
+        let adapter = GameAdapter {
+            sink: Arc::new(Mutex::new(sink)),
+            name: self.pipeline.agent().character().name.clone(),
+            actions: Vec::new(),
+        };
+        debug!("Game connected");
+
+        while let Some(frame) = stream.next().await {
+            let text = match frame? {
+                WsMessage::Text(text) => text,
+                WsMessage::Close(_) => break,
+                _ => continue,
+            };
+
+            let message: GameMessage = match serde_json::from_str(&text) {
+                Ok(message) => message,
+                Err(err) => {
+                    adapter
+                        .reply(&ServerMessage::Error {
+                            reply_to: None,
+                            message: format!("Invalid message: {}", err),
+                        })
+                        .await?;
+                    continue;
+                }
+            };
+
+            // Players keep talking while the NPC thinks
+            let pipeline = self.pipeline.clone();
+            let adapter = GameAdapter {
+                actions: message
+                    .actions
+                    .iter()
+                    .map(|action| action.name.clone())
+                    .collect(),
+                ..adapter.clone()
+            };
+            tokio::spawn(async move {
+                let reply_to = message.id.clone().unwrap_or_else(|| {
+                    let now = chrono::Utc::now();
+                    format!(
+                        "{}-{}",
+                        message.player_id,
+                        now.timestamp_nanos_opt()
+                            .unwrap_or_else(|| now.timestamp_micros())
+                    )
+                });
+                let message = GameMessage {
+                    id: Some(reply_to.clone()),
+                    ..message
+                };
+
+                let response = match pipeline.handle(&adapter, message).await {
+                    Ok(outcome) if outcome.replies.is_empty() => {
+                        Some(ServerMessage::NoReply { reply_to })
+                    }
+                    Ok(_) => None,
+                    Err(err) => {
+                        error!(%err, "Failed to handle message");
+                        Some(ServerMessage::Error {
+                            reply_to: Some(reply_to),
+                            message: err.to_string(),
+                        })
+                    }
+                };
+                if let Some(response) = response {
+                    if let Err(err) = adapter.reply(&response).await {
+                        error!(%err, "Failed to send to game");
+                    }
+                }
+            });
+        }
+
+        debug!("Game disconnected");
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct GameAdapter {
+    sink: Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, WsMessage>>>,
+    /// The character's name, recorded as the author of replies.
+    name: String,
+    /// Names of the actions offered with the message being answered.
+    actions: Vec<String>,
+}
+
+impl GameAdapter {
+    async fn reply(
+        &self,
+        message: &ServerMessage,
+    ) -> Result<(), tokio_tungstenite::tungstenite::Error> {
+        let json = serde_json::to_string(message).unwrap_or_default();
+        self.sink
+            .lock()
+            .await
+            .send(WsMessage::Text(json.into()))
+            .await
+    }
+}
+
+#[async_trait]
+impl PlatformAdapter for GameAdapter {
+    type Event = GameMessage;
+
+    fn normalize(&self, msg: &GameMessage) -> Option<InboundMessage> {
+        Some(InboundMessage {
+            message: knowledge::Message::from(msg),
+            author: msg.player_name.clone(),
+            channel_name: msg.session_id.clone(),
+            context: msg.context(),
+            mentioned_names: Default::default(),
+            mentioned_ids: Default::default(),
+            is_reply_to_bot: false,
+        })
+    }
+
+    fn max_message_length(&self) -> usize {
+        MAX_MESSAGE_LENGTH
+    }
+
+    async fn send(
+        &self,
+        inbound: &InboundMessage,
+        text: &str,
+    ) -> Result<knowledge::Message, AdapterError> {
+        let (text, actions) = parse_actions(text, &self.actions);
+        let now = chrono::Utc::now();
+        let id = format!(
+            "{}-{}",
+            self.name,
+            now.timestamp_nanos_opt()
+                .unwrap_or_else(|| now.timestamp_micros())
+        );
+
+        self.reply(&ServerMessage::Reply {
+            id: id.clone(),
+            reply_to: game_id(&inbound.message).to_string(),
+            text: text.clone(),
+            actions,
+        })
+        .await?;
+
+        Ok(knowledge::Message {
+            id,
+            source: knowledge::Source::Game,
+            source_id: self.name.clone(),
+            channel_type: inbound.message.channel_type.clone(),
+            channel_id: inbound.message.channel_id.clone(),
+            account_id: self.name.clone(),
+            role: knowledge::Role::Assistant,
+            content: text,
+            created_at: Some(now),
+            reply_to: Some(inbound.message.id.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_game_message() {
+        let msg: GameMessage = serde_json::from_value(json!({
+            "player_id": "0x42",
+            "text": "Can you open the gate?",
+            "game_state": { "location": "castle gate", "gold": 12 },
+            "actions": [{ "name": "open_gate", "description": "Opens the castle gate" }],
+        }))
+        .unwrap();
+
+        let message = knowledge::Message::from(&msg);
+        assert_eq!(message.channel_id, "game-player-0x42");
+        assert_eq!(message.channel_type, knowledge::ChannelType::DirectMessage);
+
+        let context = msg.context().unwrap();
+        assert!(context.contains("\"location\": \"castle gate\""));
+        assert!(context.contains("- open_gate: Opens the castle gate"));
+    }
+
+    #[test]
+    fn test_message_ids() {
+        let msg = |player_id: &str, session_id: Option<&str>, group: bool| GameMessage {
+            id: Some("1".to_string()),
+            player_id: player_id.to_string(),
+            player_name: None,
+            session_id: session_id.map(|session_id| session_id.to_string()),
+            group,
+            text: "Hello".to_string(),
+            game_state: None,
+            actions: Vec::new(),
+        };
+
+        let alice = knowledge::Message::from(&msg("alice", Some("castle"), false));
+        let bob = knowledge::Message::from(&msg("bob", Some("castle"), true));
+        let carol = knowledge::Message::from(&msg("carol", None, false));
+        assert_ne!(alice.id, bob.id);
+        assert_ne!(alice.id, carol.id);
+        assert_eq!(game_id(&alice), "1");
+        assert_eq!(game_id(&carol), "1");
+
+        assert_eq!(alice.channel_type, knowledge::ChannelType::DirectMessage);
+        assert_eq!(bob.channel_type, knowledge::ChannelType::Text);
+    }
+
+    #[test]
+    fn test_parse_actions() {
+        let offered = [
+            "open_gate".to_string(),
+            "give_item".to_string(),
+            "wave".to_string(),
+        ];
+        let (text, actions) = parse_actions(
+            "Of course, traveler.\n[ACTION] open_gate\n[ACTION] give_item {\"item\": \"key\"}\n[ACTION] wave {broken\n[ACTION] burn_castle",
+            &offered,
+        );
+
+        assert_eq!(text, "Of course, traveler.");
+        assert_eq!(
+            actions,
+            vec![
+                Action {
+                    name: "open_gate".to_string(),
+                    args: Value::Null,
+                },
+                Action {
+                    name: "give_item".to_string(),
+                    args: json!({ "item": "key" }),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,213 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
+    sync::Arc,
+    time::Duration,
+};
+
+/// Only room messages are needed, everything else in the timeline is skipped
+/// by the homeserver.
+const SYNC_FILTER: &str = r#"{"room":{"timeline":{"types":["m.room.message"]}}}"#;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MatrixError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Invalid response: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Matrix API error ({status}): {errcode}: {error}")]
+    Api {
+        status: u16,
+        errcode: String,
+        error: String,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SyncResponse {
+    pub next_batch: String,
+    #[serde(default)]
+    pub rooms: Rooms,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Rooms {
+    #[serde(default)]
+    pub join: HashMap<String, JoinedRoom>,
+    #[serde(default)]
+    pub invite: HashMap<String, Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct JoinedRoom {
+    #[serde(default)]
+    pub summary: RoomSummary,
+    #[serde(default)]
+    pub timeline: Timeline,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RoomSummary {
+    #[serde(rename = "m.joined_member_count")]
+    pub joined_member_count: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct Timeline {
+    #[serde(default)]
+    pub events: Vec<RoomEvent>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RoomEvent {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub event_id: String,
+    pub sender: String,
+    #[serde(default)]
+    pub origin_server_ts: i64,
+    #[serde(default)]
+    pub content: Value,
+}
+
+/// The parts of the Matrix client-server API used by the client.
+#[derive(Clone)]
+pub struct MatrixApi {
+    client: reqwest::Client,
+    homeserver: String,
+    access_token: Arc<str>,
+    transactions: Arc<AtomicU64>,
+}
+
+impl MatrixApi {
+    pub fn new(homeserver: &str, access_token: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            homeserver: homeserver.trim_end_matches('/').to_string(),
+            access_token: access_token.into(),
+            transactions: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/_matrix/client/v3{}", self.homeserver, path)
+    }
+
+    async fn request(&self, request: reqwest::RequestBuilder) -> Result<Value, MatrixError> {
+        let response = request.bearer_auth(&self.access_token).send().await?;
+        let status = response.status();
+        let body: Value = response.json().await?;
+
+        if !status.is_success() {
+            return Err(MatrixError::Api {
+                status: status.as_u16(),
+                errcode: body["errcode"].as_str().unwrap_or("M_UNKNOWN").to_string(),
+                error: body["error"].as_str().unwrap_or_default().to_string(),
+            });
+        }
+
+        Ok(body)
+    }
+
+    /// Transaction ids make retried sends idempotent, they only need to be
+    /// unique for this access token.
+    fn transaction_id(&self) -> String {
+        format!(
+            "asuka-{}-{}",
+            chrono::Utc::now().timestamp_millis(),
+            self.transactions.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    /// Returns the user id the access token belongs to.
+    pub async fn whoami(&self) -> Result<String, MatrixError> {
+        let body = self
+            .request(self.client.get(self.url("/account/whoami")))
+            .await?;
+        Ok(body["user_id"].as_str().unwrap_or_default().to_string())
+    }
+
+    /// Long polls for events after `since`. Without `since` the current state
+    /// is returned right away.
+    pub async fn sync(
+        &self,
+        since: Option<&str>,
+        timeout: Duration,
+    ) -> Result<SyncResponse, MatrixError> {
+        let timeout_ms = timeout.as_millis().to_string();
+        let mut query = vec![("timeout", timeout_ms.as_str()), ("filter", SYNC_FILTER)];
+        if let Some(since) = since {
+            query.push(("since", since));
+        }
+
+        let body = self
+            .request(
+                self.client
+                    .get(self.url("/sync"))
+                    .query(&query)
+                    // Leave the homeserver time to answer the long poll
+                    .timeout(timeout + Duration::from_secs(30)),
+            )
+            .await?;
+        Ok(serde_json::from_value(body)?)
+    }
+
+    pub async fn join(&self, room_id: &str) -> Result<(), MatrixError> {
+        self.request(
+            self.client
+                .post(self.url(&format!("/rooms/{}/join", encode(room_id))))
+                .json(&json!({})),
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn joined_member_count(&self, room_id: &str) -> Result<usize, MatrixError> {
+        let body = self
+            .request(
+                self.client
+                    .get(self.url(&format!("/rooms/{}/joined_members", encode(room_id)))),
+            )
+            .await?;
+        Ok(body["joined"]
+            .as_object()
+            .map(|joined| joined.len())
+            .unwrap_or_default())
+    }
+
+    /// Sends an `m.room.message` event and returns its id.
+    pub async fn send_message(
+        &self,
+        room_id: &str,
+        content: &Value,
+    ) -> Result<String, MatrixError> {
+        let path = format!(
+            "/rooms/{}/send/m.room.message/{}",
+            encode(room_id),
+            self.transaction_id()
+        );
+        let body = self
+            .request(self.client.put(self.url(&path)).json(content))
+            .await?;
+        Ok(body["event_id"].as_str().unwrap_or_default().to_string())
+    }
+
+    pub async fn redact(&self, room_id: &str, event_id: &str) -> Result<(), MatrixError> {
+        let path = format!(
+            "/rooms/{}/redact/{}/{}",
+            encode(room_id),
+            encode(event_id),
+            self.transaction_id()
+        );
+        self.request(self.client.put(self.url(&path)).json(&json!({})))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Room and event ids contain `!`, `$` and `:`, which have to be escaped in paths.
+fn encode(segment: &str) -> String {
+    url::form_urlencoded::byte_serialize(segment.as_bytes()).collect()
+}
@@ -0,0 +1,477 @@
+use async_trait::async_trait;
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info};
+
+use crate::pipeline::{AdapterError, InboundMessage, MessagePipeline, PlatformAdapter};
+use crate::{agent::Agent, attention::Attention, knowledge};
+
+mod api;
+
+pub use api::{MatrixApi, MatrixError, RoomEvent, SyncResponse};
+
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+/// Matrix has no hard limit, long messages are split to stay readable.
+const MAX_MESSAGE_LENGTH: usize = 4000;
+/// Every edit is a new event in the room, so they are kept infrequent.
+const EDIT_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
+pub struct MatrixClient<M: CompletionModel, E: EmbeddingModel + 'static> {
+    pipeline: MessagePipeline<M, E>,
+    api: MatrixApi,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> MatrixClient<M, E> {
+    pub fn new(
+        agent: Agent<M, E>,
+        attention: Attention<M>,
+        homeserver: &str,
+        access_token: &str,
+    ) -> Self {
+        Self::from_pipeline(
+            MessagePipeline::new(agent, attention),
+            homeserver,
+            access_token,
+        )
+    }
+
+    /// Creates a client from a pipeline, e.g. one with middleware.
+    pub fn from_pipeline(
+        pipeline: MessagePipeline<M, E>,
+        homeserver: &str,
+        access_token: &str,
+    ) -> Self {
+        Self {
+            pipeline,
+            api: MatrixApi::new(homeserver, access_token),
+        }
+    }
+
+    /// Syncs until the homeserver can't be reached. Messages sent while the
+    /// bot was offline are skipped, and invites are accepted.
+    pub async fn start(&self) -> Result<(), MatrixError> {
+        let user_id = self.api.whoami().await?;
+        let adapter = MatrixAdapter {
+            api: self.api.clone(),
+            name: localpart(&user_id).to_string(),
+            user_id,
+            member_counts: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        info!(user_id = adapter.user_id, "Starting matrix bot");
+
+        let initial = self.api.sync(None, Duration::ZERO).await?;
+        self.accept_invites(&initial).await;
+        let mut since = initial.next_batch;
+
+        loop {
+            let response = self.api.sync(Some(&since), SYNC_TIMEOUT).await?;
+            since = response.next_batch.clone();
+            self.accept_invites(&response).await;
+
+            for (room_id, room) in response.rooms.join {
+                if let Some(count) = room.summary.joined_member_count {
+                    adapter
+                        .member_counts
+                        .lock()
+                        .await
+                        .insert(room_id.clone(), count as usize);
+                }
+
+                for event in room.timeline.events {
+                    if event.kind != "m.room.message" || event.sender == adapter.user_id {
+                        continue;
+                    }
+
+                    let pipeline = self.pipeline.clone();
+                    let adapter = adapter.clone();
+                    let room_id = room_id.clone();
+                    tokio::spawn(async move {
+                        let is_direct = adapter.is_direct(&room_id).await;
+                        let message = MatrixMessage {
+                            room_id,
+                            event,
+                            is_direct,
+                        };
+                        if let Err(err) = pipeline.handle(&adapter, message).await {
+                            error!(%err, "Failed to handle message");
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    async fn accept_invites(&self, response: &SyncResponse) {
+        for room_id in response.rooms.invite.keys() {
+            info!(room_id, "Joining room");
+            if let Err(err) = self.api.join(room_id).await {
+                error!(%err, room_id, "Failed to join room");
+            }
+        }
+    }
+}
+
+/// A room message along with whether the room is a direct message.
+#[derive(Clone, Debug)]
+pub struct MatrixMessage {
+    pub room_id: String,
+    pub event: RoomEvent,
+    pub is_direct: bool,
+}
+
+impl MatrixMessage {
+    fn relation(&self) -> &Value {
+        &self.event.content["m.relates_to"]
+    }
+
+    /// Root of the thread the message was sent in, if any.
+    fn thread_root(&self) -> Option<&str> {
+        let relation = self.relation();
+        if relation["rel_type"] != "m.thread" {
+            return None;
+        }
+        relation["event_id"].as_str()
+    }
+}
+
+impl From<&MatrixMessage> for knowledge::Message {
+    fn from(msg: &MatrixMessage) -> Self {
+        let event = &msg.event;
+        let in_reply_to = msg.relation()["m.in_reply_to"]["event_id"].as_str();
+
+        Self {
+            id: event.event_id.clone(),
+            source: knowledge::Source::Matrix,
+            source_id: event.sender.clone(),
+            channel_type: if msg.thread_root().is_some() {
+                knowledge::ChannelType::Thread
+            } else if msg.is_direct {
+                knowledge::ChannelType::DirectMessage
+            } else {
+                knowledge::ChannelType::Text
+            },
+            channel_id: msg.room_id.clone(),
+            account_id: event.sender.clone(),
+            role: knowledge::Role::User,
+            content: strip_reply_fallback(event.content["body"].as_str().unwrap_or_default()),
+            created_at: Some(
+                chrono::DateTime::from_timestamp_millis(event.origin_server_ts)
+                    .unwrap_or_else(chrono::Utc::now),
+            ),
+            // Messages in a thread refer to its root, so replies stay in it
+            reply_to: msg.thread_root().or(in_reply_to).map(|id| id.to_string()),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct MatrixAdapter {
+    api: MatrixApi,
+    /// The bot's own user id and the localpart of it, used as its name.
+    user_id: String,
+    name: String,
+    /// Joined members by room, from sync summaries or fetched when missing.
+    member_counts: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl MatrixAdapter {
+    /// Rooms with only the bot and one other member are direct messages.
+    async fn is_direct(&self, room_id: &str) -> bool {
+        if let Some(count) = self.member_counts.lock().await.get(room_id) {
+            return *count <= 2;
+        }
+
+        match self.api.joined_member_count(room_id).await {
+            Ok(count) => {
+                self.member_counts
+                    .lock()
+                    .await
+                    .insert(room_id.to_string(), count);
+                count <= 2
+            }
+            Err(err) => {
+                error!(%err, room_id, "Failed to fetch room members");
+                false
+            }
+        }
+    }
+
+    fn relation(&self, inbound: &InboundMessage) -> Value {
+        let in_reply_to = json!({ "event_id": inbound.message.id });
+        match (&inbound.message.channel_type, &inbound.message.reply_to) {
+            (knowledge::ChannelType::Thread, Some(root)) => json!({
+                "rel_type": "m.thread",
+                "event_id": root,
+                "is_falling_back": false,
+                "m.in_reply_to": in_reply_to,
+            }),
+            _ => json!({ "m.in_reply_to": in_reply_to }),
+        }
+    }
+}
+
+#[async_trait]
+impl PlatformAdapter for MatrixAdapter {
+    type Event = MatrixMessage;
+
+    fn normalize(&self, msg: &MatrixMessage) -> Option<InboundMessage> {
+        let content = &msg.event.content;
+        // Notices are sent by bots, and edits repeat a message already handled
+        if msg.event.sender == self.user_id
+            || !matches!(content["msgtype"].as_str(), Some("m.text" | "m.emote"))
+            || msg.relation()["rel_type"] == "m.replace"
+        {
+            return None;
+        }
+
+        let mut message = knowledge::Message::from(msg);
+        let mut mentioned_names = std::collections::HashSet::new();
+        let mut mentioned_ids = std::collections::HashSet::new();
+        for user_id in content["m.mentions"]["user_ids"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|user_id| user_id.as_str())
+        {
+            mentioned_ids.insert(user_id.to_string());
+            if user_id == self.user_id {
+                mentioned_names.insert(self.name.clone());
+            } else {
+                mentioned_names.insert(user_id.to_string());
+            }
+        }
+        // Clients without intentional mentions put the full user id in the body
+        if message.content.contains(&self.user_id) {
+            mentioned_names.insert(self.name.clone());
+            message.content = message.content.replace(&self.user_id, &self.name);
+        }
+
+        debug!(
+            room_id = msg.room_id,
+            id = message.id,
+            "Received matrix message"
+        );
+
+        Some(InboundMessage {
+            message,
+            author: Some(localpart(&msg.event.sender).to_string()),
+            channel_name: None,
+            context: None,
+            mentioned_names,
+            mentioned_ids,
+            // The reply fallback quotes the author of the message replied to
+            is_reply_to_bot: content["body"]
+                .as_str()
+                .is_some_and(|body| body.starts_with(&format!("> <{}>", self.user_id))),
+        })
+    }
+
+    fn bot_id(&self) -> Option<String> {
+        Some(self.user_id.clone())
+    }
+
+    fn max_message_length(&self) -> usize {
+        MAX_MESSAGE_LENGTH
+    }
+
+    fn edit_interval(&self) -> Option<Duration> {
+        Some(EDIT_INTERVAL)
+    }
+
+    async fn send(
+        &self,
+        inbound: &InboundMessage,
+        text: &str,
+    ) -> Result<knowledge::Message, AdapterError> {
+        let content = json!({
+            "msgtype": "m.text",
+            "body": text,
+            "m.relates_to": self.relation(inbound),
+        });
+        let event_id = self
+            .api
+            .send_message(&inbound.message.channel_id, &content)
+            .await?;
+
+        Ok(knowledge::Message {
+            id: event_id,
+            source: knowledge::Source::Matrix,
+            source_id: self.user_id.clone(),
+            channel_type: inbound.message.channel_type.clone(),
+            channel_id: inbound.message.channel_id.clone(),
+            account_id: self.user_id.clone(),
+            role: knowledge::Role::Assistant,
+            content: text.to_string(),
+            created_at: Some(chrono::Utc::now()),
+            reply_to: Some(inbound.message.id.clone()),
+        })
+    }
+
+    /// Replaces the message with an `m.replace` event. The returned message
+    /// keeps the original id, which later edits have to refer to.
+    async fn edit(
+        &self,
+        sent: &knowledge::Message,
+        text: &str,
+    ) -> Result<knowledge::Message, AdapterError> {
+        let content = json!({
+            "msgtype": "m.text",
+            "body": format!("* {}", text),
+            "m.new_content": { "msgtype": "m.text", "body": text },
+            "m.relates_to": { "rel_type": "m.replace", "event_id": sent.id },
+        });
+        self.api.send_message(&sent.channel_id, &content).await?;
+
+        Ok(knowledge::Message {
+            content: text.to_string(),
+            ..sent.clone()
+        })
+    }
+
+    async fn delete(&self, sent: &knowledge::Message) -> Result<(), AdapterError> {
+        self.api.redact(&sent.channel_id, &sent.id).await?;
+        Ok(())
+    }
+}
+
+/// `alice` for `@alice:example.org`.
+fn localpart(user_id: &str) -> &str {
+    let user_id = user_id.strip_prefix('@').unwrap_or(user_id);
+    user_id
+        .split_once(':')
+        .map_or(user_id, |(localpart, _)| localpart)
+}
+
+/// Replies quote the message they reply to as `> ` lines before the reply
+/// itself, for clients without reply support.
+fn strip_reply_fallback(body: &str) -> String {
+    if !body.starts_with("> ") {
+        return body.to_string();
+    }
+
+    body.lines()
+        .skip_while(|line| line.starts_with('>'))
+        .skip_while(|line| line.trim().is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::{Path, State},
+        routing::{get, put},
+        Json, Router,
+    };
+
+    type Sent = Arc<std::sync::Mutex<Vec<(String, Value)>>>;
+
+    /// Serves the endpoints the adapter uses, recording sent messages.
+    async fn homeserver() -> (String, Sent) {
+        let sent: Sent = Arc::default();
+        let router = Router::new()
+            .route(
+                "/_matrix/client/v3/account/whoami",
+                get(|| async { Json(json!({ "user_id": "@shinobi:localhost" })) }),
+            )
+            .route(
+                "/_matrix/client/v3/rooms/:room_id/joined_members",
+                get(|| async {
+                    Json(json!({ "joined": {
+                        "@shinobi:localhost": {},
+                        "@alice:localhost": {},
+                    } }))
+                }),
+            )
+            .route(
+                "/_matrix/client/v3/rooms/:room_id/send/m.room.message/:txn_id",
+                put(
+                    |State(sent): State<Sent>,
+                     Path((room_id, _txn_id)): Path<(String, String)>,
+                     Json(content): Json<Value>| async move {
+                        sent.lock().unwrap().push((room_id, content));
+                        Json(json!({ "event_id": "$reply" }))
+                    },
+                ),
+            )
+            .with_state(sent.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+        (format!("http://{}", addr), sent)
+    }
+
+    fn message(content: Value) -> MatrixMessage {
+        MatrixMessage {
+            room_id: "!room:localhost".to_string(),
+            event: RoomEvent {
+                kind: "m.room.message".to_string(),
+                event_id: "$event".to_string(),
+                sender: "@alice:localhost".to_string(),
+                origin_server_ts: 1_700_000_000_000,
+                content,
+            },
+            is_direct: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_adapter() {
+        let (url, sent) = homeserver().await;
+        let api = MatrixApi::new(&url, "token");
+        let user_id = api.whoami().await.unwrap();
+        let adapter = MatrixAdapter {
+            api,
+            name: localpart(&user_id).to_string(),
+            user_id,
+            member_counts: Arc::default(),
+        };
+        assert!(adapter.is_direct("!room:localhost").await);
+
+        let inbound = adapter
+            .normalize(&message(json!({
+                "msgtype": "m.text",
+                "body": "> <@bob:localhost> where is the tavern?\n\nhey @shinobi:localhost, do you know?",
+                "m.mentions": { "user_ids": ["@shinobi:localhost"] },
+                "m.relates_to": { "m.in_reply_to": { "event_id": "$question" } },
+            })))
+            .unwrap();
+        assert_eq!(inbound.message.content, "hey shinobi, do you know?");
+        assert_eq!(inbound.message.reply_to.as_deref(), Some("$question"));
+        assert_eq!(inbound.author.as_deref(), Some("alice"));
+        assert!(inbound.mentioned_names.contains("shinobi"));
+        assert!(inbound.mentioned_ids.contains("@shinobi:localhost"));
+        assert!(!inbound.is_reply_to_bot);
+
+        let reply = adapter.send(&inbound, "It's north").await.unwrap();
+        assert_eq!(reply.id, "$reply");
+
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent[0].0, "!room:localhost");
+        assert_eq!(sent[0].1["body"], "It's north");
+        assert_eq!(
+            sent[0].1["m.relates_to"]["m.in_reply_to"]["event_id"],
+            "$event"
+        );
+    }
+
+    #[test]
+    fn test_thread_message() {
+        let msg = message(json!({
+            "msgtype": "m.text",
+            "body": "in a thread",
+            "m.relates_to": { "rel_type": "m.thread", "event_id": "$root" },
+        }));
+
+        let message = knowledge::Message::from(&msg);
+        assert_eq!(message.channel_type, knowledge::ChannelType::Thread);
+        assert_eq!(message.reply_to.as_deref(), Some("$root"));
+    }
+}
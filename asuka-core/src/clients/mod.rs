@@ -1,4 +1,10 @@
+pub mod api;
+pub mod cli;
 pub mod discord;
+pub mod game;
 pub mod github;
+pub mod matrix;
+pub mod slack;
 pub mod telegram;
+pub mod twitch;
 pub mod twitter;
@@ -0,0 +1,598 @@
+use async_trait::async_trait;
+use futures_util::{SinkExt, StreamExt};
+use regex::Regex;
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tokio::sync::Mutex;
+use tokio_tungstenite::{connect_async, tungstenite::Message as WsMessage};
+use tracing::{debug, error, info, warn};
+
+use crate::pipeline::{AdapterError, InboundMessage, MessagePipeline, PlatformAdapter};
+use crate::{agent::Agent, attention::Attention, knowledge};
+
+mod mrkdwn;
+
+pub use mrkdwn::to_mrkdwn;
+
+const SLACK_API_URL: &str = "https://slack.com/api";
+const MAX_MESSAGE_LENGTH: usize = 4000;
+/// `chat.update` allows about 50 calls per minute.
+const EDIT_INTERVAL: Duration = Duration::from_millis(1500);
+
+#[derive(Debug, thiserror::Error)]
+pub enum SlackError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Slack API error: {0}")]
+    Api(String),
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error("Invalid event: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+/// The parts of the Slack Web API used by the client.
+#[derive(Clone)]
+pub struct SlackApi {
+    client: reqwest::Client,
+    base_url: String,
+    bot_token: Arc<str>,
+    /// Display names by user id, users rarely change them.
+    names: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl SlackApi {
+    pub fn new(bot_token: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: SLACK_API_URL.to_string(),
+            bot_token: bot_token.into(),
+            names: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.trim_end_matches('/').to_string();
+        self
+    }
+
+    async fn call(
+        &self,
+        method: &str,
+        token: &str,
+        params: &[(&str, &str)],
+    ) -> Result<Value, SlackError> {
+        let response: Value = self
+            .client
+            .post(format!("{}/{}", self.base_url, method))
+            .bearer_auth(token)
+            .form(params)
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        if response["ok"].as_bool() != Some(true) {
+            let error = response["error"].as_str().unwrap_or("unknown_error");
+            return Err(SlackError::Api(format!("{} failed: {}", method, error)));
+        }
+
+        Ok(response)
+    }
+
+    /// Opens a Socket Mode connection with an app level token and returns its URL.
+    pub async fn open_connection(&self, app_token: &str) -> Result<String, SlackError> {
+        let response = self.call("apps.connections.open", app_token, &[]).await?;
+        response["url"]
+            .as_str()
+            .map(|url| url.to_string())
+            .ok_or_else(|| SlackError::Api("apps.connections.open returned no url".to_string()))
+    }
+
+    /// Returns the bot's user id and name.
+    pub async fn auth_test(&self) -> Result<(String, String), SlackError> {
+        let response = self.call("auth.test", &self.bot_token, &[]).await?;
+        Ok((
+            response["user_id"].as_str().unwrap_or_default().to_string(),
+            response["user"].as_str().unwrap_or_default().to_string(),
+        ))
+    }
+
+    pub async fn user_name(&self, user_id: &str) -> Option<String> {
+        if let Some(name) = self.names.lock().await.get(user_id) {
+            return Some(name.clone());
+        }
+
+        let response = match self
+            .call("users.info", &self.bot_token, &[("user", user_id)])
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                error!(%err, "Failed to fetch user");
+                return None;
+            }
+        };
+        let user = &response["user"];
+        let name = [
+            &user["profile"]["display_name"],
+            &user["real_name"],
+            &user["name"],
+        ]
+        .into_iter()
+        .filter_map(|name| name.as_str())
+        .find(|name| !name.is_empty())?
+        .to_string();
+
+        self.names
+            .lock()
+            .await
+            .insert(user_id.to_string(), name.clone());
+        Some(name)
+    }
+
+    /// Posts a message and returns its `ts`, which identifies it in the channel.
+    pub async fn post_message(
+        &self,
+        channel: &str,
+        text: &str,
+        thread_ts: Option<&str>,
+    ) -> Result<String, SlackError> {
+        let mut params = vec![("channel", channel), ("text", text)];
+        if let Some(thread_ts) = thread_ts {
+            params.push(("thread_ts", thread_ts));
+        }
+
+        let response = self
+            .call("chat.postMessage", &self.bot_token, &params)
+            .await?;
+        Ok(response["ts"].as_str().unwrap_or_default().to_string())
+    }
+
+    pub async fn update_message(
+        &self,
+        channel: &str,
+        ts: &str,
+        text: &str,
+    ) -> Result<(), SlackError> {
+        self.call(
+            "chat.update",
+            &self.bot_token,
+            &[("channel", channel), ("ts", ts), ("text", text)],
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete_message(&self, channel: &str, ts: &str) -> Result<(), SlackError> {
+        self.call(
+            "chat.delete",
+            &self.bot_token,
+            &[("channel", channel), ("ts", ts)],
+        )
+        .await?;
+        Ok(())
+    }
+}
+
+/// Socket Mode envelope wrapping every event.
+#[derive(Debug, Deserialize)]
+struct Envelope {
+    #[serde(rename = "type")]
+    kind: String,
+    envelope_id: Option<String>,
+    payload: Option<EventPayload>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EventPayload {
+    event: Option<SlackEvent>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct SlackEvent {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub subtype: Option<String>,
+    pub user: Option<String>,
+    pub bot_id: Option<String>,
+    #[serde(default)]
+    pub text: String,
+    #[serde(default)]
+    pub ts: String,
+    pub thread_ts: Option<String>,
+    /// Author of the thread's parent, sent for replies in threads.
+    pub parent_user_id: Option<String>,
+    #[serde(default)]
+    pub channel: String,
+    pub channel_type: Option<String>,
+}
+
+impl SlackEvent {
+    /// Parent of the thread the message was posted in, unless it is the parent.
+    fn thread_parent(&self) -> Option<&str> {
+        self.thread_ts
+            .as_deref()
+            .filter(|thread_ts| *thread_ts != self.ts)
+    }
+}
+
+/// An event along with its author's display name, looked up before the event
+/// is handled.
+#[derive(Clone, Debug)]
+pub struct SlackMessage {
+    pub event: SlackEvent,
+    pub author: Option<String>,
+}
+
+impl From<&SlackEvent> for knowledge::Message {
+    fn from(event: &SlackEvent) -> Self {
+        let user = event.user.clone().unwrap_or_default();
+        let created_at = event
+            .ts
+            .split_once('.')
+            .and_then(|(secs, micros)| {
+                chrono::DateTime::from_timestamp(
+                    secs.parse().ok()?,
+                    micros.parse::<u32>().ok()?.saturating_mul(1000),
+                )
+            })
+            .unwrap_or_else(chrono::Utc::now);
+
+        Self {
+            id: message_id(&event.channel, &event.ts),
+            source: knowledge::Source::Slack,
+            source_id: user.clone(),
+            channel_type: if event.channel_type.as_deref() == Some("im") {
+                knowledge::ChannelType::DirectMessage
+            } else if event.thread_parent().is_some() {
+                knowledge::ChannelType::Thread
+            } else {
+                knowledge::ChannelType::Text
+            },
+            channel_id: event.channel.clone(),
+            account_id: user,
+            role: knowledge::Role::User,
+            content: event.text.clone(),
+            created_at: Some(created_at),
+            reply_to: event
+                .thread_parent()
+                .map(|ts| message_id(&event.channel, ts)),
+        }
+    }
+}
+
+/// A `ts` only identifies a message within its channel, so stored ids are
+/// prefixed with the channel.
+fn message_id(channel: &str, ts: &str) -> String {
+    format!("{channel}:{ts}")
+}
+
+/// The `ts` of a stored message id.
+fn message_ts(id: &str) -> &str {
+    id.rsplit_once(':').map_or(id, |(_, ts)| ts)
+}
+
+#[derive(Clone)]
+pub struct SlackClient<M: CompletionModel, E: EmbeddingModel + 'static> {
+    pipeline: MessagePipeline<M, E>,
+    api: SlackApi,
+    app_token: String,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> SlackClient<M, E> {
+    /// Creates a client from an app level token (`xapp-`) with the
+    /// `connections:write` scope and a bot token (`xoxb-`).
+    pub fn new(
+        agent: Agent<M, E>,
+        attention: Attention<M>,
+        app_token: &str,
+        bot_token: &str,
+    ) -> Self {
+        Self::from_pipeline(MessagePipeline::new(agent, attention), app_token, bot_token)
+    }
+
+    /// Creates a client from a pipeline, e.g. one with middleware.
+    pub fn from_pipeline(
+        pipeline: MessagePipeline<M, E>,
+        app_token: &str,
+        bot_token: &str,
+    ) -> Self {
+        Self {
+            pipeline,
+            api: SlackApi::new(bot_token),
+            app_token: app_token.to_string(),
+        }
+    }
+
+    pub fn with_api(mut self, api: SlackApi) -> Self {
+        self.api = api;
+        self
+    }
+
+    pub async fn start(&self) -> Result<(), SlackError> {
+        let (user_id, name) = self.api.auth_test().await?;
+        let adapter = SlackAdapter {
+            api: self.api.clone(),
+            user_id,
+            name,
+        };
+
+        info!(name = adapter.name, "Starting slack bot");
+
+        // Slack refreshes Socket Mode connections every few hours, asking the
+        // client to reconnect first
+        loop {
+            let url = self.api.open_connection(&self.app_token).await?;
+            let (mut socket, _) = connect_async(url.as_str()).await?;
+            debug!("Connected to Slack");
+
+            while let Some(frame) = socket.next().await {
+                let text = match frame? {
+                    WsMessage::Text(text) => text,
+                    WsMessage::Close(_) => break,
+                    _ => continue,
+                };
+                let envelope: Envelope = match serde_json::from_str(&text) {
+                    Ok(envelope) => envelope,
+                    Err(err) => {
+                        warn!(%err, frame = %text, "Skipping unexpected Slack frame");
+                        continue;
+                    }
+                };
+
+                // Events have to be acknowledged within 3 seconds, before the
+                // reply is generated
+                if let Some(envelope_id) = &envelope.envelope_id {
+                    let ack = json!({ "envelope_id": envelope_id }).to_string();
+                    socket.send(WsMessage::Text(ack.into())).await?;
+                }
+
+                match envelope.kind.as_str() {
+                    "events_api" => {
+                        if let Some(event) = envelope.payload.and_then(|payload| payload.event) {
+                            self.dispatch(&adapter, event);
+                        }
+                    }
+                    "disconnect" => break,
+                    _ => debug!(kind = envelope.kind, "Ignoring Slack envelope"),
+                }
+            }
+
+            info!("Reconnecting to Slack");
+        }
+    }
+
+    /// Handles message events in the background. `app_mention` events aren't
+    /// handled since the same message also arrives as a `message` event.
+    fn dispatch(&self, adapter: &SlackAdapter, event: SlackEvent) {
+        if event.kind != "message" || event.subtype.is_some() {
+            return;
+        }
+
+        let pipeline = self.pipeline.clone();
+        let adapter = adapter.clone();
+        tokio::spawn(async move {
+            let author = match &event.user {
+                Some(user) => adapter.api.user_name(user).await,
+                None => None,
+            };
+            if let Err(err) = pipeline
+                .handle(&adapter, SlackMessage { event, author })
+                .await
+            {
+                error!(%err, "Failed to handle message");
+            }
+        });
+    }
+}
+
+#[derive(Clone)]
+struct SlackAdapter {
+    api: SlackApi,
+    /// The bot's own user id and name.
+    user_id: String,
+    name: String,
+}
+
+#[async_trait]
+impl PlatformAdapter for SlackAdapter {
+    type Event = SlackMessage;
+
+    fn normalize(&self, msg: &SlackMessage) -> Option<InboundMessage> {
+        let event = &msg.event;
+        if event.bot_id.is_some() || event.user.as_deref().unwrap_or(&self.user_id) == self.user_id
+        {
+            return None;
+        }
+
+        // Mentions are written as <@U012AB3CD>, the bot's are replaced with
+        // its name so the prompt reads naturally
+        let mut mentioned_names = std::collections::HashSet::new();
+        let mut mentioned_ids = std::collections::HashSet::new();
+        for captures in mention_regex().captures_iter(&event.text) {
+            mentioned_ids.insert(captures["id"].to_string());
+            if captures["id"] == self.user_id {
+                mentioned_names.insert(self.name.clone());
+            } else {
+                mentioned_names.insert(captures["id"].to_string());
+            }
+        }
+
+        let mut message = knowledge::Message::from(event);
+        message.content = event
+            .text
+            .replace(&format!("<@{}>", self.user_id), &format!("@{}", self.name));
+
+        Some(InboundMessage {
+            message,
+            author: msg.author.clone(),
+            channel_name: None,
+            context: None,
+            mentioned_names,
+            mentioned_ids,
+            is_reply_to_bot: event.thread_parent().is_some()
+                && event.parent_user_id.as_deref() == Some(self.user_id.as_str()),
+        })
+    }
+
+    fn bot_id(&self) -> Option<String> {
+        Some(self.user_id.clone())
+    }
+
+    fn max_message_length(&self) -> usize {
+        MAX_MESSAGE_LENGTH
+    }
+
+    fn edit_interval(&self) -> Option<Duration> {
+        Some(EDIT_INTERVAL)
+    }
+
+    /// Replies in the message's thread, starting one for messages in channels.
+    async fn send(
+        &self,
+        inbound: &InboundMessage,
+        text: &str,
+    ) -> Result<knowledge::Message, AdapterError> {
+        let thread_ts = match inbound.message.channel_type {
+            knowledge::ChannelType::DirectMessage => None,
+            knowledge::ChannelType::Thread => inbound.message.reply_to.as_deref().map(message_ts),
+            _ => Some(message_ts(&inbound.message.id)),
+        };
+
+        let channel = &inbound.message.channel_id;
+        let text = to_mrkdwn(text);
+        let ts = self.api.post_message(channel, &text, thread_ts).await?;
+
+        Ok(knowledge::Message {
+            id: message_id(channel, &ts),
+            source: knowledge::Source::Slack,
+            source_id: self.user_id.clone(),
+            channel_type: inbound.message.channel_type.clone(),
+            channel_id: channel.clone(),
+            account_id: self.user_id.clone(),
+            role: knowledge::Role::Assistant,
+            content: text,
+            created_at: Some(chrono::Utc::now()),
+            reply_to: thread_ts.map(|ts| message_id(channel, ts)),
+        })
+    }
+
+    async fn edit(
+        &self,
+        sent: &knowledge::Message,
+        text: &str,
+    ) -> Result<knowledge::Message, AdapterError> {
+        let text = to_mrkdwn(text);
+        self.api
+            .update_message(&sent.channel_id, message_ts(&sent.id), &text)
+            .await?;
+
+        Ok(knowledge::Message {
+            content: text,
+            ..sent.clone()
+        })
+    }
+
+    async fn delete(&self, sent: &knowledge::Message) -> Result<(), AdapterError> {
+        self.api
+            .delete_message(&sent.channel_id, message_ts(&sent.id))
+            .await?;
+        Ok(())
+    }
+}
+
+fn mention_regex() -> &'static Regex {
+    static MENTION_REGEX: OnceLock<Regex> = OnceLock::new();
+    MENTION_REGEX.get_or_init(|| Regex::new(r"<@(?<id>[A-Z0-9]+)(?:\|[^>]*)?>").unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adapter() -> SlackAdapter {
+        SlackAdapter {
+            api: SlackApi::new("xoxb-test"),
+            user_id: "U0BOT".to_string(),
+            name: "shinobi".to_string(),
+        }
+    }
+
+    fn event(value: Value) -> SlackMessage {
+        SlackMessage {
+            event: serde_json::from_value(value).unwrap(),
+            author: Some("Alice".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_normalize() {
+        let adapter = adapter();
+
+        let inbound = adapter
+            .normalize(&event(json!({
+                "type": "message",
+                "user": "U0ALICE",
+                "text": "<@U0BOT> what about <@U0CAROL>?",
+                "ts": "1700000000.000100",
+                "channel": "C0GENERAL",
+                "channel_type": "channel",
+            })))
+            .unwrap();
+        assert_eq!(inbound.message.id, "C0GENERAL:1700000000.000100");
+        assert_eq!(message_ts(&inbound.message.id), "1700000000.000100");
+        assert_eq!(inbound.message.channel_type, knowledge::ChannelType::Text);
+        assert_eq!(inbound.message.content, "@shinobi what about <@U0CAROL>?");
+        assert!(inbound.mentioned_names.contains("shinobi"));
+        assert!(inbound.mentioned_names.contains("U0CAROL"));
+        assert!(inbound.mentioned_ids.contains("U0BOT"));
+
+        let inbound = adapter
+            .normalize(&event(json!({
+                "type": "message",
+                "user": "U0ALICE",
+                "text": "and then?",
+                "ts": "1700000100.000200",
+                "thread_ts": "1700000000.000100",
+                "channel": "C0GENERAL",
+                "channel_type": "channel",
+            })))
+            .unwrap();
+        assert_eq!(inbound.message.channel_type, knowledge::ChannelType::Thread);
+        assert_eq!(
+            inbound.message.reply_to.as_deref(),
+            Some("C0GENERAL:1700000000.000100")
+        );
+
+        let inbound = adapter
+            .normalize(&event(json!({
+                "type": "message",
+                "user": "U0ALICE",
+                "text": "hi",
+                "ts": "1700000200.000300",
+                "channel": "D0ALICE",
+                "channel_type": "im",
+            })))
+            .unwrap();
+        assert_eq!(
+            inbound.message.channel_type,
+            knowledge::ChannelType::DirectMessage
+        );
+
+        let own = event(json!({
+            "type": "message",
+            "user": "U0BOT",
+            "text": "hello",
+            "ts": "1700000300.000400",
+            "channel": "C0GENERAL",
+        }));
+        assert!(adapter.normalize(&own).is_none());
+    }
+}
@@ -0,0 +1,109 @@
+use regex::Regex;
+use std::sync::OnceLock;
+
+/// Marks bold text and headings while italics are converted, since both
+/// use `*` in mrkdwn.
+const BOLD_MARKER: char = '\u{1}';
+
+/// Converts the Markdown models write into Slack's mrkdwn. Code spans and
+/// blocks are only escaped, everything else gets bold, italics, strikethrough,
+/// links, headings and bullet lists rewritten.
+pub fn to_mrkdwn(markdown: &str) -> String {
+    let mut in_code_block = false;
+
+    markdown
+        .lines()
+        .map(|line| {
+            let line = escape(line);
+            if line.trim_start().starts_with("```") {
+                in_code_block = !in_code_block;
+                return line;
+            }
+            if in_code_block {
+                return line;
+            }
+            convert_line(&line)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Slack reads `&`, `<` and `>` as control characters.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn convert_line(line: &str) -> String {
+    let regexes = regexes();
+
+    let line = regexes
+        .heading
+        .replace(line, format!("$indent{0}$text{0}", BOLD_MARKER));
+    let line = regexes.bullet.replace(&line, "$indent• ");
+
+    // Backticks split the line into text and code spans, only text is converted
+    line.split('`')
+        .enumerate()
+        .map(|(i, segment)| {
+            if i % 2 == 1 {
+                return segment.to_string();
+            }
+            let segment = regexes
+                .bold
+                .replace_all(segment, format!("{0}$text{0}", BOLD_MARKER));
+            let segment = regexes.italic.replace_all(&segment, "_${text}_");
+            let segment = regexes.strike.replace_all(&segment, "~$text~");
+            let segment = regexes.link.replace_all(&segment, "<$url|$text>");
+            segment.replace(BOLD_MARKER, "*")
+        })
+        .collect::<Vec<_>>()
+        .join("`")
+}
+
+struct Regexes {
+    heading: Regex,
+    bullet: Regex,
+    bold: Regex,
+    italic: Regex,
+    strike: Regex,
+    link: Regex,
+}
+
+fn regexes() -> &'static Regexes {
+    static REGEXES: OnceLock<Regexes> = OnceLock::new();
+    REGEXES.get_or_init(|| Regexes {
+        heading: Regex::new(r"^(?<indent>\s*)#{1,6}\s+(?<text>.+?)\s*#*$").unwrap(),
+        bullet: Regex::new(r"^(?<indent>\s*)[-*+]\s+").unwrap(),
+        bold: Regex::new(r"(?:\*\*|__)(?<text>[^*_\n]+?)(?:\*\*|__)").unwrap(),
+        italic: Regex::new(r"\*(?<text>[^*\s][^*\n]*?)\*").unwrap(),
+        strike: Regex::new(r"~~(?<text>[^~\n]+?)~~").unwrap(),
+        link: Regex::new(r"\[(?<text>[^\]\n]+)\]\((?<url>[^)\s]+)\)").unwrap(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_mrkdwn() {
+        assert_eq!(
+            to_mrkdwn("**Bold** and *italic* and ~~gone~~"),
+            "*Bold* and _italic_ and ~gone~"
+        );
+        assert_eq!(
+            to_mrkdwn("See [the docs](https://book.dojoengine.org) & more"),
+            "See <https://book.dojoengine.org|the docs> &amp; more"
+        );
+        assert_eq!(
+            to_mrkdwn("## Getting started\n- one\n* two"),
+            "*Getting started*\n• one\n• two"
+        );
+        assert_eq!(
+            to_mrkdwn("Run `**not bold**`\n```\nlet x = **y**;\n```"),
+            "Run `**not bold**`\n```\nlet x = **y**;\n```"
+        );
+    }
+}
@@ -1,33 +1,35 @@
 use anyhow::Result;
-use rig::{
-    completion::{CompletionModel, Prompt},
-    embeddings::EmbeddingModel,
-};
-use std::collections::HashSet;
+use async_trait::async_trait;
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use std::{collections::HashSet, time::Duration};
 use teloxide::{
     dispatching::UpdateFilterExt,
     dptree,
     prelude::{LoggingErrorHandler, Requester},
+    types::{ChatId, MessageEntityKind, MessageId, UserId},
 };
-use tracing::{debug, error, info};
+use tracing::{error, info};
 
-use crate::{agent::Agent, attention::AttentionCommand};
-use crate::{
-    attention::{Attention, AttentionContext},
-    knowledge,
-};
+use crate::pipeline::{AdapterError, InboundMessage, MessagePipeline, PlatformAdapter};
+use crate::{agent::Agent, attention::Attention, knowledge};
 
-const MAX_HISTORY_MESSAGES: i64 = 10;
+const MAX_MESSAGE_LENGTH: usize = 4096;
+/// Telegram allows about one message edit per second in a chat.
+const EDIT_INTERVAL: Duration = Duration::from_millis(1500);
 
 #[derive(Clone)]
 pub struct TelegramClient<M: CompletionModel, E: EmbeddingModel + 'static> {
-    agent: Agent<M, E>,
-    attention: Attention<M>,
+    pipeline: MessagePipeline<M, E>,
 }
 
 impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TelegramClient<M, E> {
     pub fn new(agent: Agent<M, E>, attention: Attention<M>) -> Self {
-        Self { agent, attention }
+        Self::from_pipeline(MessagePipeline::new(agent, attention))
+    }
+
+    /// Creates a client from a pipeline, e.g. one with middleware.
+    pub fn from_pipeline(pipeline: MessagePipeline<M, E>) -> Self {
+        Self { pipeline }
     }
 
     pub async fn start(&self, token: &str) -> Result<()> {
@@ -59,112 +61,41 @@ impl From<teloxide::types::Message> for knowledge::Message {
             },
             channel_id: msg.chat.id.to_string(),
             account_id: user_id,
-            role: "user".to_string(),
+            role: knowledge::Role::User,
             content: msg.text().unwrap_or_default().to_string(),
             created_at: Some(msg.date),
+            reply_to: msg.reply_to_message().map(|reply| reply.id.to_string()),
         }
     }
 }
 
 impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TelegramClient<M, E> {
     async fn run(&self, bot: teloxide::Bot) -> Result<()> {
-        let knowledge = self.agent.knowledge().clone();
-        let attention = self.attention.clone();
-        let agent = self.agent.clone();
+        let pipeline = self.pipeline.clone();
+        let me = bot.get_me().await?;
+        let bot_id = me.id;
+        let bot_username = me.username().to_string();
 
-        let handler = dptree::entry()
-            .branch(teloxide::types::Update::filter_message().endpoint(move |bot: teloxide::Bot, msg: teloxide::types::Message| {
-                let knowledge = knowledge.clone();
-                let attention = attention.clone();
-                let agent = agent.clone();
+        let handler = dptree::entry().branch(teloxide::types::Update::filter_message().endpoint(
+            move |bot: teloxide::Bot, msg: teloxide::types::Message| {
+                let pipeline = pipeline.clone();
+                let bot_username = bot_username.clone();
 
                 async move {
-                    let knowledge_msg = knowledge::Message::from(msg.clone());
-
-                    if let Err(err) = knowledge.create_message(knowledge_msg.clone()).await {
-                        error!(?err, "Failed to store message");
-                        return Err(anyhow::anyhow!(err));
-                    }
-
-                    debug!("Fetching message history for channel {}", msg.chat.id);
-                    let history = match knowledge
-                        .channel_messages(&msg.chat.id.to_string(), MAX_HISTORY_MESSAGES)
-                        .await
-                    {
-                        Ok(messages) => {
-                            debug!(message_count = messages.len(), "Retrieved message history");
-                            messages
-                        }
-                        Err(err) => {
-                            error!(?err, "Failed to fetch recent messages");
-                            return Err(anyhow::anyhow!(err));
-                        }
-                    };
-
-                    let mentioned_names: HashSet<String> = msg.text()
-                        .map(|text| {
-                            text.split_whitespace()
-                                .filter_map(|word| {
-                                    if word.starts_with('@') {
-                                        Some(word[1..].to_string())
-                                    } else {
-                                        None
-                                    }
-                                })
-                                .collect()
-                        })
-                        .unwrap_or_default();
-
-                    debug!(
-                        mentioned_names = ?mentioned_names,
-                        "Mentioned names in message"
-                    );
-
-                    let context = AttentionContext {
-                        message_content: msg.text().unwrap_or_default().to_string(),
-                        mentioned_names,
-                        history,
-                        channel_type: knowledge_msg.channel_type,
-                        source: knowledge_msg.source,
-                    };
-
-                    debug!(?context, "Attention context");
-
-                    match attention.should_reply(&context).await {
-                        AttentionCommand::Respond => {}
-                        _ => {
-                            debug!("Bot decided not to reply to message");
-                            return Ok(());
-                        }
-                    }
-
-                    let agent = agent
-                        .builder()
-                        .context(&format!(
-                            "Current time: {}",
-                            chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
-                        ))
-                        .context("Please keep your responses concise and under 2000 characters when possible.")
-                        .build();
-
-                    let response = match agent.prompt(msg.text().unwrap_or_default()).await {
-                        Ok(response) => response,
-                        Err(err) => {
-                            error!(?err, "Failed to generate response");
-                            return Err(anyhow::anyhow!(err));
-                        }
+                    let adapter = TelegramAdapter {
+                        bot,
+                        bot_id,
+                        bot_username,
                     };
-
-                    debug!(response = %response, "Generated response");
-
-                    if let Err(why) = bot.send_message(msg.chat.id, response).await {
-                        error!(?why, "Failed to send message");
-                        return Err(anyhow::anyhow!(why));
+                    if let Err(err) = pipeline.handle(&adapter, msg).await {
+                        error!(%err, "Failed to handle message");
+                        return Err(anyhow::anyhow!(err));
                     }
 
                     Ok(())
                 }
-            }));
+            },
+        ));
 
         let listener = teloxide::update_listeners::polling_default(bot.clone()).await;
 
@@ -179,3 +110,105 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TelegramClient<M
         Ok(())
     }
 }
+
+struct TelegramAdapter {
+    bot: teloxide::Bot,
+    bot_id: UserId,
+    bot_username: String,
+}
+
+#[async_trait]
+impl PlatformAdapter for TelegramAdapter {
+    type Event = teloxide::types::Message;
+
+    fn normalize(&self, msg: &teloxide::types::Message) -> Option<InboundMessage> {
+        let mentioned_names = msg
+            .text()
+            .map(|text| {
+                text.split_whitespace()
+                    .filter_map(|word| word.strip_prefix('@'))
+                    .map(|name| name.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        // Users without a username are mentioned by id, others by @username
+        let mut mentioned_ids = HashSet::new();
+        for entity in msg.parse_entities().unwrap_or_default() {
+            match entity.kind() {
+                MessageEntityKind::TextMention { user } => {
+                    mentioned_ids.insert(user.id.to_string());
+                }
+                MessageEntityKind::Mention
+                    if entity
+                        .text()
+                        .trim_start_matches('@')
+                        .eq_ignore_ascii_case(&self.bot_username) =>
+                {
+                    mentioned_ids.insert(self.bot_id.to_string());
+                }
+                _ => {}
+            }
+        }
+
+        Some(InboundMessage {
+            message: knowledge::Message::from(msg.clone()),
+            author: msg.from.as_ref().map(|user| {
+                user.username
+                    .clone()
+                    .unwrap_or_else(|| user.first_name.clone())
+            }),
+            channel_name: msg.chat.title().map(|title| title.to_string()),
+            context: None,
+            mentioned_names,
+            mentioned_ids,
+            is_reply_to_bot: msg
+                .reply_to_message()
+                .and_then(|reply| reply.from.as_ref())
+                .is_some_and(|user| user.id == self.bot_id),
+        })
+    }
+
+    fn bot_id(&self) -> Option<String> {
+        Some(self.bot_id.to_string())
+    }
+
+    fn max_message_length(&self) -> usize {
+        MAX_MESSAGE_LENGTH
+    }
+
+    fn edit_interval(&self) -> Option<Duration> {
+        Some(EDIT_INTERVAL)
+    }
+
+    async fn send(
+        &self,
+        inbound: &InboundMessage,
+        text: &str,
+    ) -> Result<knowledge::Message, AdapterError> {
+        let chat_id = ChatId(inbound.message.channel_id.parse()?);
+        let sent = self.bot.send_message(chat_id, text).await?;
+        Ok(sent.into())
+    }
+
+    async fn edit(
+        &self,
+        sent: &knowledge::Message,
+        text: &str,
+    ) -> Result<knowledge::Message, AdapterError> {
+        let chat_id = ChatId(sent.channel_id.parse()?);
+        let message_id = MessageId(sent.id.parse()?);
+        let edited = self
+            .bot
+            .edit_message_text(chat_id, message_id, text)
+            .await?;
+        Ok(edited.into())
+    }
+
+    async fn delete(&self, sent: &knowledge::Message) -> Result<(), AdapterError> {
+        let chat_id = ChatId(sent.channel_id.parse()?);
+        let message_id = MessageId(sent.id.parse()?);
+        self.bot.delete_message(chat_id, message_id).await?;
+        Ok(())
+    }
+}
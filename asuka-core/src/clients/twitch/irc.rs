@@ -0,0 +1,349 @@
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Arc,
+};
+use tokio::{net::TcpStream, sync::Mutex};
+use tokio_tungstenite::{
+    connect_async, tungstenite::Message as WsMessage, MaybeTlsStream, WebSocketStream,
+};
+use tracing::debug;
+
+type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum IrcError {
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::Error),
+    #[error("Login failed: {0}")]
+    Login(String),
+}
+
+/// A single IRC message with its IRCv3 tags.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IrcMessage {
+    pub tags: HashMap<String, String>,
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    /// Parses a line without its trailing `\r\n`.
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        let mut message = IrcMessage::default();
+
+        if let Some(tagged) = rest.strip_prefix('@') {
+            let (tags, remainder) = tagged.split_once(' ')?;
+            message.tags = tags
+                .split(';')
+                .map(|tag| match tag.split_once('=') {
+                    Some((key, value)) => (key.to_string(), unescape_tag(value)),
+                    None => (tag.to_string(), String::new()),
+                })
+                .collect();
+            rest = remainder.trim_start();
+        }
+
+        if let Some(prefixed) = rest.strip_prefix(':') {
+            let (prefix, remainder) = prefixed.split_once(' ')?;
+            message.prefix = Some(prefix.to_string());
+            rest = remainder.trim_start();
+        }
+
+        let (command, mut rest) = rest.split_once(' ').unwrap_or((rest, ""));
+        if command.is_empty() {
+            return None;
+        }
+        message.command = command.to_string();
+
+        while !rest.is_empty() {
+            if let Some(trailing) = rest.strip_prefix(':') {
+                message.params.push(trailing.to_string());
+                break;
+            }
+            let (param, remainder) = rest.split_once(' ').unwrap_or((rest, ""));
+            message.params.push(param.to_string());
+            rest = remainder.trim_start();
+        }
+
+        Some(message)
+    }
+
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags
+            .get(key)
+            .map(|value| value.as_str())
+            .filter(|value| !value.is_empty())
+    }
+
+    /// Nickname of the sender, taken from a `nick!user@host` prefix.
+    pub fn nick(&self) -> Option<&str> {
+        let prefix = self.prefix.as_deref()?;
+        prefix.split_once('!').map(|(nick, _)| nick)
+    }
+
+    /// Channel the message was sent to, without the leading `#`.
+    pub fn channel(&self) -> Option<&str> {
+        self.params.first()?.strip_prefix('#')
+    }
+
+    /// The trailing parameter, e.g. the text of a `PRIVMSG`.
+    pub fn text(&self) -> Option<&str> {
+        self.params.get(1).map(|text| text.as_str())
+    }
+}
+
+fn unescape_tag(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// Sends lines to the server. Cheap to clone and shared by every reply.
+#[derive(Clone)]
+pub struct IrcSender {
+    sink: Arc<Mutex<SplitSink<WsStream, WsMessage>>>,
+}
+
+impl IrcSender {
+    pub async fn send_raw(&self, line: &str) -> Result<(), IrcError> {
+        // Lines can't contain line breaks, they would be read as new commands
+        let line = line.replace(['\r', '\n'], " ");
+        self.sink
+            .lock()
+            .await
+            .send(WsMessage::Text(format!("{}\r\n", line).into()))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends `text` to a channel, as a reply to a message when `reply_to` is set.
+    pub async fn privmsg(
+        &self,
+        channel: &str,
+        text: &str,
+        reply_to: Option<&str>,
+    ) -> Result<(), IrcError> {
+        match reply_to {
+            Some(id) => {
+                self.send_raw(&format!(
+                    "@reply-parent-msg-id={} PRIVMSG #{} :{}",
+                    id, channel, text
+                ))
+                .await
+            }
+            None => {
+                self.send_raw(&format!("PRIVMSG #{} :{}", channel, text))
+                    .await
+            }
+        }
+    }
+}
+
+/// Connection to Twitch IRC (TMI) over WebSocket.
+pub struct IrcConnection {
+    stream: SplitStream<WsStream>,
+    sender: IrcSender,
+    pending: VecDeque<IrcMessage>,
+}
+
+impl IrcConnection {
+    pub async fn connect(url: &str) -> Result<Self, IrcError> {
+        let (stream, _) = connect_async(url).await?;
+        let (sink, stream) = stream.split();
+
+        Ok(Self {
+            stream,
+            sender: IrcSender {
+                sink: Arc::new(Mutex::new(sink)),
+            },
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn sender(&self) -> IrcSender {
+        self.sender.clone()
+    }
+
+    /// Authenticates and joins the channels. Tags and commands are requested
+    /// so messages carry ids and room state.
+    pub async fn login(
+        &mut self,
+        username: &str,
+        oauth_token: &str,
+        channels: &[String],
+    ) -> Result<(), IrcError> {
+        let token = oauth_token.trim_start_matches("oauth:");
+
+        self.sender
+            .send_raw("CAP REQ :twitch.tv/tags twitch.tv/commands")
+            .await?;
+        self.sender
+            .send_raw(&format!("PASS oauth:{}", token))
+            .await?;
+        self.sender
+            .send_raw(&format!("NICK {}", username.to_lowercase()))
+            .await?;
+
+        if !channels.is_empty() {
+            let channels = channels
+                .iter()
+                .map(|channel| format!("#{}", channel.trim_start_matches('#').to_lowercase()))
+                .collect::<Vec<_>>()
+                .join(",");
+            self.sender.send_raw(&format!("JOIN {}", channels)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the next message, or `None` once the connection is closed.
+    /// Pings are answered here and not returned.
+    pub async fn next(&mut self) -> Result<Option<IrcMessage>, IrcError> {
+        loop {
+            while let Some(message) = self.pending.pop_front() {
+                match message.command.as_str() {
+                    "PING" => {
+                        let payload = message.params.first().cloned().unwrap_or_default();
+                        self.sender.send_raw(&format!("PONG :{}", payload)).await?;
+                    }
+                    "NOTICE"
+                        if message
+                            .text()
+                            .is_some_and(|text| text.contains("Login authentication failed")) =>
+                    {
+                        return Err(IrcError::Login(message.text().unwrap_or_default().into()));
+                    }
+                    _ => return Ok(Some(message)),
+                }
+            }
+
+            match self.stream.next().await {
+                Some(Ok(WsMessage::Text(text))) => {
+                    debug!(text = text.as_str(), "Received IRC frame");
+                    self.pending
+                        .extend(text.as_str().split("\r\n").filter_map(IrcMessage::parse));
+                }
+                Some(Ok(WsMessage::Close(_))) | None => return Ok(None),
+                Some(Ok(_)) => {}
+                Some(Err(err)) => return Err(err.into()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_privmsg() {
+        let line = "@badge-info=;display-name=Alice;id=b34ccfc7;reply-parent-msg-id=;system-msg=Hello\\sthere\\:;user-id=1337 :alice!alice@alice.tmi.twitch.tv PRIVMSG #asuka :hey @shinobi how are you?";
+        let message = IrcMessage::parse(line).unwrap();
+
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.nick(), Some("alice"));
+        assert_eq!(message.channel(), Some("asuka"));
+        assert_eq!(message.text(), Some("hey @shinobi how are you?"));
+        assert_eq!(message.tag("display-name"), Some("Alice"));
+        assert_eq!(message.tag("system-msg"), Some("Hello there;"));
+        assert_eq!(message.tag("reply-parent-msg-id"), None);
+    }
+
+    #[test]
+    fn test_parse_without_tags() {
+        let message = IrcMessage::parse("PING :tmi.twitch.tv").unwrap();
+        assert_eq!(message.command, "PING");
+        assert_eq!(message.params, vec!["tmi.twitch.tv"]);
+
+        let message = IrcMessage::parse(":tmi.twitch.tv 001 shinobi :Welcome, GLHF!").unwrap();
+        assert_eq!(message.prefix.as_deref(), Some("tmi.twitch.tv"));
+        assert_eq!(message.params, vec!["shinobi", "Welcome, GLHF!"]);
+
+        assert!(IrcMessage::parse("").is_none());
+    }
+
+    /// Runs the client against a local stand-in for the Twitch server.
+    #[tokio::test]
+    async fn test_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+
+            let mut received = Vec::new();
+            while let Some(Ok(WsMessage::Text(text))) = ws.next().await {
+                received.push(text.trim_end().to_string());
+                if text.starts_with("JOIN") {
+                    break;
+                }
+            }
+
+            ws.send(WsMessage::Text(
+                "PING :tmi.twitch.tv\r\n@display-name=Alice;id=abc;user-id=42 :alice!alice@alice.tmi.twitch.tv PRIVMSG #asuka :hi shinobi\r\n".into(),
+            ))
+            .await
+            .unwrap();
+
+            while let Some(Ok(WsMessage::Text(text))) = ws.next().await {
+                received.push(text.trim_end().to_string());
+                if text.starts_with("@reply-parent-msg-id") {
+                    break;
+                }
+            }
+            received
+        });
+
+        let mut connection = IrcConnection::connect(&format!("ws://{}", addr))
+            .await
+            .unwrap();
+        connection
+            .login("Shinobi", "oauth:secret", &["#Asuka".to_string()])
+            .await
+            .unwrap();
+
+        let message = connection.next().await.unwrap().unwrap();
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.text(), Some("hi shinobi"));
+
+        connection
+            .sender()
+            .privmsg("asuka", "hello\nAlice", message.tag("id"))
+            .await
+            .unwrap();
+
+        assert_eq!(
+            server.await.unwrap(),
+            vec![
+                "CAP REQ :twitch.tv/tags twitch.tv/commands",
+                "PASS oauth:secret",
+                "NICK shinobi",
+                "JOIN #asuka",
+                "PONG :tmi.twitch.tv",
+                "@reply-parent-msg-id=abc PRIVMSG #asuka :hello Alice",
+            ]
+        );
+    }
+}
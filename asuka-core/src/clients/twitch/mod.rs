@@ -0,0 +1,316 @@
+use async_trait::async_trait;
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+use tracing::{debug, error, info};
+
+use crate::pipeline::{AdapterError, InboundMessage, MessagePipeline, PlatformAdapter};
+use crate::{agent::Agent, attention::Attention, knowledge};
+
+mod irc;
+
+pub use irc::{IrcConnection, IrcError, IrcMessage, IrcSender};
+
+pub const TWITCH_IRC_URL: &str = "wss://irc-ws.chat.twitch.tv:443";
+const MAX_MESSAGE_LENGTH: usize = 500;
+/// Twitch allows 20 messages per 30 seconds for accounts that aren't
+/// moderators of the channel.
+const MIN_SEND_INTERVAL: Duration = Duration::from_millis(1500);
+/// Prefix of the ids generated for messages without an `id` tag, e.g. from a
+/// plain IRC server. They are never sent back to Twitch as a reply parent.
+const GENERATED_ID_PREFIX: &str = "local-";
+
+/// Tells apart the ids generated within the same nanosecond.
+static GENERATED_IDS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Debug)]
+pub struct TwitchConfig {
+    /// Server to connect to, a local stand-in can be used for testing.
+    pub url: String,
+    /// Login of the bot account.
+    pub username: String,
+    /// OAuth token with the `chat:read` and `chat:edit` scopes.
+    pub oauth_token: String,
+    /// Channels to join, by login.
+    pub channels: Vec<String>,
+    /// Minimum time between messages sent to a channel. Channels in slow mode
+    /// use their own interval when it is longer.
+    pub min_send_interval: Duration,
+}
+
+impl TwitchConfig {
+    pub fn new(username: &str, oauth_token: &str, channels: Vec<String>) -> Self {
+        Self {
+            url: TWITCH_IRC_URL.to_string(),
+            username: username.to_lowercase(),
+            oauth_token: oauth_token.to_string(),
+            channels,
+            min_send_interval: MIN_SEND_INTERVAL,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TwitchClient<M: CompletionModel, E: EmbeddingModel + 'static> {
+    pipeline: MessagePipeline<M, E>,
+    config: TwitchConfig,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TwitchClient<M, E> {
+    pub fn new(agent: Agent<M, E>, attention: Attention<M>, config: TwitchConfig) -> Self {
+        Self::from_pipeline(MessagePipeline::new(agent, attention), config)
+    }
+
+    /// Creates a client from a pipeline, e.g. one with middleware.
+    pub fn from_pipeline(pipeline: MessagePipeline<M, E>, config: TwitchConfig) -> Self {
+        Self { pipeline, config }
+    }
+
+    /// Connects and handles chat messages until the connection is closed,
+    /// which is returned as an error so the client can be restarted.
+    pub async fn start(&self) -> Result<(), AdapterError> {
+        let mut connection = IrcConnection::connect(&self.config.url).await?;
+        connection
+            .login(
+                &self.config.username,
+                &self.config.oauth_token,
+                &self.config.channels,
+            )
+            .await?;
+
+        info!(channels = ?self.config.channels, "Starting twitch bot");
+
+        let adapter = TwitchAdapter {
+            sender: connection.sender(),
+            username: self.config.username.clone(),
+            min_send_interval: self.config.min_send_interval,
+            channels: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        while let Some(message) = connection.next().await? {
+            match message.command.as_str() {
+                "PRIVMSG" => {
+                    // Keep reading while the reply is generated, so pings are
+                    // answered in time
+                    let pipeline = self.pipeline.clone();
+                    let adapter = adapter.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = pipeline.handle(&adapter, message).await {
+                            error!(%err, "Failed to handle message");
+                        }
+                    });
+                }
+                "ROOMSTATE" => adapter.update_room_state(&message).await,
+                "RECONNECT" => {
+                    return Err("Server requested a reconnect".into());
+                }
+                _ => debug!(command = message.command, "Ignoring IRC message"),
+            }
+        }
+
+        Err("Connection closed".into())
+    }
+}
+
+impl From<&IrcMessage> for knowledge::Message {
+    fn from(msg: &IrcMessage) -> Self {
+        let user_id = msg
+            .tag("user-id")
+            .or(msg.nick())
+            .unwrap_or_default()
+            .to_string();
+        let created_at = msg
+            .tag("tmi-sent-ts")
+            .and_then(|ts| ts.parse().ok())
+            .and_then(chrono::DateTime::from_timestamp_millis)
+            .unwrap_or_else(chrono::Utc::now);
+
+        let channel_id = msg.channel().unwrap_or_default().to_string();
+        let id = match msg.tag("id").filter(|id| !id.is_empty()) {
+            Some(id) => id.to_string(),
+            None => generated_id(&channel_id, &user_id),
+        };
+
+        Self {
+            id,
+            source: knowledge::Source::Twitch,
+            source_id: user_id.clone(),
+            channel_type: knowledge::ChannelType::Text,
+            channel_id,
+            account_id: user_id,
+            role: knowledge::Role::User,
+            content: msg.text().unwrap_or_default().to_string(),
+            created_at: Some(created_at),
+            reply_to: msg.tag("reply-parent-msg-id").map(|id| id.to_string()),
+        }
+    }
+}
+
+fn generated_id(channel_id: &str, user_id: &str) -> String {
+    let now = chrono::Utc::now();
+    format!(
+        "{GENERATED_ID_PREFIX}{channel_id}-{user_id}-{}-{}",
+        now.timestamp_nanos_opt()
+            .unwrap_or_else(|| now.timestamp_micros()),
+        GENERATED_IDS.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[derive(Default)]
+struct ChannelState {
+    /// Slow mode interval from the channel's room state.
+    slow_mode: Duration,
+    last_sent: Option<Instant>,
+}
+
+#[derive(Clone)]
+struct TwitchAdapter {
+    sender: IrcSender,
+    username: String,
+    min_send_interval: Duration,
+    channels: Arc<Mutex<HashMap<String, ChannelState>>>,
+}
+
+impl TwitchAdapter {
+    async fn update_room_state(&self, message: &IrcMessage) {
+        let (Some(channel), Some(slow)) = (message.channel(), message.tag("slow")) else {
+            return;
+        };
+        let slow_mode = Duration::from_secs(slow.parse().unwrap_or_default());
+        debug!(channel, ?slow_mode, "Room state changed");
+
+        self.channels
+            .lock()
+            .await
+            .entry(channel.to_string())
+            .or_default()
+            .slow_mode = slow_mode;
+    }
+
+    /// Waits until a message may be sent to the channel. The slot is claimed
+    /// before waiting so concurrent replies queue up behind each other.
+    async fn wait_to_send(&self, channel: &str) {
+        let send_at = {
+            let mut channels = self.channels.lock().await;
+            let state = channels.entry(channel.to_string()).or_default();
+            let interval = state.slow_mode.max(self.min_send_interval);
+            let now = Instant::now();
+            let send_at = state
+                .last_sent
+                .map(|last_sent| (last_sent + interval).max(now))
+                .unwrap_or(now);
+            state.last_sent = Some(send_at);
+            send_at
+        };
+
+        tokio::time::sleep_until(send_at.into()).await;
+    }
+}
+
+#[async_trait]
+impl PlatformAdapter for TwitchAdapter {
+    type Event = IrcMessage;
+
+    fn normalize(&self, msg: &IrcMessage) -> Option<InboundMessage> {
+        if msg.nick() == Some(self.username.as_str()) {
+            return None;
+        }
+
+        let mentioned_names: HashSet<String> = msg
+            .text()?
+            .split_whitespace()
+            .filter_map(|word| word.strip_prefix('@'))
+            .map(|name| name.trim_end_matches(|c: char| !c.is_alphanumeric() && c != '_'))
+            .map(|name| name.to_string())
+            .collect();
+        // Logins are the ids mentions refer to, matched against `bot_id`
+        let mentioned_ids = mentioned_names
+            .iter()
+            .map(|name| name.to_lowercase())
+            .collect();
+
+        Some(InboundMessage {
+            message: knowledge::Message::from(msg),
+            author: msg
+                .tag("display-name")
+                .or(msg.nick())
+                .map(|name| name.to_string()),
+            channel_name: msg.channel().map(|channel| channel.to_string()),
+            context: None,
+            mentioned_names,
+            mentioned_ids,
+            is_reply_to_bot: msg.tag("reply-parent-user-login") == Some(self.username.as_str()),
+        })
+    }
+
+    /// The bot's login, so `@login` mentions address it on Twitch only.
+    fn bot_id(&self) -> Option<String> {
+        Some(self.username.clone())
+    }
+
+    fn max_message_length(&self) -> usize {
+        MAX_MESSAGE_LENGTH
+    }
+
+    async fn send(
+        &self,
+        inbound: &InboundMessage,
+        text: &str,
+    ) -> Result<knowledge::Message, AdapterError> {
+        let channel = &inbound.message.channel_id;
+        self.wait_to_send(channel).await;
+
+        let reply_to = Some(inbound.message.id.as_str()).filter(|id| !id.is_empty());
+        let reply_parent = reply_to.filter(|id| !id.starts_with(GENERATED_ID_PREFIX));
+        self.sender.privmsg(channel, text, reply_parent).await?;
+
+        // Twitch doesn't echo our own messages, so there is no id to record
+        let now = chrono::Utc::now();
+        Ok(knowledge::Message {
+            id: format!(
+                "{}-{}",
+                self.username,
+                now.timestamp_nanos_opt()
+                    .unwrap_or_else(|| now.timestamp_micros())
+            ),
+            source: knowledge::Source::Twitch,
+            source_id: self.username.clone(),
+            channel_type: knowledge::ChannelType::Text,
+            channel_id: channel.clone(),
+            account_id: self.username.clone(),
+            role: knowledge::Role::Assistant,
+            content: text.to_string(),
+            created_at: Some(now),
+            reply_to: reply_to.map(|id| id.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_id() {
+        let message = IrcMessage::parse(
+            "@id=b34ccfc7;user-id=1337 :alice!alice@alice.tmi.twitch.tv PRIVMSG #asuka :hi",
+        )
+        .unwrap();
+        assert_eq!(knowledge::Message::from(&message).id, "b34ccfc7");
+
+        // A plain IRC server sends no tags, every message still gets its own id
+        let message =
+            IrcMessage::parse(":alice!alice@alice.tmi.twitch.tv PRIVMSG #asuka :hi").unwrap();
+        let first = knowledge::Message::from(&message);
+        let second = knowledge::Message::from(&message);
+        assert!(first.id.starts_with("local-asuka-alice-"));
+        assert_ne!(first.id, second.id);
+    }
+}
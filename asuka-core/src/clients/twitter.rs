@@ -1,17 +1,18 @@
 use crate::{
     agent::Agent,
-    attention::{Attention, AttentionCommand, AttentionContext},
-    knowledge::{ChannelType, Message, Source},
+    attention::Attention,
+    knowledge::{ChannelType, ConversationMessage, Message, Role, Source},
+    pipeline::{AdapterError, InboundMessage, MessagePipeline, PlatformAdapter},
 };
 
-use rig::{
-    completion::{CompletionModel, Prompt},
-    embeddings::EmbeddingModel,
-};
+use async_trait::async_trait;
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
 use std::collections::HashSet;
-use tracing::{debug, error, info};
+use tracing::{error, info};
 use twitter::{authorization::Authorization, TwitterApi};
 use twitter_v2::data::ReferencedTweetKind;
+use twitter_v2::id::NumericId;
+use twitter_v2::query::TweetField;
 use twitter_v2::{
     self as twitter,
     authorization::{BearerToken, Oauth1aToken},
@@ -22,8 +23,7 @@ const MAX_HISTORY_TWEETS: i64 = 10;
 
 #[derive(Clone)]
 pub struct TwitterClient<M: CompletionModel, E: EmbeddingModel + 'static, A: Authorization> {
-    agent: Agent<M, E>,
-    attention: Attention<M>,
+    pipeline: MessagePipeline<M, E>,
     api: TwitterApi<A>,
 }
 
@@ -44,9 +44,15 @@ impl From<twitter::Tweet> for Message {
                 .author_id
                 .map(|id| id.to_string())
                 .unwrap_or_else(|| "0".to_string()),
-            role: "user".to_string(),
+            role: Role::User,
             content: tweet.text.clone(),
             created_at: Some(created_at),
+            reply_to: tweet.referenced_tweets.as_ref().and_then(|referenced| {
+                referenced
+                    .iter()
+                    .find(|t| matches!(t.kind, ReferencedTweetKind::RepliedTo))
+                    .map(|t| t.id.to_string())
+            }),
         }
     }
 }
@@ -56,8 +62,7 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TwitterClient<M,
         let api = TwitterApi::new(oauth1a_token);
 
         Self {
-            agent,
-            attention,
+            pipeline: MessagePipeline::new(agent, attention),
             api,
         }
     }
@@ -69,143 +74,92 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> TwitterClient<M,
         let api = TwitterApi::new(auth);
 
         Self {
-            agent,
-            attention,
+            pipeline: MessagePipeline::new(agent, attention),
             api,
         }
     }
 }
 
-impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization>
-    TwitterClient<M, E, A>
+impl<M, E, A> TwitterClient<M, E, A>
+where
+    M: CompletionModel + 'static,
+    E: EmbeddingModel + 'static,
+    A: Authorization + Send + Sync + 'static,
 {
-    pub async fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
+    /// Creates a client from a pipeline, e.g. one with middleware.
+    pub fn from_pipeline(pipeline: MessagePipeline<M, E>, auth: A) -> Self {
+        Self {
+            pipeline,
+            api: TwitterApi::new(auth),
+        }
+    }
+
+    /// Replaces the pipeline, e.g. with one that has middleware.
+    pub fn with_pipeline(mut self, pipeline: MessagePipeline<M, E>) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    pub async fn start(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         info!("Starting Twitter bot");
         self.listen_for_mentions().await
     }
 
-    async fn listen_for_mentions(&self) -> Result<(), Box<dyn std::error::Error>> {
+    async fn listen_for_mentions(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         let me = self.api.get_users_me().send().await?;
         let user_id = me.data.as_ref().unwrap().id;
+        let adapter = TwitterAdapter {
+            api: &self.api,
+            user_id,
+        };
+
+        // Only fetch mentions newer than the last one handled, so each mention
+        // is stored and answered once
+        let mut since_id: Option<NumericId> = None;
 
         // In a real implementation, you would use Twitter's streaming API
         // This is a simplified polling approach
         loop {
-            let mentions = self
-                .api
-                .get_user_mentions(user_id)
-                .max_results(5)
-                .send()
-                .await?;
-
-            for tweet in mentions.data.clone().unwrap_or_default() {
-                self.handle_mention(tweet).await?;
+            let mut request = self.api.get_user_mentions(user_id);
+            request.max_results(5).tweet_fields([
+                TweetField::AuthorId,
+                TweetField::ConversationId,
+                TweetField::CreatedAt,
+                TweetField::InReplyToUserId,
+                TweetField::ReferencedTweets,
+            ]);
+            if let Some(since_id) = since_id {
+                request.since_id(since_id);
+            }
+            let mentions = request.send().await?;
+
+            // Mentions are returned newest first, reply in the order they were sent
+            let mut tweets = mentions.data.clone().unwrap_or_default();
+            tweets.reverse();
+            for tweet in tweets {
+                since_id = Some(tweet.id);
+                if let Err(err) = self.pipeline.handle(&adapter, tweet).await {
+                    error!(%err, "Failed to handle mention");
+                }
             }
 
             tokio::time::sleep(tokio::time::Duration::from_secs(60)).await;
         }
     }
+}
 
-    async fn handle_mention(
-        &self,
-        tweet: twitter::Tweet,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let knowledge = self.agent.knowledge();
-        let knowledge_msg = Message::from(tweet.clone());
-
-        if let Err(err) = knowledge.create_message(knowledge_msg.clone()).await {
-            error!(?err, "Failed to store tweet");
-            return Ok(());
-        }
-
-        let thread = self.build_conversation_thread(&tweet).await?;
-
-        let mentioned_names: HashSet<String> = tweet
-            .text
-            .split_whitespace()
-            .filter(|word| word.starts_with('@'))
-            .map(|mention| mention[1..].to_string())
-            .collect();
-
-        debug!(
-            mentioned_names = ?mentioned_names,
-            "Mentioned names in tweet"
-        );
-
-        let history = thread
-            .iter()
-            .map(|t| (t.id.to_string(), t.text.clone()))
-            .collect();
-
-        let context = AttentionContext {
-            message_content: tweet.text.clone(),
-            mentioned_names,
-            history,
-            channel_type: knowledge_msg.channel_type,
-            source: knowledge_msg.source,
-        };
-
-        debug!(?context, "Attention context");
-
-        match self.attention.should_reply(&context).await {
-            AttentionCommand::Respond => {}
-            _ => {
-                debug!("Bot decided not to reply to tweet");
-                return Ok(());
-            }
-        }
-
-        let agent = self
-            .agent
-            .builder()
-            .context(&format!(
-                "Current time: {}",
-                chrono::Local::now().format("%I:%M:%S %p, %Y-%m-%d")
-            ))
-            .context("Please keep your responses concise and under 280 characters.")
-            .build();
-
-        let response = match agent.prompt(&tweet.text).await {
-            Ok(response) => response,
-            Err(err) => {
-                error!(?err, "Failed to generate response");
-                return Ok(());
-            }
-        };
-
-        debug!(response = %response, "Generated response");
-
-        // Split response into tweet-sized chunks if necessary
-        let chunks: Vec<String> = response
-            .chars()
-            .collect::<Vec<char>>()
-            .chunks(MAX_TWEET_LENGTH)
-            .map(|chunk| chunk.iter().collect::<String>())
-            .collect();
-
-        // Reply to the original tweet
-        for chunk in chunks {
-            if let Err(err) = self
-                .api
-                .post_tweet()
-                .in_reply_to_tweet_id(tweet.id)
-                .text(chunk)
-                .send()
-                .await
-            {
-                error!(?err, "Failed to send tweet");
-            }
-        }
-
-        Ok(())
-    }
+struct TwitterAdapter<'a, A> {
+    api: &'a TwitterApi<A>,
+    user_id: NumericId,
+}
 
+impl<'a, A: Authorization + Send + Sync + 'static> TwitterAdapter<'a, A> {
     async fn build_conversation_thread(
         &self,
-        tweet: &twitter::Tweet,
-    ) -> Result<Vec<twitter::Tweet>, Box<dyn std::error::Error>> {
+        tweet_id: NumericId,
+    ) -> Result<Vec<twitter::Tweet>, twitter::Error> {
         let mut thread = Vec::new();
-        let mut current_tweet = Some(tweet.clone());
+        let mut current_tweet = self.api.get_tweet(tweet_id).send().await?.data.clone();
         let mut depth = 0;
 
         while let Some(tweet) = current_tweet {
@@ -243,3 +197,101 @@ impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static, A: Authorization
         Ok(thread)
     }
 }
+
+#[async_trait]
+impl<'a, A: Authorization + Send + Sync + 'static> PlatformAdapter for TwitterAdapter<'a, A> {
+    type Event = twitter::Tweet;
+
+    fn normalize(&self, tweet: &twitter::Tweet) -> Option<InboundMessage> {
+        if tweet.author_id == Some(self.user_id) {
+            return None;
+        }
+
+        let mentioned_names: HashSet<String> = tweet
+            .text
+            .split_whitespace()
+            .filter_map(|word| word.strip_prefix('@'))
+            .map(|name| name.to_string())
+            .collect();
+
+        Some(InboundMessage {
+            message: Message::from(tweet.clone()),
+            author: None,
+            channel_name: None,
+            context: None,
+            mentioned_names,
+            // Replies in a thread mention everyone in it, so a mention alone
+            // doesn't mean the tweet is addressed to the bot
+            mentioned_ids: HashSet::new(),
+            is_reply_to_bot: tweet.in_reply_to_user_id == Some(self.user_id),
+        })
+    }
+
+    fn bot_id(&self) -> Option<String> {
+        Some(self.user_id.to_string())
+    }
+
+    fn max_message_length(&self) -> usize {
+        MAX_TWEET_LENGTH
+    }
+
+    /// The reply chain the mention is part of, since replies by others in
+    /// the conversation aren't stored.
+    async fn history(&self, inbound: &InboundMessage) -> Option<Vec<ConversationMessage>> {
+        let Some(reply_to) = &inbound.message.reply_to else {
+            return Some(Vec::new());
+        };
+
+        let thread = match self.build_conversation_thread(reply_to.parse().ok()?).await {
+            Ok(thread) => thread,
+            Err(err) => {
+                error!(?err, "Failed to fetch conversation thread");
+                return None;
+            }
+        };
+
+        Some(
+            thread
+                .into_iter()
+                .map(|tweet| {
+                    let is_own = tweet.author_id == Some(self.user_id);
+                    let mut message = Message::from(tweet);
+                    if is_own {
+                        message.role = Role::Assistant;
+                    }
+                    ConversationMessage {
+                        author: None,
+                        message,
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    async fn send(&self, inbound: &InboundMessage, text: &str) -> Result<Message, AdapterError> {
+        let posted = self
+            .api
+            .post_tweet()
+            .in_reply_to_tweet_id(inbound.message.id.parse::<NumericId>()?)
+            .text(text.to_string())
+            .send()
+            .await?;
+        let posted = posted
+            .data
+            .clone()
+            .ok_or("Posted tweet is missing from the response")?;
+
+        Ok(Message {
+            id: posted.id.to_string(),
+            source: Source::Twitter,
+            source_id: posted.id.to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: inbound.message.channel_id.clone(),
+            account_id: self.user_id.to_string(),
+            role: Role::Assistant,
+            content: text.to_string(),
+            created_at: Some(chrono::Utc::now()),
+            reply_to: Some(inbound.message.id.clone()),
+        })
+    }
+}
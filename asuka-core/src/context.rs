@@ -0,0 +1,283 @@
+use std::collections::HashMap;
+use tracing::{debug, info, warn};
+
+/// Rough number of characters per token, good enough to stay within budget
+/// without depending on a model specific tokenizer.
+const CHARS_PER_TOKEN: usize = 4;
+
+/// Truncated items shorter than this are dropped instead.
+const MIN_TRUNCATED_TOKENS: usize = 64;
+
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Token budget for the context sent with a prompt.
+#[derive(Clone, Debug)]
+pub struct ContextBudget {
+    /// Context window of the completion model.
+    pub max_tokens: usize,
+    /// Tokens kept free for the response.
+    pub response_tokens: usize,
+    /// Number of documents retrieved from the knowledge base.
+    pub documents: usize,
+}
+
+impl Default for ContextBudget {
+    fn default() -> Self {
+        Self {
+            max_tokens: 16_000,
+            response_tokens: 1_024,
+            documents: 2,
+        }
+    }
+}
+
+impl ContextBudget {
+    pub fn available(&self) -> usize {
+        self.max_tokens.saturating_sub(self.response_tokens)
+    }
+}
+
+/// Sections are filled from the highest priority down; lower priorities get
+/// whatever budget is left.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Examples,
+    Documents,
+    History,
+    Persona,
+}
+
+pub struct Section {
+    pub name: &'static str,
+    pub priority: Priority,
+    /// Items in order of preference. An item that doesn't fit is dropped and
+    /// the following ones are still tried, so one long item doesn't take the
+    /// shorter ones after it along.
+    pub items: Vec<String>,
+    /// Whether the first item that doesn't fit may be cut to the remaining budget
+    /// instead of being dropped.
+    pub truncate: bool,
+}
+
+#[derive(Debug)]
+pub struct Dropped {
+    pub section: &'static str,
+    pub index: usize,
+    pub tokens: usize,
+    pub truncated: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct AssembledContext {
+    sections: HashMap<&'static str, Vec<(usize, String)>>,
+    pub used_tokens: usize,
+    pub dropped: Vec<Dropped>,
+}
+
+impl AssembledContext {
+    /// Kept items of a section with their original index, in the order given.
+    pub fn take(&mut self, section: &str) -> Vec<(usize, String)> {
+        self.sections.remove(section).unwrap_or_default()
+    }
+}
+
+/// Fits prioritized context sections into a token budget.
+///
+/// Items are kept whole, truncated or dropped; nothing is summarized. The
+/// history is passed newest first, so it loses its oldest messages first, and
+/// a message larger than the remaining budget is skipped while the older
+/// messages that still fit are kept.
+pub struct ContextAssembler {
+    budget: usize,
+    sections: Vec<Section>,
+}
+
+impl ContextAssembler {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            budget,
+            sections: Vec::new(),
+        }
+    }
+
+    pub fn section(mut self, section: Section) -> Self {
+        self.sections.push(section);
+        self
+    }
+
+    pub fn assemble(mut self) -> AssembledContext {
+        self.sections.sort_by(|a, b| b.priority.cmp(&a.priority));
+
+        let mut assembled = AssembledContext::default();
+        for section in self.sections {
+            let mut kept = Vec::new();
+            let mut truncated = false;
+
+            for (index, item) in section.items.into_iter().enumerate() {
+                let tokens = estimate_tokens(&item);
+                let remaining = self.budget.saturating_sub(assembled.used_tokens);
+
+                // Persona is never dropped, even when it alone exceeds the budget
+                if section.priority == Priority::Persona || tokens <= remaining {
+                    assembled.used_tokens += tokens;
+                    kept.push((index, item));
+                    continue;
+                }
+
+                if !truncated && section.truncate && remaining >= MIN_TRUNCATED_TOKENS {
+                    truncated = true;
+                    let truncated: String =
+                        item.chars().take(remaining * CHARS_PER_TOKEN).collect();
+                    assembled.used_tokens += estimate_tokens(&truncated);
+                    kept.push((index, truncated));
+                    assembled.dropped.push(Dropped {
+                        section: section.name,
+                        index,
+                        tokens: tokens - remaining,
+                        truncated: true,
+                    });
+                } else {
+                    assembled.dropped.push(Dropped {
+                        section: section.name,
+                        index,
+                        tokens,
+                        truncated: false,
+                    });
+                }
+            }
+
+            assembled.sections.insert(section.name, kept);
+        }
+
+        if assembled.used_tokens > self.budget {
+            warn!(
+                used_tokens = assembled.used_tokens,
+                budget = self.budget,
+                "Persona alone exceeds the context budget"
+            );
+        }
+
+        if assembled.dropped.is_empty() {
+            debug!(
+                used_tokens = assembled.used_tokens,
+                budget = self.budget,
+                "Assembled context"
+            );
+        } else {
+            info!(
+                used_tokens = assembled.used_tokens,
+                budget = self.budget,
+                dropped = ?assembled.dropped,
+                "Context trimmed to fit budget"
+            );
+        }
+
+        assembled
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn section(name: &'static str, priority: Priority, items: &[&str], truncate: bool) -> Section {
+        Section {
+            name,
+            priority,
+            items: items.iter().map(|item| item.to_string()).collect(),
+            truncate,
+        }
+    }
+
+    #[test]
+    fn test_keeps_everything_within_budget() {
+        let mut context = ContextAssembler::new(100)
+            .section(section(
+                "persona",
+                Priority::Persona,
+                &["a".repeat(40).as_str()],
+                false,
+            ))
+            .section(section(
+                "history",
+                Priority::History,
+                &["b".repeat(40).as_str()],
+                false,
+            ))
+            .assemble();
+
+        assert_eq!(context.used_tokens, 20);
+        assert!(context.dropped.is_empty());
+        assert_eq!(context.take("history").len(), 1);
+    }
+
+    #[test]
+    fn test_drops_lower_priorities_first() {
+        let item = "x".repeat(400); // 100 tokens
+        let mut context = ContextAssembler::new(250)
+            .section(section("examples", Priority::Examples, &[&item], false))
+            .section(section(
+                "history",
+                Priority::History,
+                &[&item, &item, &item],
+                false,
+            ))
+            .section(section("persona", Priority::Persona, &[&item], false))
+            .assemble();
+
+        assert_eq!(context.take("persona").len(), 1);
+        let history = context.take("history");
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].0, 0);
+        assert!(context.take("examples").is_empty());
+        assert_eq!(context.dropped.len(), 3);
+    }
+
+    #[test]
+    fn test_skips_items_that_dont_fit() {
+        let short = "x".repeat(40); // 10 tokens
+        let long = "x".repeat(400); // 100 tokens
+        let mut context = ContextAssembler::new(80)
+            .section(section("persona", Priority::Persona, &[&short], false))
+            .section(section(
+                "history",
+                Priority::History,
+                &[&short, &long, &short, &short],
+                false,
+            ))
+            .assemble();
+
+        let history: Vec<_> = context
+            .take("history")
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(history, vec![0, 2, 3]);
+        assert_eq!(context.dropped.len(), 1);
+        assert_eq!(context.dropped[0].index, 1);
+        assert_eq!(context.used_tokens, 40);
+    }
+
+    #[test]
+    fn test_truncates_documents() {
+        let item = "x".repeat(800); // 200 tokens
+        let mut context = ContextAssembler::new(300)
+            .section(section("persona", Priority::Persona, &[&item], false))
+            .section(section(
+                "documents",
+                Priority::Documents,
+                &[&item, &item],
+                true,
+            ))
+            .assemble();
+
+        let documents = context.take("documents");
+        assert_eq!(documents.len(), 1);
+        assert_eq!(estimate_tokens(&documents[0].1), 100);
+        assert!(context.dropped[0].truncated);
+        assert!(!context.dropped[1].truncated);
+        assert_eq!(context.used_tokens, 300);
+    }
+}
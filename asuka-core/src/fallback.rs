@@ -0,0 +1,318 @@
+use futures::future::BoxFuture;
+use rig::completion::{
+    CompletionError, CompletionModel, CompletionRequest, CompletionResponse, ModelChoice,
+};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, warn};
+
+/// How a failed completion is handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The provider is rate limiting us. Retried after a backoff.
+    RateLimited,
+    /// The provider is overloaded, unreachable or timed out. Retried after a backoff.
+    Transient,
+    /// The request itself was rejected. Retrying or using another provider
+    /// won't help.
+    InvalidRequest,
+    /// Anything else, such as authentication errors or responses that couldn't
+    /// be parsed. Not retried, but the next model is tried.
+    Provider,
+}
+
+impl ErrorKind {
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ErrorKind::RateLimited | ErrorKind::Transient)
+    }
+
+    pub fn can_fall_back(&self) -> bool {
+        *self != ErrorKind::InvalidRequest
+    }
+}
+
+/// Classifies a completion error. Providers report most errors as a message,
+/// so this matches on the error types used by the Anthropic and OpenAI APIs.
+pub fn classify(err: &CompletionError) -> ErrorKind {
+    match err {
+        CompletionError::HttpError(err) => match err.status().map(|status| status.as_u16()) {
+            Some(429) => ErrorKind::RateLimited,
+            Some(400 | 404 | 413 | 422) => ErrorKind::InvalidRequest,
+            Some(401 | 403) => ErrorKind::Provider,
+            _ => ErrorKind::Transient,
+        },
+        CompletionError::ProviderError(message) => {
+            let message = message.to_lowercase();
+            if message.contains("rate_limit") || message.contains("rate limit") {
+                ErrorKind::RateLimited
+            } else if message.contains("overloaded")
+                || message.contains("api_error")
+                || message.contains("server_error")
+                || message.contains("timeout")
+                || message.contains("timed out")
+            {
+                ErrorKind::Transient
+            } else if message.contains("invalid_request") {
+                ErrorKind::InvalidRequest
+            } else {
+                ErrorKind::Provider
+            }
+        }
+        _ => ErrorKind::Provider,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Retries per model after the first attempt.
+    pub max_retries: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Time after which an attempt is abandoned and counted as transient.
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(8),
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff before the given retry, starting at 1.
+    pub fn backoff(&self, retry: usize) -> Duration {
+        exponential_backoff(self.initial_backoff, self.max_backoff, retry)
+    }
+}
+
+/// Doubles `initial` for every attempt after the first, up to `max`. Attempts
+/// start at 1.
+pub fn exponential_backoff(initial: Duration, max: Duration, attempt: usize) -> Duration {
+    let factor = 2u32.saturating_pow(attempt.saturating_sub(1) as u32);
+    initial.saturating_mul(factor).min(max)
+}
+
+/// A failed attempt, reported in tracing when a completion falls back or fails.
+#[derive(Debug)]
+pub struct Attempt {
+    pub model: String,
+    pub retry: usize,
+    pub kind: ErrorKind,
+    pub error: String,
+    pub elapsed: Duration,
+}
+
+/// Completion model that tries an ordered list of models, retrying each one
+/// according to a [`RetryPolicy`] before falling back to the next.
+///
+/// Models of different providers can be mixed, which makes it possible to use
+/// one as a fallback for the other with any client or [`crate::agent::Agent`].
+#[derive(Clone)]
+pub struct FallbackModel {
+    models: Arc<Vec<(String, Box<dyn DynCompletionModel>)>>,
+    policy: RetryPolicy,
+}
+
+impl FallbackModel {
+    pub fn builder() -> FallbackModelBuilder {
+        FallbackModelBuilder::default()
+    }
+}
+
+#[derive(Default)]
+pub struct FallbackModelBuilder {
+    models: Vec<(String, Box<dyn DynCompletionModel>)>,
+    policy: RetryPolicy,
+}
+
+impl FallbackModelBuilder {
+    /// Adds a model, tried after the ones added before it. The name is only
+    /// used in logs.
+    pub fn model(mut self, name: &str, model: impl CompletionModel + 'static) -> Self {
+        self.models.push((name.to_string(), Box::new(model)));
+        self
+    }
+
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn build(self) -> FallbackModel {
+        FallbackModel {
+            models: Arc::new(self.models),
+            policy: self.policy,
+        }
+    }
+}
+
+impl CompletionModel for FallbackModel {
+    type Response = ();
+
+    async fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> Result<CompletionResponse<Self::Response>, CompletionError> {
+        let mut attempts: Vec<Attempt> = Vec::new();
+        let mut last_error = None;
+
+        'models: for (name, model) in self.models.iter() {
+            for retry in 0..=self.policy.max_retries {
+                if retry > 0 {
+                    let backoff = self.policy.backoff(retry);
+                    debug!(model = name, retry, ?backoff, "Retrying completion");
+                    tokio::time::sleep(backoff).await;
+                }
+
+                let started = Instant::now();
+                let result = tokio::time::timeout(
+                    self.policy.timeout,
+                    model.completion(clone_request(&request)),
+                )
+                .await;
+
+                let (kind, err) = match result {
+                    Ok(Ok(choice)) => {
+                        if !attempts.is_empty() {
+                            warn!(
+                                model = name,
+                                ?attempts,
+                                "Completion succeeded after failed attempts"
+                            );
+                        }
+                        return Ok(CompletionResponse {
+                            choice,
+                            raw_response: (),
+                        });
+                    }
+                    Ok(Err(err)) => (classify(&err), err),
+                    Err(_) => (
+                        ErrorKind::Transient,
+                        CompletionError::ProviderError(format!(
+                            "Request timed out after {:?}",
+                            self.policy.timeout
+                        )),
+                    ),
+                };
+
+                warn!(model = name, retry, ?kind, %err, "Completion failed");
+                attempts.push(Attempt {
+                    model: name.clone(),
+                    retry,
+                    kind,
+                    error: err.to_string(),
+                    elapsed: started.elapsed(),
+                });
+                last_error = Some(err);
+
+                if !kind.can_fall_back() {
+                    break 'models;
+                }
+                if !kind.is_retryable() {
+                    break;
+                }
+            }
+        }
+
+        error!(?attempts, "All completion attempts failed");
+        Err(last_error
+            .unwrap_or_else(|| CompletionError::ProviderError("No models configured".to_string())))
+    }
+}
+
+/// `CompletionRequest` isn't `Clone`, but each attempt needs its own copy.
+fn clone_request(request: &CompletionRequest) -> CompletionRequest {
+    CompletionRequest {
+        prompt: request.prompt.clone(),
+        preamble: request.preamble.clone(),
+        chat_history: request.chat_history.clone(),
+        documents: request.documents.clone(),
+        tools: request.tools.clone(),
+        temperature: request.temperature,
+        max_tokens: request.max_tokens,
+        additional_params: request.additional_params.clone(),
+    }
+}
+
+/// Object safe counterpart of [`CompletionModel`] so models of different
+/// providers can be stored together.
+trait DynCompletionModel: Send + Sync {
+    fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> BoxFuture<'_, Result<ModelChoice, CompletionError>>;
+}
+
+impl<M: CompletionModel> DynCompletionModel for M {
+    fn completion(
+        &self,
+        request: CompletionRequest,
+    ) -> BoxFuture<'_, Result<ModelChoice, CompletionError>> {
+        Box::pin(async move {
+            CompletionModel::completion(self, request)
+                .await
+                .map(|response| response.choice)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestModel;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_after_retries() {
+        let primary = TestModel::failing("overloaded_error: Overloaded");
+        let secondary = TestModel::new("ok");
+        let model = FallbackModel::builder()
+            .model("primary", primary.clone())
+            .model("secondary", secondary.clone())
+            .retry_policy(policy())
+            .build();
+
+        let response = model.completion_request("hi").send().await.unwrap();
+        assert!(matches!(response.choice, ModelChoice::Message(text) if text == "ok"));
+        assert_eq!(primary.calls(), 3);
+        assert_eq!(secondary.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_request_is_not_retried() {
+        let primary = TestModel::failing("invalid_request_error: prompt is too long");
+        let secondary = TestModel::new("ok");
+        let model = FallbackModel::builder()
+            .model("primary", primary.clone())
+            .model("secondary", secondary.clone())
+            .retry_policy(policy())
+            .build();
+
+        assert!(model.completion_request("hi").send().await.is_err());
+        assert_eq!(primary.calls(), 1);
+        assert_eq!(secondary.calls(), 0);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_millis(500));
+        assert_eq!(policy.backoff(2), Duration::from_secs(1));
+        assert_eq!(policy.backoff(10), Duration::from_secs(8));
+    }
+}
@@ -3,7 +3,7 @@ mod store;
 mod models;
 mod error;
 
-pub use types::{Source, ChannelType, MessageMetadata, MessageContent};
+pub use types::{Source, ChannelType, MessageMetadata, MessageContent, Role};
 pub use store::KnowledgeBase;
-pub use models::{Document, Message, Account, Channel, Conversation};
+pub use models::{Document, Message, Account, Channel, Conversation, ConversationMessage, Mute};
 pub use error::ConversionError;
\ No newline at end of file
@@ -1,6 +1,6 @@
 use std::str::FromStr;
 
-use super::types::{ChannelType, Source};
+use super::types::{ChannelType, Role, Source};
 use chrono::{DateTime, NaiveDateTime, Utc};
 use rig::Embed;
 use rig_sqlite::{Column, ColumnValue, SqliteVectorStoreTable};
@@ -45,11 +45,32 @@ pub struct Message {
     pub channel_type: ChannelType,
     pub channel_id: String,
     pub account_id: String,
-    pub role: String,
+    pub role: Role,
     #[embed]
     pub content: String,
     #[serde(deserialize_with = "deserialize_datetime")]
     pub created_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Id of the message this one replies to, if any.
+    #[serde(default)]
+    pub reply_to: Option<String>,
+}
+
+/// A stored message along with its author's display name, if known.
+#[derive(Clone, Debug)]
+pub struct ConversationMessage {
+    pub author: Option<String>,
+    pub message: Message,
+}
+
+/// The bot was told to stop talking in a channel, or to one user in it, until
+/// `muted_until`.
+#[derive(Clone, Debug)]
+pub struct Mute {
+    pub source: Source,
+    pub channel_id: String,
+    /// The muted user, or `None` when the whole channel is muted.
+    pub user_id: Option<String>,
+    pub muted_until: DateTime<Utc>,
 }
 
 fn deserialize_datetime<'de, D>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error>
@@ -129,6 +150,7 @@ impl SqliteVectorStoreTable for Message {
             Column::new("role", "TEXT"),
             Column::new("content", "TEXT"),
             Column::new("created_at", "TIMESTAMP DEFAULT CURRENT_TIMESTAMP"),
+            Column::new("reply_to", "TEXT").indexed(),
         ]
     }
 
@@ -147,8 +169,12 @@ impl SqliteVectorStoreTable for Message {
             ),
             ("channel_id", Box::new(self.channel_id.clone())),
             ("account_id", Box::new(self.account_id.clone())),
-            ("role", Box::new(self.role.clone())),
+            ("role", Box::new(self.role.as_str().to_string())),
             ("content", Box::new(self.content.clone())),
+            (
+                "reply_to",
+                Box::new(self.reply_to.clone().unwrap_or_default()),
+            ),
         ]
     }
 }
@@ -233,9 +259,37 @@ impl TryFrom<&Row<'_>> for Message {
             })?,
             channel_id: row.get(4)?,
             account_id: row.get(5)?,
-            role: row.get(6)?,
+            role: Role::from_str(&row.get::<_, String>(6)?).map_err(|_| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    6,
+                    rusqlite::types::Type::Text,
+                    Box::new(super::error::ConversionError("Invalid role".to_string())),
+                )
+            })?,
             content: row.get(7)?,
             created_at: row.get(8)?,
+            reply_to: row
+                .get::<_, Option<String>>(9)?
+                .filter(|reply_to| !reply_to.is_empty()),
+        })
+    }
+}
+
+impl TryFrom<&Row<'_>> for Mute {
+    type Error = rusqlite::Error;
+
+    fn try_from(row: &Row) -> Result<Self, Self::Error> {
+        Ok(Mute {
+            source: Source::from_str(&row.get::<_, String>(0)?).map_err(|_| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    0,
+                    rusqlite::types::Type::Text,
+                    Box::new(super::error::ConversionError("Invalid source".to_string())),
+                )
+            })?,
+            channel_id: row.get(1)?,
+            user_id: Some(row.get::<_, String>(2)?).filter(|user_id| !user_id.is_empty()),
+            muted_until: row.get(3)?,
         })
     }
 }
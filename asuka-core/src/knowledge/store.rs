@@ -1,3 +1,4 @@
+use chrono::{DateTime, Utc};
 use rig::{
     embeddings::{EmbeddingModel, EmbeddingsBuilder},
     vector_store::VectorStoreError,
@@ -5,7 +6,8 @@ use rig::{
 use tokio_rusqlite::Connection;
 use tracing::{debug, info};
 
-use super::models::{Account, Channel, Document, Message};
+use super::models::{Account, Channel, ConversationMessage, Document, Message, Mute};
+use super::types::Source;
 use rig_sqlite::{SqliteError, SqliteVectorIndex, SqliteVectorStore};
 use rusqlite::OptionalExtension;
 
@@ -19,6 +21,8 @@ pub struct KnowledgeBase<E: EmbeddingModel + Clone + 'static> {
 
 impl<E: EmbeddingModel> KnowledgeBase<E> {
     pub async fn new(conn: Connection, embedding_model: E) -> Result<Self, VectorStoreError> {
+        Self::migrate(&conn).await?;
+
         let document_store = SqliteVectorStore::new(conn.clone(), &embedding_model).await?;
         let message_store = SqliteVectorStore::new(conn.clone(), &embedding_model).await?;
 
@@ -49,6 +53,18 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
                 );
                 CREATE INDEX IF NOT EXISTS idx_channel_id_type ON channels(channel_id, channel_type);
 
+                -- Channels, or users within a channel, the bot was told to stop
+                -- talking to. An empty user_id mutes the whole channel. Channel
+                -- ids are only unique within a platform.
+                CREATE TABLE IF NOT EXISTS mutes (
+                    source TEXT NOT NULL,
+                    channel_id TEXT NOT NULL,
+                    user_id TEXT NOT NULL DEFAULT '',
+                    muted_until TIMESTAMP NOT NULL,
+                    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                    PRIMARY KEY (source, channel_id, user_id)
+                );
+
                 COMMIT;"
             )
             .map_err(tokio_rusqlite::Error::from)
@@ -64,6 +80,41 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
         })
     }
 
+    /// Adds the columns introduced after a database was created. The vector
+    /// store only creates missing tables, so this has to run before it indexes
+    /// the new columns.
+    async fn migrate(conn: &Connection) -> Result<(), VectorStoreError> {
+        conn.call(|conn| {
+            let columns = conn
+                .prepare("SELECT name FROM pragma_table_info('messages')")?
+                .query_map([], |row| row.get::<_, String>(0))?
+                .collect::<Result<Vec<_>, _>>()?;
+            // A new database gets the current schema from the vector store
+            if columns.is_empty() {
+                return Ok(());
+            }
+
+            if !columns.iter().any(|column| column == "role") {
+                info!("Adding role column to messages");
+                conn.execute(
+                    "ALTER TABLE messages ADD COLUMN role TEXT NOT NULL DEFAULT 'user'",
+                    [],
+                )?;
+            }
+            if !columns.iter().any(|column| column == "reply_to") {
+                info!("Adding reply_to column to messages");
+                conn.execute_batch(
+                    "ALTER TABLE messages ADD COLUMN reply_to TEXT;
+                     CREATE INDEX IF NOT EXISTS idx_messages_reply_to ON messages(reply_to);",
+                )?;
+            }
+
+            Ok(())
+        })
+        .await
+        .map_err(|e| VectorStoreError::DatastoreError(Box::new(e)))
+    }
+
     pub async fn create_user(
         &self,
         name: String,
@@ -76,6 +127,7 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
                     "INSERT INTO accounts (name, source, created_at, updated_at, source_id)
                  VALUES (?1, ?2, CURRENT_TIMESTAMP, CURRENT_TIMESTAMP, ?3)
                  ON CONFLICT(source_id) DO UPDATE SET 
+                     name = ?1,
                      updated_at = CURRENT_TIMESTAMP
                  RETURNING id",
                     rusqlite::params![name, source, source_id],
@@ -217,8 +269,8 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
         self.conn
             .call(move |conn| {
                 conn.execute(
-                    "INSERT INTO messages (id, source, source_id, channel_type, channel_id, account_id, content, role, created_at) 
-                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, CURRENT_TIMESTAMP)
+                    "INSERT INTO messages (id, source, source_id, channel_type, channel_id, account_id, content, role, reply_to, created_at) 
+                        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, CURRENT_TIMESTAMP)
                         ON CONFLICT (id) DO UPDATE SET 
                             content = ?7",
                     rusqlite::params![
@@ -229,7 +281,8 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
                         msg.channel_id,
                         msg.account_id,
                         msg.content,
-                        msg.role
+                        msg.role.as_str(),
+                        msg.reply_to
                     ],
                 )
                 .map_err(tokio_rusqlite::Error::from)
@@ -252,20 +305,22 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
                 let tx = conn.transaction()?;
 
                 tx.execute(
-                    "INSERT INTO messages (id, channel_id, account_id, content, role, created_at) 
-                 VALUES (?1, ?2, ?3, ?4, ?5, CURRENT_TIMESTAMP)
+                    "INSERT INTO messages (id, channel_id, account_id, content, role, reply_to, created_at) 
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, CURRENT_TIMESTAMP)
                  ON CONFLICT (id) DO UPDATE SET 
                      channel_id = ?2, 
                      account_id = ?3, 
                      content = ?4, 
                      role = ?5,
+                     reply_to = ?6,
                      created_at = CURRENT_TIMESTAMP",
-                    [
-                        &msg.id,
-                        &msg.channel_id,
-                        &msg.account_id,
-                        &msg.content,
-                        &msg.role,
+                    rusqlite::params![
+                        msg.id,
+                        msg.channel_id,
+                        msg.account_id,
+                        msg.content,
+                        msg.role.as_str(),
+                        msg.reply_to,
                     ],
                 )?;
 
@@ -282,7 +337,7 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
     pub async fn get_message(&self, id: i64) -> Result<Option<Message>, SqliteError> {
         self.conn
             .call(move |conn| {
-                Ok(conn.prepare("SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at FROM messages WHERE id = ?1")?
+                Ok(conn.prepare("SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at, reply_to FROM messages WHERE id = ?1")?
                     .query_row(rusqlite::params![id], |row| {
                         Message::try_from(row)
                     }).optional()?)
@@ -299,7 +354,7 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
         self.conn
             .call(move |conn| {
                 let mut stmt = conn.prepare(
-                    "SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at 
+                    "SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at, reply_to 
                      FROM messages 
                      WHERE channel_id = ?1 
                      ORDER BY created_at DESC 
@@ -322,7 +377,7 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
         self.conn
             .call(move |conn| {
                 let mut stmt = conn.prepare(
-                    "SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at 
+                    "SELECT id, source, source_id, channel_type, channel_id, account_id, role, content, created_at, reply_to 
                      FROM messages 
                      ORDER BY created_at DESC 
                      LIMIT ?1",
@@ -367,6 +422,127 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
             .map_err(|e| anyhow::anyhow!(e))
     }
 
+    /// Returns the most recent messages of a channel, oldest first, with the
+    /// author names recorded in `accounts`. Messages stored within the same
+    /// second keep the order they were stored in.
+    pub async fn channel_history(
+        &self,
+        source: &Source,
+        channel_id: &str,
+        limit: usize,
+    ) -> Result<Vec<ConversationMessage>, SqliteError> {
+        let source = source.as_str();
+        let channel_id = channel_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT m.id, m.source, m.source_id, m.channel_type, m.channel_id, m.account_id, m.role, m.content, m.created_at, m.reply_to, a.name
+                     FROM messages m
+                     LEFT JOIN accounts a ON a.source_id = m.account_id
+                     WHERE m.channel_id = ?1 AND m.source = ?2
+                     ORDER BY m.created_at DESC, m.rowid DESC
+                     LIMIT ?3",
+                )?;
+
+                let mut messages = stmt
+                    .query_map(rusqlite::params![channel_id, source, limit], |row| {
+                        Ok(ConversationMessage {
+                            author: row.get(10)?,
+                            message: Message::try_from(row)?,
+                        })
+                    })?
+                    .collect::<Result<Vec<_>, _>>()?;
+                messages.reverse();
+
+                Ok(messages)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
+    /// Mutes the bot in a channel until `muted_until`, or only towards one user
+    /// when `user_id` is given. Replaces an earlier mute of the same scope.
+    pub async fn mute(
+        &self,
+        source: Source,
+        channel_id: String,
+        user_id: Option<String>,
+        muted_until: DateTime<Utc>,
+    ) -> Result<(), SqliteError> {
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO mutes (source, channel_id, user_id, muted_until, created_at)
+                     VALUES (?1, ?2, ?3, ?4, CURRENT_TIMESTAMP)
+                     ON CONFLICT (source, channel_id, user_id) DO UPDATE SET
+                         muted_until = ?4,
+                         created_at = CURRENT_TIMESTAMP",
+                    rusqlite::params![
+                        source.as_str(),
+                        channel_id,
+                        user_id.unwrap_or_default(),
+                        muted_until
+                    ],
+                )
+                .map_err(tokio_rusqlite::Error::from)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+        Ok(())
+    }
+
+    /// Lifts the mute of a channel, or of one user in it when `user_id` is given.
+    pub async fn unmute(
+        &self,
+        source: Source,
+        channel_id: String,
+        user_id: Option<String>,
+    ) -> Result<(), SqliteError> {
+        self.conn
+            .call(move |conn| {
+                conn.execute(
+                    "DELETE FROM mutes WHERE source = ?1 AND channel_id = ?2 AND user_id = ?3",
+                    rusqlite::params![source.as_str(), channel_id, user_id.unwrap_or_default()],
+                )
+                .map_err(tokio_rusqlite::Error::from)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))?;
+        Ok(())
+    }
+
+    /// Returns the mutes that keep the bot from replying to `user_id` in a
+    /// channel: the channel's own and the user's, unless they have expired.
+    pub async fn active_mutes(
+        &self,
+        source: Source,
+        channel_id: &str,
+        user_id: &str,
+    ) -> Result<Vec<Mute>, SqliteError> {
+        let channel_id = channel_id.to_string();
+        let user_id = user_id.to_string();
+
+        self.conn
+            .call(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT source, channel_id, user_id, muted_until
+                     FROM mutes
+                     WHERE source = ?1 AND channel_id = ?2 AND user_id IN ('', ?3)
+                         AND muted_until > ?4",
+                )?;
+                let mutes = stmt
+                    .query_map(
+                        rusqlite::params![source.as_str(), channel_id, user_id, Utc::now()],
+                        |row| Mute::try_from(row),
+                    )?
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(mutes)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+
     pub async fn add_message_embeddings(&self, msg: Message) -> anyhow::Result<()> {
         let embeddings = EmbeddingsBuilder::new(self.embedding_model.clone())
             .documents(vec![msg.clone()])?
@@ -394,4 +570,212 @@ impl<E: EmbeddingModel> KnowledgeBase<E> {
         info!("Successfully added documents to KnowledgeBase");
         Ok(())
     }
+
+    /// Waits for every write queued before the call to complete, then
+    /// checkpoints the write-ahead log into the database file if the database
+    /// uses one. The connection runs calls in order on its own thread, so
+    /// writes started by tasks that have since been cancelled still finish.
+    pub async fn flush(&self) -> Result<(), SqliteError> {
+        self.conn
+            .call(|conn| {
+                conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+                    .map_err(tokio_rusqlite::Error::from)
+            })
+            .await
+            .map_err(|e| SqliteError::DatabaseError(Box::new(e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        knowledge::{ChannelType, Role},
+        testing::{connection, knowledge_base, TestEmbeddingModel},
+    };
+
+    fn message(id: &str, role: Role, reply_to: Option<&str>) -> Message {
+        Message {
+            id: id.to_string(),
+            source: Source::Discord,
+            source_id: "alice".to_string(),
+            channel_type: ChannelType::Text,
+            channel_id: "general".to_string(),
+            account_id: "alice".to_string(),
+            role,
+            content: format!("message {id}"),
+            created_at: None,
+            reply_to: reply_to.map(|reply_to| reply_to.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reply_round_trip() {
+        let knowledge = knowledge_base().await;
+        knowledge
+            .create_message(message("1", Role::User, None))
+            .await
+            .unwrap();
+        knowledge
+            .create_message(message("2", Role::Assistant, Some("1")))
+            .await
+            .unwrap();
+
+        let history = knowledge
+            .channel_history(&Source::Discord, "general", 10)
+            .await
+            .unwrap();
+        let history: Vec<_> = history
+            .into_iter()
+            .map(|entry| (entry.message.id, entry.message.role, entry.message.reply_to))
+            .collect();
+        assert_eq!(
+            history,
+            vec![
+                ("1".to_string(), Role::User, None),
+                ("2".to_string(), Role::Assistant, Some("1".to_string())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_channel_history_is_per_source() {
+        let knowledge = knowledge_base().await;
+        knowledge
+            .create_message(message("1", Role::User, None))
+            .await
+            .unwrap();
+        knowledge
+            .create_message(Message {
+                source: Source::Slack,
+                ..message("2", Role::User, None)
+            })
+            .await
+            .unwrap();
+
+        let history = knowledge
+            .channel_history(&Source::Slack, "general", 10)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].message.id, "2");
+    }
+
+    #[tokio::test]
+    async fn test_migrates_messages_table() {
+        let conn = connection().await;
+        conn.call(|conn| {
+            conn.execute_batch(
+                "CREATE TABLE messages (
+                    id TEXT PRIMARY KEY,
+                    source TEXT,
+                    source_id TEXT,
+                    channel_type TEXT,
+                    channel_id TEXT,
+                    account_id TEXT,
+                    content TEXT,
+                    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+                );
+                INSERT INTO messages (id, source, source_id, channel_type, channel_id, account_id, content)
+                VALUES ('1', 'discord', 'alice', 'text', 'general', 'alice', 'hi');",
+            )
+            .map_err(tokio_rusqlite::Error::from)
+        })
+        .await
+        .unwrap();
+
+        let knowledge = KnowledgeBase::new(conn, TestEmbeddingModel).await.unwrap();
+        let messages = knowledge
+            .get_recent_messages_in_channel("general".to_string(), 10)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].role, Role::User);
+        assert_eq!(messages[0].reply_to, None);
+
+        knowledge
+            .create_message(message("2", Role::Assistant, Some("1")))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mutes() {
+        let knowledge = knowledge_base().await;
+        let muted_until = Utc::now() + chrono::Duration::hours(1);
+        knowledge
+            .mute(Source::Discord, "general".to_string(), None, muted_until)
+            .await
+            .unwrap();
+
+        let mutes = knowledge
+            .active_mutes(Source::Discord, "general", "alice")
+            .await
+            .unwrap();
+        assert_eq!(mutes.len(), 1);
+        assert_eq!(mutes[0].source, Source::Discord);
+        assert_eq!(mutes[0].channel_id, "general");
+        assert_eq!(mutes[0].user_id, None);
+        assert!(knowledge
+            .active_mutes(Source::Telegram, "general", "alice")
+            .await
+            .unwrap()
+            .is_empty());
+
+        knowledge
+            .unmute(Source::Discord, "general".to_string(), None)
+            .await
+            .unwrap();
+        assert!(knowledge
+            .active_mutes(Source::Discord, "general", "alice")
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_user_mutes() {
+        let knowledge = knowledge_base().await;
+        let muted_until = Utc::now() + chrono::Duration::hours(1);
+        knowledge
+            .mute(
+                Source::Discord,
+                "general".to_string(),
+                Some("alice".to_string()),
+                muted_until,
+            )
+            .await
+            .unwrap();
+
+        let mutes = knowledge
+            .active_mutes(Source::Discord, "general", "alice")
+            .await
+            .unwrap();
+        assert_eq!(mutes.len(), 1);
+        assert_eq!(mutes[0].user_id.as_deref(), Some("alice"));
+        assert!(knowledge
+            .active_mutes(Source::Discord, "general", "bob")
+            .await
+            .unwrap()
+            .is_empty());
+
+        knowledge
+            .mute(Source::Discord, "general".to_string(), None, muted_until)
+            .await
+            .unwrap();
+        knowledge
+            .unmute(
+                Source::Discord,
+                "general".to_string(),
+                Some("alice".to_string()),
+            )
+            .await
+            .unwrap();
+        let mutes = knowledge
+            .active_mutes(Source::Discord, "general", "alice")
+            .await
+            .unwrap();
+        assert_eq!(mutes.len(), 1);
+        assert_eq!(mutes[0].user_id, None);
+    }
 }
@@ -9,6 +9,11 @@ pub enum Source {
     X,
     Twitter,
     Twitch,
+    Cli,
+    Api,
+    Slack,
+    Matrix,
+    Game,
 }
 
 impl Source {
@@ -20,6 +25,11 @@ impl Source {
             Source::X => "x",
             Source::Twitter => "twitter",
             Source::Twitch => "twitch",
+            Source::Cli => "cli",
+            Source::Api => "api",
+            Source::Slack => "slack",
+            Source::Matrix => "matrix",
+            Source::Game => "game",
         }
     }
 }
@@ -35,12 +45,17 @@ impl FromStr for Source {
             "x" => Ok(Source::X),
             "twitter" => Ok(Source::Twitter),
             "twitch" => Ok(Source::Twitch),
+            "cli" => Ok(Source::Cli),
+            "api" => Ok(Source::Api),
+            "slack" => Ok(Source::Slack),
+            "matrix" => Ok(Source::Matrix),
+            "game" => Ok(Source::Game),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, serde::Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum ChannelType {
     DirectMessage,
@@ -74,6 +89,34 @@ impl FromStr for ChannelType {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "user" => Ok(Role::User),
+            "assistant" => Ok(Role::Assistant),
+            _ => Err(()),
+        }
+    }
+}
+
 pub trait MessageMetadata {
     fn id(&self) -> String;
     fn source_id(&self) -> String;
@@ -17,7 +17,16 @@ pub mod agent;
 pub mod attention;
 pub mod character;
 pub mod clients;
+pub mod context;
+pub mod fallback;
 pub mod knowledge;
 pub mod loaders;
 pub mod mcp;
 pub mod ops;
+pub mod pipeline;
+pub mod runtime;
+pub mod streaming;
+pub mod tools;
+
+#[cfg(test)]
+mod testing;
@@ -40,6 +40,15 @@ impl McpClient {
         Ok(Self { inner: client })
     }
 
+    /// Client that only connects to `url` when a request is made.
+    #[cfg(test)]
+    pub(crate) fn lazy(url: &str) -> Self {
+        let transport = WebSocketTransport::new(url, None);
+        Self {
+            inner: Client::builder(transport).build(),
+        }
+    }
+
     pub async fn get_tools(&self) -> Result<Vec<ToolDefinition>> {
         let response = self
             .inner
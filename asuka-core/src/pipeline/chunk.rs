@@ -0,0 +1,154 @@
+/// Messages at most this long are never split on lines.
+const MIN_CHUNK_LENGTH: usize = 100;
+
+pub fn chunk_message(text: &str, max_length: usize, min_chunk_length: usize) -> Vec<String> {
+    // Base case: if text is shorter than min_chunk_length, return as single chunk
+    if text.len() <= min_chunk_length {
+        return vec![text.to_string()];
+    }
+
+    let mut chunks = Vec::new();
+
+    // Find split point for current chunk
+    let mut split_index = text.len();
+    let mut in_heading = false;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        // Start new chunk on headings
+        if line.starts_with('#') && i > 0 {
+            split_index = text.find(line).unwrap_or(text.len());
+            in_heading = true;
+            break;
+        }
+
+        // Check if adding this line would exceed max_length
+        let line_start = text.find(line).unwrap_or(text.len());
+        if line_start + line.len() > max_length && i > 0 {
+            split_index = line_start;
+            break;
+        }
+    }
+
+    // Split text and recurse
+    if split_index < text.len() {
+        let (chunk, rest) = text.split_at(split_index);
+        let mut chunk = chunk.trim().to_string();
+
+        // Add newline after chunk if we're not splitting on a heading
+        if !in_heading && !rest.trim().starts_with('#') {
+            chunk.push('\n');
+        }
+
+        // Strip trailing newline if it's the last character
+        if chunk.ends_with('\n') {
+            chunk.pop();
+        }
+
+        chunks.push(chunk);
+        chunks.extend(chunk_message(rest.trim(), max_length, min_chunk_length));
+    } else {
+        chunks.push(text.trim().to_string());
+    }
+
+    chunks
+}
+
+/// Splits a response into messages of at most `max_length` bytes. Chunks are
+/// split on lines by [`chunk_message`], and lines that are still too long are
+/// split on the last whitespace that fits.
+pub fn split_message(text: &str, max_length: usize) -> Vec<String> {
+    chunk_message(text, max_length, MIN_CHUNK_LENGTH)
+        .into_iter()
+        .flat_map(|chunk| split_long(&chunk, max_length))
+        .filter(|chunk| !chunk.is_empty())
+        .collect()
+}
+
+fn split_long(text: &str, max_length: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = text.trim();
+
+    while rest.len() > max_length {
+        let mut end = max_length;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let split = if rest[end..].starts_with(char::is_whitespace) {
+            end
+        } else {
+            rest[..end]
+                .rfind(char::is_whitespace)
+                .filter(|&split| split > 0)
+                .unwrap_or(end)
+        };
+
+        parts.push(rest[..split].trim_end().to_string());
+        rest = rest[split..].trim_start();
+    }
+    parts.push(rest.to_string());
+
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_message_single_chunk() {
+        let text = "This is a short message";
+        let chunks = chunk_message(text, 100, 1000);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0], text);
+    }
+
+    #[test]
+    fn test_chunk_message_multiple_chunks() {
+        let text = "Line 1\nLine 2\nLine 3";
+        let chunks = chunk_message(text, 10, 5);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[0], "Line 1");
+        assert_eq!(chunks[1], "Line 2");
+        assert_eq!(chunks[2], "Line 3");
+    }
+
+    #[test]
+    fn test_chunk_message_empty_lines() {
+        let text = "Line 1\n\n\nLine 2";
+        let chunks = chunk_message(text, 100, 1000);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0], "Line 1\n\n\nLine 2");
+    }
+
+    #[test]
+    fn test_chunk_message_markdown() {
+        let text = "# Heading 1\nSome text under heading 1\n## Heading 2\nMore text\n# Heading 3\nFinal text";
+        let chunks = chunk_message(text, 100, 50);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0], "# Heading 1\nSome text under heading 1");
+        assert_eq!(
+            chunks[1],
+            "## Heading 2\nMore text\n# Heading 3\nFinal text"
+        );
+    }
+
+    #[test]
+    fn test_split_message_long_line() {
+        let text = "one two three four five six";
+        let chunks = split_message(text, 10);
+        assert_eq!(chunks, vec!["one two", "three four", "five six"]);
+    }
+
+    #[test]
+    fn test_no_chunking_under_min_length() {
+        let text = "This is a message that won't be chunked because it's under the minimum length";
+        let chunks = chunk_message(text, 10, 1000);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0], text);
+    }
+}
@@ -0,0 +1,1044 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+use rig::{
+    completion::{CompletionModel, PromptError},
+    embeddings::EmbeddingModel,
+};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::sync::watch;
+use tracing::{debug, error, info};
+
+use crate::{
+    agent::Agent,
+    attention::{Attention, AttentionCommand, AttentionContext},
+    character::PromptContext,
+    knowledge::{ChannelType, ConversationMessage, Message, Role},
+    streaming::EditThrottle,
+};
+
+mod chunk;
+
+pub use chunk::{chunk_message, split_message};
+
+const PLACEHOLDER: &str = "…";
+/// Shown instead of a response that failed partway, which isn't stored.
+const STREAM_FAILED: &str = "Sorry, something went wrong while answering. Please try again.";
+/// Sent by an admin to mute the bot in a channel, or to lift the mute. Anyone
+/// else mutes the bot towards themselves only.
+const MUTE_COMMAND: &str = "!mute";
+const UNMUTE_COMMAND: &str = "!unmute";
+
+pub type AdapterError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PipelineError {
+    #[error("Failed to store message: {0}")]
+    Store(#[from] anyhow::Error),
+    #[error("Failed to generate response: {0}")]
+    Prompt(#[from] PromptError),
+    #[error("Failed to send message: {0}")]
+    Send(#[source] AdapterError),
+}
+
+/// A message received on a platform, normalized by its [`PlatformAdapter`].
+#[derive(Clone, Debug)]
+pub struct InboundMessage {
+    pub message: Message,
+    /// Display name of the author, stored with their account.
+    pub author: Option<String>,
+    /// Human readable channel name used in prompts. Defaults to the channel id.
+    pub channel_name: Option<String>,
+    /// Extra context for the reply prompt, such as the state of a game.
+    pub context: Option<String>,
+    pub mentioned_names: HashSet<String>,
+    /// Platform user ids mentioned in the message.
+    pub mentioned_ids: HashSet<String>,
+    /// Whether the message replies to one of the bot's own messages.
+    pub is_reply_to_bot: bool,
+}
+
+/// What a platform has to provide to be driven by the [`MessagePipeline`].
+#[async_trait]
+pub trait PlatformAdapter: Send + Sync {
+    /// The platform's own message type.
+    type Event: Send;
+
+    /// Converts a platform message, or returns `None` for messages that should
+    /// be skipped entirely, such as those sent by bots.
+    fn normalize(&self, event: &Self::Event) -> Option<InboundMessage>;
+
+    /// The bot's own user id on the platform, matched against
+    /// [`InboundMessage::mentioned_ids`].
+    fn bot_id(&self) -> Option<String> {
+        None
+    }
+
+    /// Longest message the platform accepts, in bytes.
+    fn max_message_length(&self) -> usize;
+
+    /// Minimum time between edits of a message. Platforms that return `None`
+    /// receive the complete response instead of a streamed one.
+    fn edit_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Conversation leading up to the message, oldest first. Returning `None`
+    /// uses the channel history stored in the knowledge base.
+    async fn history(&self, _inbound: &InboundMessage) -> Option<Vec<ConversationMessage>> {
+        None
+    }
+
+    /// Sends `text` in reply to the inbound message and returns what was sent.
+    async fn send(&self, inbound: &InboundMessage, text: &str) -> Result<Message, AdapterError>;
+
+    /// Replaces the text of a sent message. Only called when
+    /// [`PlatformAdapter::edit_interval`] returns a value.
+    async fn edit(&self, _sent: &Message, _text: &str) -> Result<Message, AdapterError> {
+        Err("Editing messages is not supported".into())
+    }
+
+    async fn delete(&self, _sent: &Message) -> Result<(), AdapterError> {
+        Ok(())
+    }
+}
+
+/// Hooks into the [`MessagePipeline`]. Every method has a default that leaves
+/// the message untouched, so middleware only implements what it needs.
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Called once the inbound message is stored. Returning false drops the
+    /// message before attention is consulted.
+    async fn on_message(&self, _inbound: &mut InboundMessage) -> bool {
+        true
+    }
+
+    /// Called with the attention decision, which may be overridden.
+    async fn on_attention(
+        &self,
+        _inbound: &InboundMessage,
+        command: AttentionCommand,
+    ) -> AttentionCommand {
+        command
+    }
+
+    /// Called with the documents attached to the prompt, before the response
+    /// is generated.
+    async fn on_documents(&self, _inbound: &InboundMessage, _documents: &[String]) {}
+
+    /// Called with the complete response before it is sent. Streamed previews
+    /// show the response as generated, the final message the modified one.
+    async fn on_response(&self, _inbound: &InboundMessage, _response: &mut String) {}
+}
+
+/// Result of handling a message.
+#[derive(Debug)]
+pub struct Outcome {
+    pub command: AttentionCommand,
+    /// Messages sent in reply, as stored in the knowledge base.
+    pub replies: Vec<Message>,
+}
+
+/// Store, attention, prompt and send flow shared by every client. Clients
+/// implement a [`PlatformAdapter`] and pass their messages to
+/// [`MessagePipeline::handle`].
+#[derive(Clone)]
+pub struct MessagePipeline<M: CompletionModel, E: EmbeddingModel + 'static> {
+    agent: Agent<M, E>,
+    attention: Attention<M>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    in_flight: InFlight,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> MessagePipeline<M, E> {
+    pub fn new(agent: Agent<M, E>, attention: Attention<M>) -> Self {
+        Self {
+            agent,
+            attention,
+            middleware: Vec::new(),
+            in_flight: InFlight::default(),
+        }
+    }
+
+    /// Adds middleware, called after the middleware added before it.
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn agent(&self) -> &Agent<M, E> {
+        &self.agent
+    }
+
+    pub fn attention(&self) -> &Attention<M> {
+        &self.attention
+    }
+
+    /// Stops handling new events, which are ignored from now on. Events
+    /// already being handled carry on, see [`MessagePipeline::idle`].
+    pub fn close(&self) {
+        self.in_flight.close();
+    }
+
+    /// Waits until no event is being handled.
+    pub async fn idle(&self) {
+        self.in_flight.idle().await;
+    }
+
+    /// Counts work done outside [`MessagePipeline::handle`], such as an API
+    /// request, as an event being handled until the guard is dropped. Returns
+    /// `None` once closed.
+    pub(crate) fn enter(&self) -> Option<InFlightGuard> {
+        self.in_flight.enter()
+    }
+
+    pub async fn handle<A: PlatformAdapter>(
+        &self,
+        adapter: &A,
+        event: A::Event,
+    ) -> Result<Outcome, PipelineError> {
+        let ignored = |command| Outcome {
+            command,
+            replies: Vec::new(),
+        };
+
+        let Some(_handling) = self.in_flight.enter() else {
+            debug!("Pipeline closed, ignoring event");
+            return Ok(ignored(AttentionCommand::Ignore));
+        };
+
+        let Some(mut inbound) = adapter.normalize(&event) else {
+            return Ok(ignored(AttentionCommand::Ignore));
+        };
+        self.store(&inbound).await?;
+
+        for middleware in &self.middleware {
+            if !middleware.on_message(&mut inbound).await {
+                debug!(id = inbound.message.id, "Message dropped by middleware");
+                return Ok(ignored(AttentionCommand::Ignore));
+            }
+        }
+
+        let conversation = self.conversation(adapter, &inbound).await;
+
+        let max_history = self.attention.config().max_history_messages.max(0) as usize;
+        let history = conversation[conversation.len().saturating_sub(max_history)..]
+            .iter()
+            .map(|entry| &entry.message)
+            .chain(std::iter::once(&inbound.message))
+            .map(|msg| (msg.source_id.clone(), msg.content.clone()))
+            .collect();
+
+        let context = AttentionContext {
+            message_content: inbound.message.content.clone(),
+            channel_id: inbound.message.channel_id.clone(),
+            mentioned_names: inbound.mentioned_names.clone(),
+            mentioned_ids: inbound.mentioned_ids.clone(),
+            bot_id: adapter.bot_id(),
+            is_reply_to_bot: inbound.is_reply_to_bot,
+            history,
+            channel_type: inbound.message.channel_type.clone(),
+            source: inbound.message.source.clone(),
+            messages_since_reply: conversation
+                .iter()
+                .rposition(|entry| entry.message.role == Role::Assistant)
+                .map(|index| conversation.len() - index),
+            engagement: self
+                .attention
+                .engagement(&inbound.message.channel_id, &inbound.message.account_id),
+        };
+        debug!(?context, "Attention context");
+
+        if let Some(command) = self.check_mutes(&inbound, &context).await {
+            return Ok(ignored(command));
+        }
+
+        let mut command = self.attention.should_reply(&context).await;
+        for middleware in &self.middleware {
+            command = middleware.on_attention(&inbound, command).await;
+        }
+        if command == AttentionCommand::Stop {
+            self.attention.disengage(&inbound.message.channel_id);
+            // The model may also stop because the conversation is over, which
+            // is no reason to stay quiet for the whole mute duration. Telling
+            // the bot to stop by name silences it for everyone, saying so
+            // while talking to it only for the author.
+            if self.attention.is_stop_request(&context) {
+                let user_id = (!self.attention.is_mentioned(&context))
+                    .then(|| inbound.message.account_id.clone());
+                self.mute(&inbound.message, user_id).await;
+            }
+        }
+        if command != AttentionCommand::Respond {
+            debug!(?command, "Bot decided not to reply to message");
+            return Ok(ignored(command));
+        }
+
+        let replies = self.respond(adapter, &inbound, &conversation).await?;
+        if !replies.is_empty() {
+            self.attention
+                .engage(&inbound.message.channel_id, &inbound.message.account_id);
+        }
+
+        Ok(Outcome { command, replies })
+    }
+
+    /// Handles mute commands and mutes before attention is consulted,
+    /// returning the command to stop with if the message goes no further.
+    /// Mentioning the bot lifts every mute that applies.
+    async fn check_mutes(
+        &self,
+        inbound: &InboundMessage,
+        context: &AttentionContext,
+    ) -> Option<AttentionCommand> {
+        let knowledge = self.agent.knowledge();
+        let message = &inbound.message;
+
+        // Admins mute the whole channel, anyone else only for themselves
+        let user_id = (!self
+            .attention
+            .config()
+            .admin_ids
+            .contains(&message.account_id))
+        .then(|| message.account_id.clone());
+        match message.content.trim() {
+            MUTE_COMMAND => {
+                self.mute(message, user_id).await;
+                return Some(AttentionCommand::Stop);
+            }
+            UNMUTE_COMMAND => {
+                if let Err(err) = knowledge
+                    .unmute(
+                        message.source.clone(),
+                        message.channel_id.clone(),
+                        user_id.clone(),
+                    )
+                    .await
+                {
+                    error!(?err, "Failed to unmute");
+                }
+                info!(
+                    channel_id = message.channel_id,
+                    ?user_id,
+                    "Unmuted by command"
+                );
+                return Some(AttentionCommand::Ignore);
+            }
+            _ => {}
+        }
+
+        let mutes = match knowledge
+            .active_mutes(
+                message.source.clone(),
+                &message.channel_id,
+                &message.account_id,
+            )
+            .await
+        {
+            Ok(mutes) if mutes.is_empty() => return None,
+            Ok(mutes) => mutes,
+            Err(err) => {
+                error!(?err, "Failed to fetch mutes");
+                return None;
+            }
+        };
+
+        if !self.attention.is_mentioned(context) {
+            debug!(?mutes, "Bot is muted");
+            return Some(AttentionCommand::Ignore);
+        }
+
+        for mute in mutes {
+            if let Err(err) = knowledge
+                .unmute(mute.source, mute.channel_id, mute.user_id)
+                .await
+            {
+                error!(?err, "Failed to lift mute");
+            }
+        }
+        info!(channel_id = message.channel_id, "Mute lifted by mention");
+        None
+    }
+
+    /// Mutes the channel of the message for the configured duration, or only
+    /// towards `user_id` when given.
+    async fn mute(&self, message: &Message, user_id: Option<String>) {
+        let duration =
+            chrono::Duration::from_std(self.attention.config().mute_duration).unwrap_or_default();
+        let muted_until = chrono::Utc::now() + duration;
+
+        info!(
+            source = message.source.as_str(),
+            channel_id = message.channel_id,
+            ?user_id,
+            %muted_until,
+            "Muting bot"
+        );
+        if let Err(err) = self
+            .agent
+            .knowledge()
+            .mute(
+                message.source.clone(),
+                message.channel_id.clone(),
+                user_id,
+                muted_until,
+            )
+            .await
+        {
+            error!(?err, "Failed to store mute");
+        }
+    }
+
+    async fn store(&self, inbound: &InboundMessage) -> Result<(), PipelineError> {
+        let knowledge = self.agent.knowledge();
+        knowledge.create_message(inbound.message.clone()).await?;
+
+        if let Some(author) = &inbound.author {
+            if let Err(err) = knowledge
+                .create_user(
+                    author.clone(),
+                    inbound.message.source.as_str().to_string(),
+                    inbound.message.account_id.clone(),
+                )
+                .await
+            {
+                error!(?err, "Failed to store account");
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn conversation<A: PlatformAdapter>(
+        &self,
+        adapter: &A,
+        inbound: &InboundMessage,
+    ) -> Vec<ConversationMessage> {
+        if let Some(history) = adapter.history(inbound).await {
+            return history;
+        }
+
+        let limit = self
+            .agent
+            .history_window()
+            .max(self.attention.config().max_history_messages.max(0) as usize);
+        match self
+            .agent
+            .knowledge()
+            .channel_history(
+                &inbound.message.source,
+                &inbound.message.channel_id,
+                limit + 1,
+            )
+            .await
+        {
+            Ok(messages) => messages
+                .into_iter()
+                .filter(|entry| entry.message.id != inbound.message.id)
+                .collect(),
+            Err(err) => {
+                error!(?err, "Failed to fetch conversation history");
+                Vec::new()
+            }
+        }
+    }
+
+    /// Generates the reply and sends it, streaming it into a placeholder when
+    /// the platform supports edits.
+    async fn respond<A: PlatformAdapter>(
+        &self,
+        adapter: &A,
+        inbound: &InboundMessage,
+        conversation: &[ConversationMessage],
+    ) -> Result<Vec<Message>, PipelineError> {
+        let context = PromptContext {
+            user: Some(
+                inbound
+                    .author
+                    .clone()
+                    .unwrap_or_else(|| inbound.message.account_id.clone()),
+            ),
+            source: Some(inbound.message.source.clone()),
+            channel_type: Some(inbound.message.channel_type.clone()),
+            channel: Some(
+                inbound
+                    .channel_name
+                    .clone()
+                    .unwrap_or_else(|| inbound.message.channel_id.clone()),
+            ),
+            context: inbound.context.clone(),
+        };
+
+        let reply = self
+            .agent
+            .reply_stream(&context, conversation, &inbound.message.content)
+            .await?;
+        for middleware in &self.middleware {
+            middleware.on_documents(inbound, &reply.documents).await;
+        }
+        let mut stream = reply.tokens;
+
+        let max_length = adapter.max_message_length();
+        let mut throttle = adapter.edit_interval().map(EditThrottle::new);
+        let mut placeholder = match throttle {
+            Some(_) => Some(
+                adapter
+                    .send(inbound, PLACEHOLDER)
+                    .await
+                    .map_err(PipelineError::Send)?,
+            ),
+            None => None,
+        };
+
+        // Edit the placeholder as the response comes in, until it outgrows a
+        // single message
+        let mut response = String::new();
+        let mut shown = String::new();
+        while let Some(token) = stream.next().await {
+            match token {
+                Ok(token) => response.push_str(&token),
+                Err(err) => {
+                    error!(?err, "Failed to stream response");
+                    let shown = match &placeholder {
+                        Some(sent) => adapter.edit(sent, STREAM_FAILED).await,
+                        None => adapter.send(inbound, STREAM_FAILED).await,
+                    };
+                    if let Err(err) = shown {
+                        error!(?err, "Failed to report the failed response");
+                    }
+                    return Err(PromptError::CompletionError(err).into());
+                }
+            }
+
+            let (Some(sent), Some(throttle)) = (&placeholder, throttle.as_mut()) else {
+                continue;
+            };
+            // Some platforms reject edits that don't change the text
+            if response.len() > max_length || response.trim() == shown.trim() || !throttle.ready() {
+                continue;
+            }
+            match adapter.edit(sent, &response).await {
+                Ok(edited) => {
+                    placeholder = Some(edited);
+                    shown = response.clone();
+                }
+                Err(err) => error!(?err, "Failed to edit message"),
+            }
+        }
+
+        if response.trim().is_empty() {
+            if let Some(sent) = &placeholder {
+                if let Err(err) = adapter.delete(sent).await {
+                    error!(?err, "Failed to delete placeholder");
+                }
+            }
+            return Ok(Vec::new());
+        }
+
+        for middleware in &self.middleware {
+            middleware.on_response(inbound, &mut response).await;
+        }
+        debug!(response = %response, "Generated response");
+
+        let mut replies = Vec::new();
+        for (i, chunk) in split_message(&response, max_length).into_iter().enumerate() {
+            let sent = match (i, placeholder.take()) {
+                (0, Some(sent)) if chunk.trim() == shown.trim() => Ok(sent),
+                (0, Some(sent)) => adapter.edit(&sent, &chunk).await,
+                _ => adapter.send(inbound, &chunk).await,
+            };
+            let sent = match sent {
+                Ok(sent) => sent,
+                Err(err) => {
+                    error!(?err, "Failed to send message");
+                    continue;
+                }
+            };
+
+            let reply = Message {
+                role: Role::Assistant,
+                channel_type: inbound.message.channel_type.clone(),
+                reply_to: Some(inbound.message.id.clone()),
+                ..sent
+            };
+            if let Err(err) = self.agent.knowledge().create_message(reply.clone()).await {
+                error!(?err, "Failed to store reply");
+            }
+            replies.push(reply);
+        }
+
+        Ok(replies)
+    }
+}
+
+/// Number of events being handled, and whether new ones are still accepted.
+#[derive(Default)]
+struct InFlightState {
+    closed: bool,
+    count: usize,
+}
+
+/// Tracks the events being handled so shutdown can wait for them.
+#[derive(Clone)]
+struct InFlight(Arc<watch::Sender<InFlightState>>);
+
+impl Default for InFlight {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(InFlightState::default()).0))
+    }
+}
+
+impl InFlight {
+    /// Counts an event until the returned guard is dropped. Returns `None`
+    /// once closed.
+    fn enter(&self) -> Option<InFlightGuard> {
+        let mut entered = false;
+        self.0.send_modify(|state| {
+            if !state.closed {
+                state.count += 1;
+                entered = true;
+            }
+        });
+        entered.then(|| InFlightGuard(self.0.clone()))
+    }
+
+    fn close(&self) {
+        self.0.send_modify(|state| state.closed = true);
+    }
+
+    async fn idle(&self) {
+        let _ = self.0.subscribe().wait_for(|state| state.count == 0).await;
+    }
+}
+
+pub(crate) struct InFlightGuard(Arc<watch::Sender<InFlightState>>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|state| state.count -= 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        attention::AttentionConfig,
+        knowledge::{Document, Source},
+        streaming::{StreamingCompletionModel, TokenStream},
+        testing::{knowledge_base, TestEmbeddingModel, TestModel},
+    };
+    use futures::future::BoxFuture;
+    use rig::completion::{CompletionError, CompletionRequest};
+
+    struct TestAdapter;
+
+    #[async_trait]
+    impl PlatformAdapter for TestAdapter {
+        type Event = InboundMessage;
+
+        fn normalize(&self, event: &InboundMessage) -> Option<InboundMessage> {
+            Some(event.clone())
+        }
+
+        fn max_message_length(&self) -> usize {
+            2000
+        }
+
+        async fn send(
+            &self,
+            inbound: &InboundMessage,
+            text: &str,
+        ) -> Result<Message, AdapterError> {
+            Ok(Message {
+                id: format!("shinobi: {text}"),
+                source_id: "shinobi".to_string(),
+                account_id: "shinobi".to_string(),
+                content: text.to_string(),
+                ..inbound.message.clone()
+            })
+        }
+    }
+
+    /// Adapter that streams responses and records everything it shows.
+    #[derive(Default)]
+    struct StreamingAdapter {
+        shown: std::sync::Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl PlatformAdapter for StreamingAdapter {
+        type Event = InboundMessage;
+
+        fn normalize(&self, event: &InboundMessage) -> Option<InboundMessage> {
+            Some(event.clone())
+        }
+
+        fn max_message_length(&self) -> usize {
+            2000
+        }
+
+        fn edit_interval(&self) -> Option<Duration> {
+            Some(Duration::ZERO)
+        }
+
+        async fn send(
+            &self,
+            inbound: &InboundMessage,
+            text: &str,
+        ) -> Result<Message, AdapterError> {
+            self.shown.lock().unwrap().push(text.to_string());
+            TestAdapter.send(inbound, text).await
+        }
+
+        async fn edit(&self, sent: &Message, text: &str) -> Result<Message, AdapterError> {
+            self.shown.lock().unwrap().push(text.to_string());
+            Ok(Message {
+                content: text.to_string(),
+                ..sent.clone()
+            })
+        }
+    }
+
+    /// Streams the start of a response, then fails.
+    struct FailingStream;
+
+    impl StreamingCompletionModel for FailingStream {
+        fn stream(
+            &self,
+            _request: CompletionRequest,
+        ) -> BoxFuture<'_, Result<TokenStream, CompletionError>> {
+            let tokens: TokenStream = Box::pin(futures::stream::iter([
+                Ok("Check the ".to_string()),
+                Err(CompletionError::ProviderError(
+                    "Connection reset".to_string(),
+                )),
+            ]));
+            Box::pin(async move { Ok(tokens) })
+        }
+    }
+
+    fn config() -> AttentionConfig {
+        AttentionConfig {
+            bot_names: vec!["shinobi".to_string()],
+            admin_ids: vec!["admin".to_string()],
+            ..Default::default()
+        }
+    }
+
+    async fn pipeline_with(
+        config: AttentionConfig,
+        response: &'static str,
+    ) -> MessagePipeline<TestModel, TestEmbeddingModel> {
+        let character =
+            toml::from_str("name = \"Shinobi\"\npreamble = \"You are a support bot.\"").unwrap();
+        let model = TestModel::new(response);
+        let agent = Agent::new(character, model.clone(), knowledge_base().await);
+        MessagePipeline::new(agent, Attention::new(config, model))
+    }
+
+    async fn pipeline(response: &'static str) -> MessagePipeline<TestModel, TestEmbeddingModel> {
+        pipeline_with(config(), response).await
+    }
+
+    fn inbound(account_id: &str, content: &str) -> InboundMessage {
+        InboundMessage {
+            message: Message {
+                id: format!("{account_id}: {content}"),
+                source: Source::Discord,
+                source_id: account_id.to_string(),
+                channel_type: ChannelType::Text,
+                channel_id: "general".to_string(),
+                account_id: account_id.to_string(),
+                role: Role::User,
+                content: content.to_string(),
+                created_at: None,
+                reply_to: None,
+            },
+            author: None,
+            channel_name: None,
+            context: None,
+            mentioned_names: HashSet::new(),
+            mentioned_ids: HashSet::new(),
+            is_reply_to_bot: false,
+        }
+    }
+
+    fn context(inbound: &InboundMessage) -> AttentionContext {
+        AttentionContext {
+            message_content: inbound.message.content.clone(),
+            channel_id: inbound.message.channel_id.clone(),
+            mentioned_names: inbound.mentioned_names.clone(),
+            mentioned_ids: inbound.mentioned_ids.clone(),
+            bot_id: None,
+            is_reply_to_bot: inbound.is_reply_to_bot,
+            history: Vec::new(),
+            channel_type: inbound.message.channel_type.clone(),
+            source: inbound.message.source.clone(),
+            messages_since_reply: None,
+            engagement: None,
+        }
+    }
+
+    /// Whether the bot is muted towards `user_id` in the test channel, by a
+    /// channel or a user mute.
+    async fn is_muted_for(
+        pipeline: &MessagePipeline<TestModel, TestEmbeddingModel>,
+        user_id: &str,
+    ) -> bool {
+        !pipeline
+            .agent()
+            .knowledge()
+            .active_mutes(Source::Discord, "general", user_id)
+            .await
+            .unwrap()
+            .is_empty()
+    }
+
+    /// Whether the whole test channel is muted.
+    async fn is_muted(pipeline: &MessagePipeline<TestModel, TestEmbeddingModel>) -> bool {
+        is_muted_for(pipeline, "nobody").await
+    }
+
+    #[tokio::test]
+    async fn test_stop_phrase_mutes_channel() {
+        let pipeline = pipeline("[RESPOND] 0.9").await;
+
+        let outcome = pipeline
+            .handle(&TestAdapter, inbound("alice", "shinobi, shut up"))
+            .await
+            .unwrap();
+        assert_eq!(outcome.command, AttentionCommand::Stop);
+        assert!(is_muted(&pipeline).await);
+
+        let outcome = pipeline
+            .handle(
+                &TestAdapter,
+                inbound("bob", "anyone know how dojo models work?"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.command, AttentionCommand::Ignore);
+    }
+
+    #[tokio::test]
+    async fn test_mute_expires() {
+        let config = AttentionConfig {
+            mute_duration: Duration::from_millis(200),
+            ..config()
+        };
+        let pipeline = pipeline_with(config, "[IGNORE]").await;
+
+        pipeline
+            .handle(&TestAdapter, inbound("admin", MUTE_COMMAND))
+            .await
+            .unwrap();
+        assert!(is_muted(&pipeline).await);
+
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert!(!is_muted(&pipeline).await);
+    }
+
+    #[tokio::test]
+    async fn test_model_stop_does_not_mute() {
+        let pipeline = pipeline("[STOP] 0.9").await;
+
+        let outcome = pipeline
+            .handle(
+                &TestAdapter,
+                inbound("alice", "thanks all, that settles it"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(outcome.command, AttentionCommand::Stop);
+        assert!(!is_muted(&pipeline).await);
+    }
+
+    #[tokio::test]
+    async fn test_stop_while_engaged_mutes_author() {
+        let pipeline = pipeline("[RESPOND] 0.9").await;
+        pipeline.attention().engage("general", "alice");
+
+        let outcome = pipeline
+            .handle(&TestAdapter, inbound("alice", "please stop"))
+            .await
+            .unwrap();
+        assert_eq!(outcome.command, AttentionCommand::Stop);
+        assert!(is_muted_for(&pipeline, "alice").await);
+        assert!(!is_muted(&pipeline).await);
+    }
+
+    #[tokio::test]
+    async fn test_stop_phrase_in_direct_message_gets_reply() {
+        let pipeline = pipeline("Recalibrate it in the settings.").await;
+        let mut message = inbound("alice", "how do I stop my controller from drifting");
+        message.message.channel_type = ChannelType::DirectMessage;
+
+        let outcome = pipeline.handle(&TestAdapter, message).await.unwrap();
+        assert_eq!(outcome.command, AttentionCommand::Respond);
+        assert_eq!(outcome.replies.len(), 1);
+        assert_eq!(
+            outcome.replies[0].content,
+            "Recalibrate it in the settings."
+        );
+        assert!(!is_muted_for(&pipeline, "alice").await);
+    }
+
+    #[tokio::test]
+    async fn test_mute_commands() {
+        let pipeline = pipeline("[IGNORE]").await;
+
+        // Anyone but an admin only mutes the bot towards themselves
+        pipeline
+            .handle(&TestAdapter, inbound("alice", MUTE_COMMAND))
+            .await
+            .unwrap();
+        assert!(is_muted_for(&pipeline, "alice").await);
+        assert!(!is_muted(&pipeline).await);
+        pipeline
+            .handle(&TestAdapter, inbound("alice", UNMUTE_COMMAND))
+            .await
+            .unwrap();
+        assert!(!is_muted_for(&pipeline, "alice").await);
+
+        let outcome = pipeline
+            .handle(&TestAdapter, inbound("admin", MUTE_COMMAND))
+            .await
+            .unwrap();
+        assert_eq!(outcome.command, AttentionCommand::Stop);
+        assert!(is_muted(&pipeline).await);
+
+        pipeline
+            .handle(&TestAdapter, inbound("alice", UNMUTE_COMMAND))
+            .await
+            .unwrap();
+        assert!(is_muted(&pipeline).await);
+
+        pipeline
+            .handle(&TestAdapter, inbound("admin", UNMUTE_COMMAND))
+            .await
+            .unwrap();
+        assert!(!is_muted(&pipeline).await);
+    }
+
+    #[tokio::test]
+    async fn test_mention_lifts_mute() {
+        let pipeline = pipeline("[IGNORE]").await;
+        pipeline
+            .mute(&inbound("admin", MUTE_COMMAND).message, None)
+            .await;
+
+        let message = inbound("alice", "anyone around?");
+        assert_eq!(
+            pipeline.check_mutes(&message, &context(&message)).await,
+            Some(AttentionCommand::Ignore)
+        );
+        assert!(is_muted(&pipeline).await);
+
+        let message = inbound("alice", "shinobi, are you around?");
+        assert_eq!(
+            pipeline.check_mutes(&message, &context(&message)).await,
+            None
+        );
+        assert!(!is_muted(&pipeline).await);
+    }
+
+    #[tokio::test]
+    async fn test_close_waits_for_handled_events() {
+        let pipeline = pipeline("Hi!").await;
+        let handling = pipeline.in_flight.enter().unwrap();
+        pipeline.close();
+
+        let outcome = pipeline
+            .handle(&TestAdapter, inbound("alice", "shinobi, are you there?"))
+            .await
+            .unwrap();
+        assert_eq!(outcome.command, AttentionCommand::Ignore);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(50), pipeline.idle())
+                .await
+                .is_err()
+        );
+
+        drop(handling);
+        tokio::time::timeout(Duration::from_secs(1), pipeline.idle())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_stream_is_not_stored() {
+        let character =
+            toml::from_str("name = \"Shinobi\"\npreamble = \"You are a support bot.\"").unwrap();
+        let model = TestModel::new("[RESPOND] 0.9");
+        let agent = Agent::new(character, model.clone(), knowledge_base().await)
+            .with_streaming_model(FailingStream);
+        let pipeline = MessagePipeline::new(agent, Attention::new(config(), model));
+        let adapter = StreamingAdapter::default();
+
+        let result = pipeline
+            .handle(
+                &adapter,
+                inbound("alice", "shinobi, my controller is broken"),
+            )
+            .await;
+        assert!(matches!(result, Err(PipelineError::Prompt(_))));
+        assert_eq!(
+            *adapter.shown.lock().unwrap(),
+            vec![PLACEHOLDER, "Check the ", STREAM_FAILED]
+        );
+
+        let history = pipeline
+            .agent()
+            .knowledge()
+            .channel_history(&Source::Discord, "general", 10)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].message.role, Role::User);
+    }
+
+    /// Records the documents middleware is shown.
+    #[derive(Clone, Default)]
+    struct DocumentRecorder(Arc<std::sync::Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Middleware for DocumentRecorder {
+        async fn on_documents(&self, _inbound: &InboundMessage, documents: &[String]) {
+            self.0.lock().unwrap().extend(documents.iter().cloned());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_sees_prompt_documents() {
+        let character =
+            toml::from_str("name = \"Shinobi\"\npreamble = \"You are a support bot.\"").unwrap();
+        let model = TestModel::new("[RESPOND] 0.9");
+        let mut knowledge = knowledge_base().await;
+        knowledge
+            .add_documents([Document {
+                id: "faq".to_string(),
+                source_id: "docs".to_string(),
+                content: "Recalibrate the controller in the settings.".to_string(),
+                created_at: None,
+                metadata: None,
+            }])
+            .await
+            .unwrap();
+        let agent = Agent::new(character, model.clone(), knowledge);
+        let recorder = DocumentRecorder::default();
+        let pipeline = MessagePipeline::new(agent, Attention::new(config(), model))
+            .with_middleware(recorder.clone());
+
+        pipeline
+            .handle(
+                &TestAdapter,
+                inbound("alice", "shinobi, my controller is broken"),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec!["<file id: faq>\nRecalibrate the controller in the settings.\n</file>"]
+        );
+    }
+}
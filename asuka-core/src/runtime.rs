@@ -0,0 +1,379 @@
+use async_trait::async_trait;
+use rig::{completion::CompletionModel, embeddings::EmbeddingModel};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::watch, task::JoinSet};
+use tracing::{error, info, warn};
+use twitter_v2::authorization::Authorization;
+
+use crate::{
+    agent::Agent,
+    attention::Attention,
+    clients::{
+        api::ApiServer,
+        discord::DiscordClient,
+        game::GameServer,
+        matrix::MatrixClient,
+        slack::SlackClient,
+        telegram::TelegramClient,
+        twitch::{TwitchClient, TwitchConfig},
+        twitter::TwitterClient,
+    },
+    fallback::exponential_backoff,
+    pipeline::MessagePipeline,
+};
+
+pub type ServiceError = Box<dyn std::error::Error + Send + Sync>;
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A long running client driven by the [`Runtime`]. `run` only returns when
+/// the client stops, an error is treated as a crash and the client restarted.
+#[async_trait]
+pub trait Service: Send + Sync + 'static {
+    /// Name used in logs.
+    fn name(&self) -> &str;
+
+    async fn run(&self) -> Result<(), ServiceError>;
+}
+
+#[derive(Clone, Debug)]
+pub struct RestartPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Restarts in a row after which a service is given up on. `None` restarts
+    /// it forever.
+    pub max_restarts: Option<usize>,
+    /// A service that ran for this long is considered healthy again and its
+    /// backoff is reset.
+    pub reset_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_restarts: None,
+            reset_after: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Exponential backoff before the given restart, starting at 1.
+    pub fn backoff(&self, restart: usize) -> Duration {
+        exponential_backoff(self.initial_backoff, self.max_backoff, restart)
+    }
+}
+
+/// Runs any number of clients on top of one [`Agent`].
+///
+/// Every client is supervised in its own task and restarted with a backoff
+/// when it fails or panics. Ctrl-C stops accepting new messages, waits up to
+/// the shutdown timeout for the ones being handled, then stops all clients and
+/// flushes the knowledge base before [`Runtime::run`] returns.
+pub struct Runtime<M: CompletionModel, E: EmbeddingModel + 'static> {
+    pipeline: MessagePipeline<M, E>,
+    services: Vec<Arc<dyn Service>>,
+    policy: RestartPolicy,
+    shutdown_timeout: Duration,
+}
+
+impl<M: CompletionModel + 'static, E: EmbeddingModel + 'static> Runtime<M, E> {
+    pub fn new(agent: Agent<M, E>, attention: Attention<M>) -> Self {
+        Self::from_pipeline(MessagePipeline::new(agent, attention))
+    }
+
+    /// Creates a runtime whose clients share a pipeline, e.g. one with middleware.
+    pub fn from_pipeline(pipeline: MessagePipeline<M, E>) -> Self {
+        Self {
+            pipeline,
+            services: Vec::new(),
+            policy: RestartPolicy::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+        }
+    }
+
+    pub fn with_restart_policy(mut self, policy: RestartPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// How long shutdown waits for messages being handled before stopping the
+    /// clients anyway.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    pub fn pipeline(&self) -> &MessagePipeline<M, E> {
+        &self.pipeline
+    }
+
+    pub fn agent(&self) -> &Agent<M, E> {
+        self.pipeline.agent()
+    }
+
+    pub fn discord(self, token: &str) -> Self {
+        let client = DiscordClient::from_pipeline(self.pipeline.clone());
+        let token = token.to_string();
+        self.service(ClientService::new("discord", move || {
+            let client = client.clone();
+            let token = token.clone();
+            async move { Ok(client.start(&token).await?) }
+        }))
+    }
+
+    pub fn telegram(self, token: &str) -> Self {
+        let client = TelegramClient::from_pipeline(self.pipeline.clone());
+        let token = token.to_string();
+        self.service(ClientService::new("telegram", move || {
+            let client = client.clone();
+            let token = token.clone();
+            async move { Ok(client.start(&token).await?) }
+        }))
+    }
+
+    pub fn slack(self, app_token: &str, bot_token: &str) -> Self {
+        let client = SlackClient::from_pipeline(self.pipeline.clone(), app_token, bot_token);
+        self.service(ClientService::new("slack", move || {
+            let client = client.clone();
+            async move { Ok(client.start().await?) }
+        }))
+    }
+
+    pub fn matrix(self, homeserver: &str, access_token: &str) -> Self {
+        let client = MatrixClient::from_pipeline(self.pipeline.clone(), homeserver, access_token);
+        self.service(ClientService::new("matrix", move || {
+            let client = client.clone();
+            async move { Ok(client.start().await?) }
+        }))
+    }
+
+    pub fn twitch(self, config: TwitchConfig) -> Self {
+        let client = TwitchClient::from_pipeline(self.pipeline.clone(), config);
+        self.service(ClientService::new("twitch", move || {
+            let client = client.clone();
+            async move { client.start().await }
+        }))
+    }
+
+    pub fn twitter<A>(self, auth: A) -> Self
+    where
+        A: Authorization + Send + Sync + 'static,
+    {
+        let client = Arc::new(TwitterClient::from_pipeline(self.pipeline.clone(), auth));
+        self.service(ClientService::new("twitter", move || {
+            let client = client.clone();
+            async move { client.start().await }
+        }))
+    }
+
+    /// Serves the OpenAI compatible API on `addr`, optionally requiring an
+    /// API key.
+    pub fn api(self, addr: SocketAddr, api_key: Option<&str>) -> Self {
+        let mut server = ApiServer::from_pipeline(self.pipeline.clone());
+        if let Some(api_key) = api_key {
+            server = server.with_api_key(api_key);
+        }
+        self.service(ClientService::new("api", move || {
+            let server = server.clone();
+            async move { Ok(server.start(addr).await?) }
+        }))
+    }
+
+    /// Serves the in-game NPC WebSocket on `addr`.
+    pub fn game(self, addr: SocketAddr) -> Self {
+        let server = GameServer::from_pipeline(self.pipeline.clone());
+        self.service(ClientService::new("game", move || {
+            let server = server.clone();
+            async move { Ok(server.start(addr).await?) }
+        }))
+    }
+
+    /// Adds a client that isn't built in.
+    pub fn service(mut self, service: impl Service) -> Self {
+        self.services.push(Arc::new(service));
+        self
+    }
+
+    /// Runs every client until all of them stop or Ctrl-C is pressed.
+    pub async fn run(self) -> anyhow::Result<()> {
+        let (shutdown, stopped) = watch::channel(false);
+
+        let mut supervisors = JoinSet::new();
+        for service in self.services {
+            supervisors.spawn(supervise(service, self.policy.clone(), stopped.clone()));
+        }
+
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                if let Err(err) = result {
+                    error!(?err, "Failed to listen for Ctrl-C");
+                }
+                info!("Shutting down");
+                self.pipeline.close();
+                if tokio::time::timeout(self.shutdown_timeout, self.pipeline.idle())
+                    .await
+                    .is_err()
+                {
+                    warn!(
+                        timeout = ?self.shutdown_timeout,
+                        "Messages still being handled, stopping clients anyway"
+                    );
+                }
+                let _ = shutdown.send(true);
+            }
+            _ = async { while supervisors.join_next().await.is_some() {} } => {
+                info!("All clients stopped");
+            }
+        }
+        while supervisors.join_next().await.is_some() {}
+
+        self.pipeline
+            .agent()
+            .knowledge()
+            .flush()
+            .await
+            .map_err(|err| anyhow::anyhow!(err))?;
+        info!("Shutdown complete");
+
+        Ok(())
+    }
+}
+
+/// Runs a service until it stops, restarting it when it fails.
+async fn supervise(
+    service: Arc<dyn Service>,
+    policy: RestartPolicy,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let name = service.name().to_string();
+    let mut restarts = 0;
+
+    loop {
+        info!(service = name, "Starting client");
+        let started = Instant::now();
+
+        // Run in its own task so a panic is reported as a crash instead of
+        // taking the supervisor down
+        let mut task = tokio::spawn({
+            let service = service.clone();
+            async move { service.run().await }
+        });
+        let result = tokio::select! {
+            result = &mut task => result,
+            _ = shutdown.wait_for(|stopped| *stopped) => {
+                task.abort();
+                let _ = task.await;
+                info!(service = name, "Client stopped");
+                return;
+            }
+        };
+
+        match result {
+            Ok(Ok(())) => {
+                info!(service = name, "Client finished");
+                return;
+            }
+            Ok(Err(err)) => error!(service = name, %err, "Client failed"),
+            Err(err) => error!(service = name, %err, "Client panicked"),
+        }
+
+        if started.elapsed() >= policy.reset_after {
+            restarts = 0;
+        }
+        restarts += 1;
+        if policy.max_restarts.is_some_and(|max| restarts > max) {
+            error!(service = name, restarts, "Giving up on client");
+            return;
+        }
+
+        let backoff = policy.backoff(restarts);
+        warn!(service = name, restarts, ?backoff, "Restarting client");
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {}
+            _ = shutdown.wait_for(|stopped| *stopped) => return,
+        }
+    }
+}
+
+/// Adapts a built in client, started by calling `start` again on every restart.
+struct ClientService<F> {
+    name: &'static str,
+    start: F,
+}
+
+impl<F> ClientService<F> {
+    fn new(name: &'static str, start: F) -> Self {
+        Self { name, start }
+    }
+}
+
+#[async_trait]
+impl<F, Fut> Service for ClientService<F>
+where
+    F: Fn() -> Fut + Send + Sync + 'static,
+    Fut: std::future::Future<Output = Result<(), ServiceError>> + Send,
+{
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn run(&self) -> Result<(), ServiceError> {
+        (self.start)().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    struct FailingService {
+        runs: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Service for FailingService {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        async fn run(&self) -> Result<(), ServiceError> {
+            if self.runs.fetch_add(1, Ordering::SeqCst) == 1 {
+                panic!("client panicked");
+            }
+            Err("connection lost".into())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_restarts_failed_service() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let policy = RestartPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(1),
+            max_restarts: Some(2),
+            reset_after: Duration::from_secs(60),
+        };
+        let (_shutdown, stopped) = watch::channel(false);
+
+        let service = Arc::new(FailingService { runs: runs.clone() });
+        supervise(service, policy, stopped).await;
+
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RestartPolicy::default();
+        assert_eq!(policy.backoff(1), Duration::from_secs(1));
+        assert_eq!(policy.backoff(3), Duration::from_secs(4));
+        assert_eq!(policy.backoff(10), Duration::from_secs(60));
+    }
+}
@@ -0,0 +1,194 @@
+use futures::{future::BoxFuture, stream};
+use rig::completion::{CompletionError, CompletionRequest};
+use serde_json::{json, Value};
+use tracing::debug;
+
+use super::{StreamingCompletionModel, TokenStream};
+
+const ANTHROPIC_API_URL: &str = "https://api.anthropic.com";
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+const DEFAULT_MAX_TOKENS: u64 = 2048;
+
+/// Anthropic model called through the streaming Messages API.
+#[derive(Clone)]
+pub struct StreamingModel {
+    client: reqwest::Client,
+    base_url: String,
+    api_key: String,
+    model: String,
+}
+
+impl StreamingModel {
+    pub fn new(api_key: &str, model: &str) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            base_url: ANTHROPIC_API_URL.to_string(),
+            api_key: api_key.to_string(),
+            model: model.to_string(),
+        }
+    }
+
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        self.base_url = base_url.to_string();
+        self
+    }
+
+    fn request_body(&self, request: &CompletionRequest) -> Value {
+        let mut messages: Vec<Value> = request
+            .chat_history
+            .iter()
+            .map(|msg| json!({ "role": msg.role, "content": msg.content }))
+            .collect();
+        messages.push(json!({ "role": "user", "content": prompt_with_documents(request) }));
+
+        let mut body = json!({
+            "model": self.model,
+            "max_tokens": request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            "messages": messages,
+            "stream": true,
+        });
+        if let Some(preamble) = &request.preamble {
+            body["system"] = json!(preamble);
+        }
+        if let Some(temperature) = request.temperature {
+            body["temperature"] = json!(temperature);
+        }
+
+        body
+    }
+}
+
+impl StreamingCompletionModel for StreamingModel {
+    fn stream(
+        &self,
+        request: CompletionRequest,
+    ) -> BoxFuture<'_, Result<TokenStream, CompletionError>> {
+        Box::pin(async move {
+            let response = self
+                .client
+                .post(format!("{}/v1/messages", self.base_url))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", ANTHROPIC_VERSION)
+                .json(&self.request_body(&request))
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(CompletionError::ProviderError(response.text().await?));
+            }
+
+            debug!(model = self.model, "Streaming response");
+            Ok(Box::pin(text_deltas(response)) as TokenStream)
+        })
+    }
+}
+
+/// Attaches the context documents to the prompt, the same way rig does for
+/// non-streaming requests.
+fn prompt_with_documents(request: &CompletionRequest) -> String {
+    if request.documents.is_empty() {
+        return request.prompt.clone();
+    }
+
+    let documents = request
+        .documents
+        .iter()
+        .map(|doc| format!("<file id: {}>\n{}\n</file>", doc.id, doc.text))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "<attachments>\n{}</attachments>\n\n{}",
+        documents, request.prompt
+    )
+}
+
+/// Reads server-sent events from the response and yields the text deltas.
+fn text_deltas(
+    response: reqwest::Response,
+) -> impl futures::Stream<Item = Result<String, CompletionError>> {
+    stream::unfold(
+        Some((response, Vec::new())),
+        |state: Option<(reqwest::Response, Vec<u8>)>| async move {
+            let (mut response, mut buffer) = state?;
+            loop {
+                if let Some(end) = buffer.windows(2).position(|window| window == b"\n\n") {
+                    let event: Vec<u8> = buffer.drain(..end + 2).collect();
+                    match parse_event(&String::from_utf8_lossy(&event)) {
+                        Some(Ok(Event::Text(text))) => {
+                            return Some((Ok(text), Some((response, buffer))))
+                        }
+                        Some(Ok(Event::Stop)) => return None,
+                        Some(Err(err)) => return Some((Err(err), None)),
+                        None => continue,
+                    }
+                }
+
+                match response.chunk().await {
+                    Ok(Some(chunk)) => buffer.extend_from_slice(&chunk),
+                    Ok(None) => return None,
+                    Err(err) => return Some((Err(err.into()), None)),
+                }
+            }
+        },
+    )
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Text(String),
+    Stop,
+}
+
+/// Parses a single server-sent event. Events without text, like pings and
+/// block boundaries, are skipped.
+fn parse_event(event: &str) -> Option<Result<Event, CompletionError>> {
+    let data = event
+        .lines()
+        .find_map(|line| line.strip_prefix("data:"))?
+        .trim();
+    let data: Value = match serde_json::from_str(data) {
+        Ok(data) => data,
+        Err(err) => return Some(Err(err.into())),
+    };
+
+    match data["type"].as_str()? {
+        "content_block_delta" if data["delta"]["type"] == "text_delta" => Some(Ok(Event::Text(
+            data["delta"]["text"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+        ))),
+        "message_stop" => Some(Ok(Event::Stop)),
+        "error" => Some(Err(CompletionError::ProviderError(
+            data["error"]["message"]
+                .as_str()
+                .unwrap_or("Unknown error")
+                .to_string(),
+        ))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_event() {
+        let delta = "event: content_block_delta\ndata: {\"type\":\"content_block_delta\",\"index\":0,\"delta\":{\"type\":\"text_delta\",\"text\":\"Hello\"}}\n\n";
+        assert_eq!(
+            parse_event(delta).unwrap().unwrap(),
+            Event::Text("Hello".to_string())
+        );
+
+        let ping = "event: ping\ndata: {\"type\": \"ping\"}\n\n";
+        assert!(parse_event(ping).is_none());
+
+        let stop = "event: message_stop\ndata: {\"type\":\"message_stop\"}\n\n";
+        assert_eq!(parse_event(stop).unwrap().unwrap(), Event::Stop);
+
+        let error = "event: error\ndata: {\"type\":\"error\",\"error\":{\"type\":\"overloaded_error\",\"message\":\"Overloaded\"}}\n\n";
+        assert!(parse_event(error).unwrap().is_err());
+    }
+}
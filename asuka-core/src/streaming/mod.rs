@@ -0,0 +1,64 @@
+use futures::{future::BoxFuture, Stream};
+use rig::completion::{CompletionError, CompletionRequest};
+use std::{
+    pin::Pin,
+    time::{Duration, Instant},
+};
+
+pub mod anthropic;
+
+/// Text of a response as it is generated.
+pub type TokenStream = Pin<Box<dyn Stream<Item = Result<String, CompletionError>> + Send>>;
+
+/// Completion model that can yield its response while it is being generated.
+pub trait StreamingCompletionModel: Send + Sync {
+    fn stream(
+        &self,
+        request: CompletionRequest,
+    ) -> BoxFuture<'_, Result<TokenStream, CompletionError>>;
+}
+
+/// Limits how often a message is edited while a streamed response comes in,
+/// so clients stay within the platform's rate limits.
+pub struct EditThrottle {
+    interval: Duration,
+    last_edit: Option<Instant>,
+}
+
+impl EditThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_edit: None,
+        }
+    }
+
+    /// Whether an edit may be sent now. Returns true at most once per interval.
+    pub fn ready(&mut self) -> bool {
+        let now = Instant::now();
+        if let Some(last_edit) = self.last_edit {
+            if now.duration_since(last_edit) < self.interval {
+                return false;
+            }
+        }
+
+        self.last_edit = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_throttle() {
+        let mut throttle = EditThrottle::new(Duration::from_secs(60));
+        assert!(throttle.ready());
+        assert!(!throttle.ready());
+
+        let mut throttle = EditThrottle::new(Duration::ZERO);
+        assert!(throttle.ready());
+        assert!(throttle.ready());
+    }
+}
@@ -0,0 +1,105 @@
+//! Models and an in-memory knowledge base for tests, so they run without
+//! API keys.
+
+use rig::{
+    completion::{
+        CompletionError, CompletionModel, CompletionRequest, CompletionResponse, ModelChoice,
+    },
+    embeddings::{Embedding, EmbeddingError, EmbeddingModel},
+};
+use sqlite_vec::sqlite3_vec_init;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Once,
+};
+use tokio_rusqlite::{ffi::sqlite3_auto_extension, Connection};
+
+use crate::knowledge::KnowledgeBase;
+
+/// Completion model answering every request with the same response, or
+/// failing every request with the same provider error.
+#[derive(Clone)]
+pub struct TestModel {
+    response: Result<&'static str, &'static str>,
+    calls: Arc<AtomicUsize>,
+}
+
+impl TestModel {
+    pub fn new(response: &'static str) -> Self {
+        Self {
+            response: Ok(response),
+            calls: Arc::default(),
+        }
+    }
+
+    pub fn failing(error: &'static str) -> Self {
+        Self {
+            response: Err(error),
+            calls: Arc::default(),
+        }
+    }
+
+    /// Number of completions requested so far.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+impl CompletionModel for TestModel {
+    type Response = ();
+
+    async fn completion(
+        &self,
+        _request: CompletionRequest,
+    ) -> Result<CompletionResponse<()>, CompletionError> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        match self.response {
+            Ok(response) => Ok(CompletionResponse {
+                choice: ModelChoice::Message(response.to_string()),
+                raw_response: (),
+            }),
+            Err(error) => Err(CompletionError::ProviderError(error.to_string())),
+        }
+    }
+}
+
+/// Embeds every text as the same vector.
+#[derive(Clone)]
+pub struct TestEmbeddingModel;
+
+impl EmbeddingModel for TestEmbeddingModel {
+    const MAX_DOCUMENTS: usize = 16;
+
+    fn ndims(&self) -> usize {
+        4
+    }
+
+    async fn embed_texts(
+        &self,
+        texts: impl IntoIterator<Item = String> + Send,
+    ) -> Result<Vec<Embedding>, EmbeddingError> {
+        Ok(texts
+            .into_iter()
+            .map(|document| Embedding {
+                document,
+                vec: vec![0.5; 4],
+            })
+            .collect())
+    }
+}
+
+/// In-memory database with the `sqlite-vec` extension loaded.
+pub async fn connection() -> Connection {
+    static SQLITE_VEC: Once = Once::new();
+    SQLITE_VEC.call_once(|| unsafe {
+        sqlite3_auto_extension(Some(std::mem::transmute(sqlite3_vec_init as *const ())));
+    });
+
+    Connection::open_in_memory().await.unwrap()
+}
+
+pub async fn knowledge_base() -> KnowledgeBase<TestEmbeddingModel> {
+    KnowledgeBase::new(connection().await, TestEmbeddingModel)
+        .await
+        .unwrap()
+}
@@ -0,0 +1,414 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use rig::{
+    agent::AgentBuilder,
+    completion::{CompletionModel, ToolDefinition},
+    embeddings::{EmbedError, EmbeddingError, EmbeddingModel, EmbeddingsBuilder},
+    tool::{Tool, ToolDyn, ToolError, ToolSet},
+    vector_store::{
+        in_memory_store::InMemoryVectorStore, VectorStoreError, VectorStoreIndex,
+        VectorStoreIndexDyn,
+    },
+    Embed,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::{debug, info};
+
+use crate::mcp::{self, McpClient};
+
+const DEFAULT_DYNAMIC_SAMPLE: usize = 3;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ToolRegistryError {
+    #[error("Failed to prepare tool definitions for embedding: {0}")]
+    Embed(#[from] EmbedError),
+    #[error("Failed to embed tool definitions: {0}")]
+    Embedding(#[from] EmbeddingError),
+}
+
+/// Tools available to the agent. Static and MCP tools are offered with every
+/// prompt, dynamic tools are picked by similarity between the prompt and their
+/// definitions.
+///
+/// The registry is cheap to clone and is attached to every agent built by
+/// [`crate::agent::Agent`], so all clients get the same tools.
+#[derive(Clone, Default)]
+pub struct ToolRegistry {
+    static_tools: Vec<SharedTool>,
+    dynamic_tools: Vec<SharedTool>,
+    dynamic_index: Option<DynamicIndex>,
+    dynamic_sample: usize,
+}
+
+impl ToolRegistry {
+    pub fn builder() -> ToolRegistryBuilder {
+        ToolRegistryBuilder::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.static_tools.is_empty() && self.dynamic_tools.is_empty()
+    }
+
+    /// Names of every registered tool.
+    pub fn names(&self) -> Vec<String> {
+        self.static_tools
+            .iter()
+            .chain(self.dynamic_tools.iter())
+            .map(|tool| tool.name())
+            .collect()
+    }
+
+    /// Adds the registered tools to an agent builder.
+    pub fn attach<M: CompletionModel>(&self, mut builder: AgentBuilder<M>) -> AgentBuilder<M> {
+        // `AgentBuilder::tool` takes ownership of a typed tool, so every tool is
+        // added as a dynamic tool instead. Static tools use an index that always
+        // returns all of them.
+        if !self.static_tools.is_empty() {
+            let index = AllTools(self.static_tools.iter().map(|tool| tool.name()).collect());
+            builder =
+                builder.dynamic_tools(self.static_tools.len(), index, tool_set(&self.static_tools));
+        }
+
+        if let Some(index) = &self.dynamic_index {
+            builder = builder.dynamic_tools(
+                self.dynamic_sample,
+                index.clone(),
+                tool_set(&self.dynamic_tools),
+            );
+        }
+
+        builder
+    }
+}
+
+#[derive(Default)]
+pub struct ToolRegistryBuilder {
+    static_tools: Vec<SharedTool>,
+    dynamic_tools: Vec<SharedTool>,
+    dynamic_sample: Option<usize>,
+}
+
+impl ToolRegistryBuilder {
+    /// Registers a tool that is offered with every prompt.
+    pub fn static_tool(mut self, tool: impl Tool + 'static) -> Self {
+        self.static_tools.push(SharedTool(Arc::new(tool)));
+        self
+    }
+
+    /// Registers a tool that is only offered when its definition is similar to
+    /// the prompt.
+    pub fn dynamic_tool(mut self, tool: impl Tool + 'static) -> Self {
+        self.dynamic_tools.push(SharedTool(Arc::new(tool)));
+        self
+    }
+
+    /// Number of dynamic tools offered with each prompt.
+    pub fn dynamic_sample(mut self, sample: usize) -> Self {
+        self.dynamic_sample = Some(sample);
+        self
+    }
+
+    /// Registers tools served by an MCP server, as listed by
+    /// [`McpClient::get_tools`]. They are offered with every prompt.
+    pub fn mcp_tools(mut self, client: McpClient, tools: Vec<mcp::ToolDefinition>) -> Self {
+        self.static_tools
+            .extend(tools.into_iter().map(|definition| {
+                SharedTool(Arc::new(McpTool {
+                    client: client.clone(),
+                    definition,
+                }))
+            }));
+        self
+    }
+
+    /// Embeds the definitions of the dynamic tools with `model`. The model is
+    /// only called when dynamic tools were registered.
+    pub async fn build<E: EmbeddingModel + 'static>(
+        self,
+        model: &E,
+    ) -> Result<ToolRegistry, ToolRegistryError> {
+        let dynamic_index = if self.dynamic_tools.is_empty() {
+            None
+        } else {
+            let mut documents = Vec::with_capacity(self.dynamic_tools.len());
+            for tool in &self.dynamic_tools {
+                let definition = tool.definition(String::new()).await;
+                documents.push(ToolDocument {
+                    name: definition.name.clone(),
+                    definition: format!("{}: {}", definition.name, definition.description),
+                });
+            }
+
+            let embeddings = EmbeddingsBuilder::new(model.clone())
+                .documents(documents)?
+                .build()
+                .await?;
+            let store =
+                InMemoryVectorStore::from_documents_with_id_f(embeddings, |doc| doc.name.clone());
+
+            Some(DynamicIndex(Arc::new(store.index(model.clone()))))
+        };
+
+        let registry = ToolRegistry {
+            static_tools: self.static_tools,
+            dynamic_tools: self.dynamic_tools,
+            dynamic_index,
+            dynamic_sample: self.dynamic_sample.unwrap_or(DEFAULT_DYNAMIC_SAMPLE),
+        };
+        info!(tools = ?registry.names(), "Registered tools");
+
+        Ok(registry)
+    }
+}
+
+fn tool_set(tools: &[SharedTool]) -> ToolSet {
+    let mut set = ToolSet::default();
+    for tool in tools {
+        set.add_tool(tool.clone());
+    }
+    set
+}
+
+/// Tool shared between the agents built for each prompt.
+#[derive(Clone)]
+struct SharedTool(Arc<dyn ToolDyn>);
+
+impl ToolDyn for SharedTool {
+    fn name(&self) -> String {
+        self.0.name()
+    }
+
+    fn definition(
+        &self,
+        prompt: String,
+    ) -> Pin<Box<dyn Future<Output = ToolDefinition> + Send + Sync + '_>> {
+        self.0.definition(prompt)
+    }
+
+    fn call(
+        &self,
+        args: String,
+    ) -> Pin<Box<dyn Future<Output = Result<String, ToolError>> + Send + Sync + '_>> {
+        self.0.call(args)
+    }
+}
+
+/// Tool executed by an MCP server.
+struct McpTool {
+    client: McpClient,
+    definition: mcp::ToolDefinition,
+}
+
+impl ToolDyn for McpTool {
+    fn name(&self) -> String {
+        self.definition.name.clone()
+    }
+
+    fn definition(
+        &self,
+        _prompt: String,
+    ) -> Pin<Box<dyn Future<Output = ToolDefinition> + Send + Sync + '_>> {
+        let definition = ToolDefinition {
+            name: self.definition.name.clone(),
+            description: self.definition.description.clone(),
+            parameters: self.definition.parameters.clone(),
+        };
+        Box::pin(async move { definition })
+    }
+
+    fn call(
+        &self,
+        args: String,
+    ) -> Pin<Box<dyn Future<Output = Result<String, ToolError>> + Send + Sync + '_>> {
+        let client = self.client.clone();
+        let name = self.definition.name.clone();
+        debug!(tool = name, args, "Calling MCP tool");
+
+        // The returned future has to be `Sync`, which the MCP request isn't, so
+        // the request runs on its own task.
+        let handle = tokio::spawn(async move {
+            let args: Value = serde_json::from_str(&args)?;
+            client
+                .execute_tool(&name, args)
+                .await
+                .map_err(|err| ToolError::ToolCallError(err.into()))
+        });
+
+        Box::pin(async move {
+            let result = handle
+                .await
+                .map_err(|err| ToolError::ToolCallError(Box::new(err)))??;
+            Ok(result.to_string())
+        })
+    }
+}
+
+#[derive(Embed, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct ToolDocument {
+    name: String,
+    #[embed]
+    definition: String,
+}
+
+#[derive(Clone)]
+struct DynamicIndex(Arc<dyn VectorStoreIndexDyn>);
+
+impl VectorStoreIndex for DynamicIndex {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        self.0
+            .top_n(query, n)
+            .await?
+            .into_iter()
+            .map(|(score, id, doc)| Ok((score, id, serde_json::from_value(doc)?)))
+            .collect()
+    }
+
+    async fn top_n_ids(
+        &self,
+        query: &str,
+        n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        self.0.top_n_ids(query, n).await
+    }
+}
+
+/// Index that matches every static tool regardless of the prompt.
+struct AllTools(Vec<String>);
+
+impl VectorStoreIndex for AllTools {
+    async fn top_n<T: for<'a> Deserialize<'a> + Send>(
+        &self,
+        _query: &str,
+        _n: usize,
+    ) -> Result<Vec<(f64, String, T)>, VectorStoreError> {
+        Ok(Vec::new())
+    }
+
+    async fn top_n_ids(
+        &self,
+        _query: &str,
+        _n: usize,
+    ) -> Result<Vec<(f64, String)>, VectorStoreError> {
+        Ok(self.0.iter().map(|name| (1.0, name.clone())).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rig::completion::Completion;
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::{TestEmbeddingModel, TestModel};
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("Test tool error")]
+    struct TestToolError;
+
+    macro_rules! test_tool {
+        ($tool:ident, $name:literal) => {
+            struct $tool;
+
+            impl Tool for $tool {
+                const NAME: &'static str = $name;
+                type Error = TestToolError;
+                type Args = ();
+                type Output = String;
+
+                async fn definition(&self, _prompt: String) -> ToolDefinition {
+                    ToolDefinition {
+                        name: $name.to_string(),
+                        description: format!("The {} tool", $name),
+                        parameters: json!({ "type": "object", "properties": {} }),
+                    }
+                }
+
+                async fn call(&self, _args: ()) -> Result<String, TestToolError> {
+                    Ok($name.to_string())
+                }
+            }
+        };
+    }
+
+    test_tool!(Balance, "balance");
+    test_tool!(Price, "price");
+    test_tool!(Weather, "weather");
+    test_tool!(Dice, "dice");
+    test_tool!(Quote, "quote");
+
+    /// Names of the tools offered to the model with a prompt.
+    async fn offered(registry: &ToolRegistry) -> Vec<String> {
+        let agent = registry
+            .attach(AgentBuilder::new(TestModel::new("")))
+            .build();
+        let request = agent
+            .completion("What's the weather?", Vec::new())
+            .await
+            .unwrap()
+            .build();
+
+        let mut names: Vec<_> = request.tools.into_iter().map(|tool| tool.name).collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_static_tools_always_offered() {
+        let registry = ToolRegistry::builder()
+            .static_tool(Balance)
+            .static_tool(Price)
+            .build(&TestEmbeddingModel)
+            .await
+            .unwrap();
+
+        assert_eq!(offered(&registry).await, vec!["balance", "price"]);
+    }
+
+    #[tokio::test]
+    async fn test_dynamic_tools_sampled() {
+        let registry = ToolRegistry::builder()
+            .static_tool(Balance)
+            .dynamic_tool(Weather)
+            .dynamic_tool(Dice)
+            .dynamic_tool(Quote)
+            .dynamic_sample(2)
+            .build(&TestEmbeddingModel)
+            .await
+            .unwrap();
+
+        let offered = offered(&registry).await;
+        assert_eq!(offered.len(), 3);
+        assert!(offered.contains(&"balance".to_string()));
+        assert!(offered
+            .iter()
+            .filter(|name| *name != "balance")
+            .all(|name| ["weather", "dice", "quote"].contains(&name.as_str())));
+    }
+
+    #[tokio::test]
+    async fn test_names() {
+        let definition = mcp::ToolDefinition {
+            name: "search".to_string(),
+            description: "Searches the web".to_string(),
+            parameters: json!({ "type": "object", "properties": {} }),
+        };
+        let registry = ToolRegistry::builder()
+            .static_tool(Balance)
+            .dynamic_tool(Weather)
+            .mcp_tools(McpClient::lazy("ws://localhost:1"), vec![definition])
+            .build(&TestEmbeddingModel)
+            .await
+            .unwrap();
+
+        let mut names = registry.names();
+        names.sort();
+        assert_eq!(names, vec!["balance", "search", "weather"]);
+        assert!(!registry.is_empty());
+        assert!(ToolRegistry::default().is_empty());
+    }
+}
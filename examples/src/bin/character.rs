@@ -0,0 +1,59 @@
+use clap::{Parser, Subcommand};
+
+use asuka_core::character::Character;
+use asuka_core::init_logging;
+
+#[derive(Parser)]
+#[command(author, version, about = "Convert characters between formats", long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Convert an Eliza character.json into a character TOML file
+    Import {
+        /// Path to the Eliza character JSON file
+        input: String,
+
+        /// Path to write the character TOML file to
+        output: String,
+    },
+
+    /// Convert a character TOML file into an Eliza character.json
+    Export {
+        /// Path to the character TOML file
+        input: String,
+
+        /// Path to write the Eliza character JSON file to
+        output: String,
+    },
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    init_logging();
+
+    let args = Args::parse();
+
+    let warnings = match args.command {
+        Command::Import { input, output } => {
+            let json = std::fs::read_to_string(&input)?;
+            let (character, warnings) = Character::from_eliza_json(&json)?;
+            std::fs::write(&output, character.to_toml()?)?;
+            warnings
+        }
+        Command::Export { input, output } => {
+            let character = Character::load(&input)?;
+            let (json, warnings) = character.to_eliza_json()?;
+            std::fs::write(&output, json)?;
+            warnings
+        }
+    };
+
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+
+    Ok(())
+}
@@ -3,13 +3,22 @@ use rig::providers::{self, anthropic, openai};
 use sqlite_vec::sqlite3_vec_init;
 use tokio_rusqlite::ffi::sqlite3_auto_extension;
 use tokio_rusqlite::Connection;
+use twitter_v2::authorization::Oauth1aToken;
 
+use asuka_core::agent::Agent;
 use asuka_core::attention::{Attention, AttentionConfig};
-use asuka_core::character;
+use asuka_core::character::{self, CharacterWatcher};
+use asuka_core::clients::cli::CliClient;
+use asuka_core::clients::twitch::TwitchConfig;
+use asuka_core::fallback::FallbackModel;
 use asuka_core::init_logging;
 use asuka_core::knowledge::KnowledgeBase;
 use asuka_core::loaders::{MultiLoader, MultiLoaderConfig};
-use asuka_core::{agent::Agent, clients::discord::DiscordClient};
+use asuka_core::mcp::{McpClient, McpEndpoint};
+use asuka_core::runtime::Runtime;
+use asuka_core::streaming::anthropic::StreamingModel;
+use asuka_core::tools::ToolRegistry;
+use asuka_starknet::{add_token::AddToken, transfer::Transfer};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -24,7 +33,47 @@ struct Args {
 
     /// Discord API token (can also be set via DISCORD_API_TOKEN env var)
     #[arg(long, env)]
-    discord_api_token: String,
+    discord_api_token: Option<String>,
+
+    /// Telegram bot token (can also be set via TELEGRAM_BOT_TOKEN env var)
+    #[arg(long, env)]
+    telegram_bot_token: Option<String>,
+
+    /// Slack app level and bot tokens, the client only starts when both are
+    /// set (can also be set via SLACK_* env vars)
+    #[arg(long, env)]
+    slack_app_token: Option<String>,
+    #[arg(long, env)]
+    slack_bot_token: Option<String>,
+
+    /// Matrix homeserver URL and access token, the client only starts when
+    /// both are set (can also be set via MATRIX_* env vars)
+    #[arg(long, env)]
+    matrix_homeserver: Option<String>,
+    #[arg(long, env)]
+    matrix_access_token: Option<String>,
+
+    /// Twitch bot login and OAuth token, the client only starts when both are
+    /// set (can also be set via TWITCH_* env vars)
+    #[arg(long, env)]
+    twitch_username: Option<String>,
+    #[arg(long, env)]
+    twitch_oauth_token: Option<String>,
+
+    /// Twitch channels to join
+    #[arg(long, env, value_delimiter = ',')]
+    twitch_channels: Vec<String>,
+
+    /// Twitter OAuth 1.0a credentials, the client only starts when all four
+    /// are set (can also be set via TWITTER_* env vars)
+    #[arg(long, env)]
+    twitter_consumer_key: Option<String>,
+    #[arg(long, env)]
+    twitter_consumer_secret: Option<String>,
+    #[arg(long, env)]
+    twitter_access_token: Option<String>,
+    #[arg(long, env)]
+    twitter_access_token_secret: Option<String>,
 
     /// XAI API token (can also be set via XAI_API_KEY env var)
     #[arg(long, env = "XAI_API_KEY")]
@@ -49,6 +98,36 @@ struct Args {
     /// Local path to store downloaded content
     #[arg(long, default_value = ".sources")]
     sources_path: String,
+
+    /// Give the agent the Starknet `add_token` and `transfer` tools. Replies
+    /// aren't streamed while tools are registered.
+    #[arg(long)]
+    starknet_tools: bool,
+
+    /// Address to serve the OpenAI compatible API on (e.g. 127.0.0.1:8080)
+    #[arg(long, env = "API_ADDR")]
+    api_addr: Option<std::net::SocketAddr>,
+
+    /// Bearer token required by the API (can also be set via API_KEY env var)
+    #[arg(long, env = "API_KEY")]
+    api_key: Option<String>,
+
+    /// Address to serve the in-game NPC WebSocket on (e.g. 127.0.0.1:8081)
+    #[arg(long, env = "GAME_ADDR")]
+    game_addr: Option<std::net::SocketAddr>,
+
+    /// Account ids allowed to `!mute` and `!unmute` the bot
+    #[arg(long, env, value_delimiter = ',')]
+    admin_ids: Vec<String>,
+
+    /// Chat with the character in the terminal instead of starting the
+    /// platform clients
+    #[arg(long)]
+    cli: bool,
+
+    /// MCP server to load additional tools from (e.g. ws://localhost:3000)
+    #[arg(long, env = "MCP_URL")]
+    mcp_url: Option<String>,
 }
 
 #[tokio::main]
@@ -58,18 +137,31 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let args = Args::parse();
 
-    let character_content =
-        std::fs::read_to_string(&args.character).expect("Failed to read character file");
-    let character: character::Character =
-        toml::from_str(&character_content).expect("Failed to parse character TOML");
+    let character = character::Character::load(&args.character)?;
 
     // Initialize clients
     let oai = providers::openai::Client::new(&args.openai_api_key);
     let anthropic = anthropic::ClientBuilder::new(&args.anthropic_api_key).build();
 
     let embedding_model = oai.embedding_model(openai::TEXT_EMBEDDING_3_SMALL);
-    let completion_model = anthropic.completion_model(anthropic::CLAUDE_3_5_SONNET);
-    let small_completion_model = anthropic.completion_model(anthropic::CLAUDE_3_HAIKU);
+    // Fall back to OpenAI when Anthropic is overloaded or unreachable
+    let completion_model = FallbackModel::builder()
+        .model(
+            anthropic::CLAUDE_3_5_SONNET,
+            anthropic.completion_model(anthropic::CLAUDE_3_5_SONNET),
+        )
+        .model(openai::GPT_4O, oai.completion_model(openai::GPT_4O))
+        .build();
+    let small_completion_model = FallbackModel::builder()
+        .model(
+            anthropic::CLAUDE_3_HAIKU,
+            anthropic.completion_model(anthropic::CLAUDE_3_HAIKU),
+        )
+        .model(
+            openai::GPT_4O_MINI,
+            oai.completion_model(openai::GPT_4O_MINI),
+        )
+        .build();
 
     // Initialize the `sqlite-vec`extension
     // See: https://alexgarcia.xyz/sqlite-vec/rust.html
@@ -78,7 +170,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     let conn = Connection::open(args.db_path).await?;
-    let mut knowledge = KnowledgeBase::new(conn.clone(), embedding_model).await?;
+    let mut knowledge = KnowledgeBase::new(conn.clone(), embedding_model.clone()).await?;
 
     let loader = MultiLoader::new(
         MultiLoaderConfig {
@@ -91,16 +183,98 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .add_documents(loader.load_sources(args.sources).await?)
         .await?;
 
-    let agent = Agent::new(character, completion_model, knowledge);
+    let mut tools = ToolRegistry::builder();
+    if args.starknet_tools {
+        // Starknet tools keep their tables in a separate database from the knowledge base
+        let tools_conn = Connection::open(":memory:").await?;
+        tools_conn
+            .call(|conn| {
+                conn.execute_batch(asuka_starknet::transfer::INIT_SQL)
+                    .map_err(tokio_rusqlite::Error::from)
+            })
+            .await?;
+
+        // `swap` isn't registered: it only fetches an Ekubo quote for a fixed
+        // amount and never submits a transaction, so the agent would report
+        // swaps that didn't happen.
+        tools = tools
+            .static_tool(AddToken::new(tools_conn.clone()))
+            .static_tool(Transfer::new(tools_conn));
+    }
+    if let Some(url) = args.mcp_url {
+        let mcp = McpClient::new(McpEndpoint {
+            url,
+            auth_token: None,
+        })
+        .await?;
+        let definitions = mcp.get_tools().await?;
+        tools = tools.mcp_tools(mcp, definitions);
+    }
+    let tools = tools.build(&embedding_model).await?;
+
+    let agent = Agent::new(character, completion_model, knowledge)
+        .with_tools(tools)
+        .with_streaming_model(StreamingModel::new(
+            &args.anthropic_api_key,
+            anthropic::CLAUDE_3_5_SONNET,
+        ));
+
+    // Reload the character when its file changes, without re-indexing sources
+    CharacterWatcher::new(&args.character, agent.character_handle()).spawn();
 
+    // The character's `[attention]` rules are added to these, and reloaded with it
     let config = AttentionConfig {
-        bot_names: vec![agent.character.name.clone()],
+        admin_ids: args.admin_ids.clone(),
         ..Default::default()
     };
-    let attention = Attention::new(config, small_completion_model);
+    let attention =
+        Attention::new(config, small_completion_model).with_character(agent.character_handle());
 
-    let discord = DiscordClient::new(agent, attention);
-    discord.start(&args.discord_api_token).await?;
+    if args.cli {
+        CliClient::new(agent, attention).start().await?;
+        return Ok(());
+    }
+
+    let mut runtime = Runtime::new(agent, attention);
+    if let Some(token) = &args.discord_api_token {
+        runtime = runtime.discord(token);
+    }
+    if let Some(token) = &args.telegram_bot_token {
+        runtime = runtime.telegram(token);
+    }
+    if let (Some(app_token), Some(bot_token)) = (&args.slack_app_token, &args.slack_bot_token) {
+        runtime = runtime.slack(app_token, bot_token);
+    }
+    if let (Some(homeserver), Some(token)) = (&args.matrix_homeserver, &args.matrix_access_token) {
+        runtime = runtime.matrix(homeserver, token);
+    }
+    if let (Some(username), Some(token)) = (&args.twitch_username, &args.twitch_oauth_token) {
+        runtime = runtime.twitch(TwitchConfig::new(
+            username,
+            token,
+            args.twitch_channels.clone(),
+        ));
+    }
+    if let Some(addr) = args.api_addr {
+        runtime = runtime.api(addr, args.api_key.as_deref());
+    }
+    if let Some(addr) = args.game_addr {
+        runtime = runtime.game(addr);
+    }
+    if let (Some(consumer_key), Some(consumer_secret), Some(token), Some(secret)) = (
+        args.twitter_consumer_key,
+        args.twitter_consumer_secret,
+        args.twitter_access_token,
+        args.twitter_access_token_secret,
+    ) {
+        runtime = runtime.twitter(Oauth1aToken::new(
+            consumer_key,
+            consumer_secret,
+            token,
+            secret,
+        ));
+    }
+    runtime.run().await?;
 
     Ok(())
 }